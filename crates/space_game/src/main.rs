@@ -1,8 +1,10 @@
 use anyhow::anyhow;
 use bytemuck::{Pod, Zeroable};
-use log::{info, warn};
+use instant::Instant;
+use log::{error, info, warn};
 use nalgebra::{Isometry3, Matrix4, UnitQuaternion, Vector2, Vector3};
 use plat::EventHandler;
+use space_game_core::ecs::Reactor;
 use wgpu::{
     Backends, Device, DeviceDescriptor, Features, Instance, Limits, PresentMode, Queue, Surface,
     SurfaceConfiguration, TextureUsages, TextureViewDescriptor,
@@ -12,6 +14,7 @@ use winit::event::{DeviceEvent, ElementState, Event, KeyboardInput, VirtualKeyCo
 use winit::event_loop::ControlFlow;
 use winit::window::Window;
 
+mod net;
 mod plat;
 mod render;
 
@@ -19,6 +22,7 @@ fn main() -> anyhow::Result<()> {
     plat::do_main()
 }
 
+use crate::net::ServerConnection;
 use crate::render::Renderer;
 
 #[derive(Copy, Clone, Pod, Zeroable, Default, Debug)]
@@ -40,6 +44,17 @@ pub async fn run(window: Window) -> anyhow::Result<EventHandler> {
     )
     .await?;
 
+    let reactor = Reactor::builder().build()?;
+    let states = reactor.new_state_container();
+    let server = match plat::connect(ServerConnection::new()) {
+        Ok(server) => Some(server),
+        Err(err) => {
+            warn!("error connecting to server, playing offline: {err}");
+            None
+        }
+    };
+    let mut last_frame = Instant::now();
+
     let mut view = Isometry3::<f64>::default();
 
     let mut grabbed = false;
@@ -131,6 +146,18 @@ pub async fn run(window: Window) -> anyhow::Result<EventHandler> {
             }
         }
 
+        for event in server.iter().flat_map(|server| server.try_iter()) {
+            if let Err(err) = reactor.dispatch_any(&states, event) {
+                error!("{err}");
+            }
+        }
+
+        let now = Instant::now();
+        if let Err(err) = reactor.run_frame(&states, now - last_frame) {
+            error!("{err}");
+        }
+        last_frame = now;
+
         let surface_texture = surface.get_current_texture().unwrap();
         let surface_view = surface_texture
             .texture
@@ -0,0 +1,47 @@
+use log::info;
+use space_game_core::ecs::{network_events, AnyEvent, EncodedEvent, EventRegistry, Handshake};
+
+/// Path of the server's WebSocket endpoint.
+pub const SERVER_PATH: &str = "/api/v1/ws";
+
+/// Client side of the protocol spoken with `space_game_server`, independent of the
+/// platform's WebSocket.
+///
+/// The client sends its [`Handshake`] once the socket opens, and the first message
+/// from the server is its `Handshake`. Every later message is an [`EncodedEvent`].
+pub struct ServerConnection {
+    /// `Event`s which can be received from the server.
+    registry: EventRegistry,
+    /// Whether the server's handshake has been received and checked.
+    connected: bool,
+}
+
+impl ServerConnection {
+    pub fn new() -> ServerConnection {
+        ServerConnection {
+            registry: network_events(),
+            connected: false,
+        }
+    }
+
+    /// Get the handshake to send once the socket opens.
+    pub fn handshake(&self) -> Vec<u8> {
+        Handshake::new(&self.registry).to_bytes()
+    }
+
+    /// Handle a binary message from the server, and return the event it carries.
+    ///
+    /// Fails if the server's handshake doesn't match ours, in which case the socket
+    /// should be closed.
+    pub fn receive(&mut self, data: &[u8]) -> anyhow::Result<Option<AnyEvent>> {
+        if !self.connected {
+            Handshake::from_bytes(data)?.check(&self.registry)?;
+            self.connected = true;
+            info!("Connected to server");
+            return Ok(None);
+        }
+
+        let event = EncodedEvent::from_bytes(data)?;
+        Ok(Some(self.registry.decode(&event)?))
+    }
+}
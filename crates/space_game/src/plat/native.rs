@@ -1,11 +1,20 @@
 use std::fs::File;
 use std::io::Read;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
 
-use log::error;
+use log::{error, info};
+use space_game_core::ecs::AnyEvent;
+use tungstenite::Message;
 use winit::dpi::PhysicalSize;
 use winit::event_loop::EventLoop;
 use winit::window::WindowBuilder;
 
+use crate::net::{ServerConnection, SERVER_PATH};
+
+/// Address of the server to connect to, which is `space_game_server`'s default.
+const SERVER_ADDR: &str = "127.0.0.1:8000";
+
 pub fn do_main() -> anyhow::Result<()> {
     env_logger::init();
 
@@ -27,3 +36,35 @@ pub async fn load_res(path: &str) -> anyhow::Result<Vec<u8>> {
     File::open(path)?.read_to_end(&mut buf)?;
     Ok(buf)
 }
+
+/// Connect to the server, and return the events it sends once the handshake succeeds.
+pub fn connect(mut connection: ServerConnection) -> anyhow::Result<Receiver<AnyEvent>> {
+    let (mut socket, _) = tungstenite::connect(format!("ws://{SERVER_ADDR}{SERVER_PATH}"))?;
+    socket.write_message(Message::Binary(connection.handshake()))?;
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || loop {
+        let data = match socket.read_message() {
+            Ok(Message::Binary(data)) => data,
+            Ok(Message::Close(_)) | Err(_) => {
+                info!("Disconnected from server");
+                return;
+            }
+            Ok(_) => continue,
+        };
+        match connection.receive(&data) {
+            Ok(Some(event)) => {
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => {}
+            Err(err) => {
+                error!("{err:?}");
+                let _ = socket.close(None);
+                return;
+            }
+        }
+    });
+    Ok(receiver)
+}
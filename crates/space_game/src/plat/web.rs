@@ -1,9 +1,12 @@
+use std::sync::mpsc::{self, Receiver};
+
 use anyhow::anyhow;
 use js_sys::{ArrayBuffer, Uint8Array};
+use space_game_core::ecs::AnyEvent;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
-use web_sys::Response;
+use web_sys::{BinaryType, MessageEvent, Response, WebSocket};
 
 use log::error;
 use winit::dpi::PhysicalSize;
@@ -11,6 +14,8 @@ use winit::event_loop::EventLoop;
 use winit::platform::web::WindowExtWebSys;
 use winit::window::WindowBuilder;
 
+use crate::net::{ServerConnection, SERVER_PATH};
+
 pub fn do_main() -> anyhow::Result<()> {
     std::panic::set_hook(Box::new(console_error_panic_hook::hook));
     console_log::init()?;
@@ -69,3 +74,54 @@ pub async fn load_res(path: &str) -> anyhow::Result<Vec<u8>> {
     .unchecked_into::<ArrayBuffer>();
     Ok(Uint8Array::new(&array_buffer).to_vec())
 }
+
+/// Connect to the server which served the page, and return the events it sends once
+/// the handshake succeeds.
+pub fn connect(mut connection: ServerConnection) -> anyhow::Result<Receiver<AnyEvent>> {
+    let window = web_sys::window().ok_or_else(|| anyhow!("error getting window"))?;
+    let location = window.location();
+    let scheme = match location.protocol() {
+        Ok(protocol) if protocol == "https:" => "wss",
+        _ => "ws",
+    };
+    let host = location.host().map_err(|_| anyhow!("error getting host"))?;
+    let socket = WebSocket::new(&format!("{scheme}://{host}{SERVER_PATH}"))
+        .map_err(|_| anyhow!("error opening websocket"))?;
+    socket.set_binary_type(BinaryType::Arraybuffer);
+
+    let handshake = connection.handshake();
+    let on_open = Closure::<dyn FnMut()>::new({
+        let socket = socket.clone();
+        move || {
+            if socket.send_with_u8_array(&handshake).is_err() {
+                error!("error sending handshake");
+            }
+        }
+    });
+    socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+    on_open.forget();
+
+    let (sender, receiver) = mpsc::channel();
+    let on_message = Closure::<dyn FnMut(MessageEvent)>::new({
+        let socket = socket.clone();
+        move |message: MessageEvent| {
+            let buffer = match message.data().dyn_into::<ArrayBuffer>() {
+                Ok(buffer) => buffer,
+                Err(_) => return,
+            };
+            match connection.receive(&Uint8Array::new(&buffer).to_vec()) {
+                Ok(Some(event)) => {
+                    let _ = sender.send(event);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    error!("{err:?}");
+                    let _ = socket.close();
+                }
+            }
+        }
+    });
+    socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    on_message.forget();
+    Ok(receiver)
+}
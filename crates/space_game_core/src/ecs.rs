@@ -1,5 +1,9 @@
 //! TODO
 
+mod clock;
+
+mod command;
+
 #[allow(clippy::missing_docs_in_private_items)]
 mod entity;
 
@@ -8,19 +12,71 @@ mod event;
 #[allow(clippy::missing_docs_in_private_items)]
 mod handler;
 
+mod hierarchy;
+
+mod lifecycle;
+
+mod network;
+
+mod plugin;
+
+mod query;
+
 mod reactor;
 
+mod replay;
+
+mod save;
+
+mod schedule;
+
+mod spatial;
+
 mod state;
 
+mod testing;
+
 #[allow(clippy::missing_docs_in_private_items)]
 mod topic;
 
-pub use event::{AnyEvent, Event, EventWriter};
-pub use handler::{EventHandlerFn, Handler};
-pub use reactor::{HandlerGroup, InitEvent, Reactor};
-pub use state::{AnyState, DelayedReader, Reader, State, StateContainer, Writer};
+pub use clock::{SimClock, DEFAULT_MAX_CATCH_UP_TICKS, DEFAULT_TIMESTEP};
+pub use command::Commands;
+pub use entity::{
+    Archetype, ArchetypeId, Component, ComponentId, ComponentRegistry, EntityId, EntityState,
+    MissingEntityError, SerializableComponent,
+};
+#[allow(deprecated)]
+pub use entity::{CreateEntity, DestroyEntity};
+pub use event::{AnyEvent, EntityEvent, Event, EventWriter};
+pub use handler::{Condition, ConditionFn, EventHandlerFn, Handler, ReadOnlyHandlerFnArg};
+pub use hierarchy::{Ancestors, Children, GlobalTransform, HierarchyError, Parent, Transform};
+pub use lifecycle::{OnAdd, OnDespawn, OnRemove};
+pub use network::{network_events, Handshake, HandshakeError, NETWORK_PROTOCOL_VERSION};
+pub use plugin::Plugin;
+pub use query::{
+    Query, QueryData, QueryFilter, QueryIter, QueryIterMut, ReadOnlyQueryData, Target, With,
+    Without,
+};
+pub use reactor::{
+    BuildReactorError, DependencyGraph, DispatchError, DispatchErrorKind, DispatchMode,
+    DispatchReport, ErrorPolicy, HandlerFailure, HandlerGroup, InitEvent, Reactor, ReactorBuilder,
+    DEFAULT_EVENT_BUDGET, DEFAULT_MAX_CASCADE_DEPTH,
+};
+pub use replay::{
+    EncodedEvent, EventRegistry, Recorder, Replay, ReplayError, SerializableEvent,
+    REPLAY_FORMAT_VERSION,
+};
+pub use save::{
+    LoadError, SaveError, SaveFormat, SerializableState, StateRegistry, SAVE_FORMAT_VERSION,
+};
+pub use schedule::{ScheduleHandle, Scheduler, Tick};
+pub use spatial::{BoundingSphere, RayHit, SpatialIndex, SpatialIndexPlugin};
+pub use state::{AnyState, Changed, DelayedReader, Reader, State, StateContainer, Writer};
+pub use testing::{ReactorTest, ReactorTestRun};
 pub use topic::{AnyTopic, Publisher, Subscriber, Topic};
 
+pub use space_game_core_derive::{handler_group, Event, State, Topic};
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -33,6 +89,7 @@ mod test {
         }
         impl State for MyState {}
 
+        #[allow(dead_code)]
         #[derive(Clone, Default)]
         struct MyStateCopy(MyState);
         impl State for MyStateCopy {}
@@ -61,20 +118,971 @@ mod test {
             Ok(())
         }
 
+        let run = ReactorTest::new()
+            .configure(|builder| builder.add(handler1).add(handler2))
+            .dispatch(MyEvent { counter: 5 })
+            .run();
+
+        run.assert_no_failures();
+        assert_eq!(run.events::<MyEvent>().len(), 1 + 2 + 4 + 8 + 16 + 32);
+        #[allow(clippy::identity_op)]
+        {
+            assert_eq!(
+                run.state::<MyState>().sum,
+                1 * 5 + 2 * 4 + 4 * 3 + 8 * 2 + 16 * 1
+            );
+        }
+    }
+
+    #[test]
+    fn test_delayed_reader() {
+        #[derive(Clone, Default)]
+        struct Position(i32);
+        impl State for Position {}
+
+        #[derive(Clone, Default)]
+        struct Velocity(i32);
+        impl State for Velocity {}
+
+        #[derive(Debug)]
+        struct Step;
+        impl Event for Step {}
+
+        // `Position` feeds back into `Velocity` and vice-versa. Without the delay this
+        // would be rejected as a cycle.
+        fn integrate(
+            _: &Step,
+            velocity: Reader<'_, Velocity>,
+            mut position: Writer<'_, Position>,
+        ) -> anyhow::Result<()> {
+            position.0 += velocity.0;
+            Ok(())
+        }
+
+        fn spring(
+            _: &Step,
+            position: DelayedReader<'_, Position>,
+            mut velocity: Writer<'_, Velocity>,
+        ) -> anyhow::Result<()> {
+            velocity.0 -= position.0;
+            Ok(())
+        }
+
         let reactor = Reactor::builder()
-            .add(handler1)
-            .add(handler2)
+            .add(integrate)
+            .add(spring)
             .build()
             .unwrap();
 
         let states = reactor.new_state_container();
-        reactor.dispatch(&states, MyEvent { counter: 5 });
-        #[allow(clippy::identity_op)]
-        {
+        states.get_mut::<Position>().unwrap().0 = 10;
+        states.end_cycle();
+
+        let mut expected_position = 10;
+        let mut expected_velocity = 0;
+        for _ in 0..5 {
+            let previous_position = expected_position;
+            expected_velocity -= previous_position;
+            expected_position += expected_velocity;
+
+            reactor.dispatch(&states, Step).unwrap();
+            assert_eq!(states.get::<Velocity>().unwrap().0, expected_velocity);
+            assert_eq!(states.get::<Position>().unwrap().0, expected_position);
             assert_eq!(
-                states.get::<MyState>().unwrap().sum,
-                1 * 5 + 2 * 4 + 4 * 3 + 8 * 2 + 16 * 1
+                states.get_delayed::<Position>().unwrap().0,
+                expected_position
             );
         }
     }
+
+    #[test]
+    fn test_parallel_dispatch_matches_serial() {
+        #[derive(Clone, Default, PartialEq, Debug)]
+        struct A(Vec<u32>);
+        impl State for A {}
+
+        #[derive(Clone, Default, PartialEq, Debug)]
+        struct B(Vec<u32>);
+        impl State for B {}
+
+        #[derive(Clone, Default, PartialEq, Debug)]
+        struct Sums(Vec<u32>);
+        impl State for Sums {}
+
+        #[derive(Clone, Default, PartialEq, Debug)]
+        struct Lengths(Vec<usize>);
+        impl State for Lengths {}
+
+        #[derive(Clone, Default, PartialEq, Debug)]
+        struct Log(Vec<(u32, u32)>);
+        impl State for Log {}
+
+        #[derive(Debug)]
+        struct Msg(u32);
+        impl Topic for Msg {}
+
+        #[derive(Debug)]
+        struct Step {
+            depth: u32,
+            source: u32,
+        }
+        impl Event for Step {}
+
+        fn write_a(
+            ev: &Step,
+            mut a: Writer<'_, A>,
+            msgs: Publisher<'_, Msg>,
+        ) -> anyhow::Result<()> {
+            a.0.push(ev.depth * 10 + ev.source);
+            msgs.publish(Msg(ev.depth));
+            msgs.publish(Msg(ev.source));
+            Ok(())
+        }
+
+        fn write_b(ev: &Step, mut b: Writer<'_, B>, events: EventWriter<'_>) -> anyhow::Result<()> {
+            b.0.push(ev.source);
+            if ev.depth < 3 {
+                events.write(Step {
+                    depth: ev.depth + 1,
+                    source: 1,
+                });
+            }
+            Ok(())
+        }
+
+        fn sum_msgs(
+            _: &Step,
+            msgs: Subscriber<'_, Msg>,
+            mut sums: Writer<'_, Sums>,
+        ) -> anyhow::Result<()> {
+            sums.0.push(msgs.iter().map(|m| m.0).sum());
+            Ok(())
+        }
+
+        fn count_a(
+            _: &Step,
+            a: Reader<'_, A>,
+            mut lengths: Writer<'_, Lengths>,
+        ) -> anyhow::Result<()> {
+            lengths.0.push(a.0.len());
+            Ok(())
+        }
+
+        fn emit_from_b(ev: &Step, b: Reader<'_, B>, events: EventWriter<'_>) -> anyhow::Result<()> {
+            if ev.depth < 3 {
+                events.write(Step {
+                    depth: ev.depth + 1,
+                    source: 2 + b.0.len() as u32 % 2,
+                });
+            }
+            Ok(())
+        }
+
+        fn log(
+            ev: &Step,
+            _a: Reader<'_, A>,
+            _b: Reader<'_, B>,
+            mut log: Writer<'_, Log>,
+        ) -> anyhow::Result<()> {
+            log.0.push((ev.depth, ev.source));
+            Ok(())
+        }
+
+        #[derive(Clone, PartialEq, Debug)]
+        struct Spawned(u32);
+        impl Component for Spawned {}
+
+        // Each reserves `EntityId`s, which must be handed out in serial order.
+        fn spawn_left(ev: &Step, commands: Commands<'_>) -> anyhow::Result<()> {
+            for _ in 0..100 {
+                let entity = commands.spawn();
+                commands.insert(entity, Spawned(ev.depth * 10 + ev.source));
+            }
+            Ok(())
+        }
+
+        fn spawn_right(ev: &Step, commands: Commands<'_>) -> anyhow::Result<()> {
+            for _ in 0..100 {
+                let entity = commands.spawn();
+                commands.insert(entity, Spawned(100 + ev.depth * 10 + ev.source));
+            }
+            Ok(())
+        }
+
+        fn run(mode: DispatchMode) -> (A, B, Sums, Lengths, Log, Vec<(EntityId, Spawned)>) {
+            let reactor = Reactor::builder()
+                .dispatch_mode(mode)
+                .add(write_a)
+                .add(spawn_left)
+                .add(write_b)
+                .add(sum_msgs)
+                .add(spawn_right)
+                .add(count_a)
+                .add(emit_from_b)
+                .add(log)
+                .build()
+                .unwrap();
+
+            let states = reactor.new_state_container();
+            reactor
+                .dispatch(
+                    &states,
+                    Step {
+                        depth: 0,
+                        source: 0,
+                    },
+                )
+                .unwrap();
+
+            let result = (
+                states.get::<A>().unwrap().clone(),
+                states.get::<B>().unwrap().clone(),
+                states.get::<Sums>().unwrap().clone(),
+                states.get::<Lengths>().unwrap().clone(),
+                states.get::<Log>().unwrap().clone(),
+                {
+                    let entities = states.get::<EntityState>().unwrap();
+                    let mut spawned = entities
+                        .entities()
+                        .map(|e| (e, entities.get::<Spawned>(e).unwrap().clone()))
+                        .collect::<Vec<_>>();
+                    spawned.sort_by_key(|(e, _)| *e);
+                    spawned
+                },
+            );
+            result
+        }
+
+        let serial = run(DispatchMode::Serial);
+        assert_eq!(serial.4 .0.len(), 15);
+        assert_eq!(serial.5.len(), 3000);
+        // Run on several threads, so handlers in a wave really run concurrently.
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        for _ in 0..10 {
+            assert_eq!(pool.install(|| run(DispatchMode::Parallel)), serial);
+        }
+    }
+
+    #[test]
+    fn test_dependency_graph() {
+        #[derive(Clone, Default)]
+        struct Position(i32);
+        impl State for Position {}
+
+        #[derive(Debug)]
+        struct Step;
+        impl Event for Step {}
+
+        fn move_ship(_: &Step, mut position: Writer<'_, Position>) -> anyhow::Result<()> {
+            position.0 += 1;
+            Ok(())
+        }
+
+        fn draw_ship(_: &Step, _position: Reader<'_, Position>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn trail_ship(_: &Step, _position: DelayedReader<'_, Position>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        let reactor = Reactor::builder()
+            .add(draw_ship)
+            .add(move_ship)
+            .add(trail_ship)
+            .build()
+            .unwrap();
+        assert!(reactor.dependency_graph(&InitEvent::id()).is_none());
+        let graph = reactor.dependency_graph(&Step::id()).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&graph.to_json()).unwrap();
+        let nodes = json["nodes"].as_array().unwrap();
+        let find = |suffix: &str| {
+            nodes
+                .iter()
+                .position(|n| n["name"].as_str().unwrap().ends_with(suffix))
+                .unwrap()
+        };
+        let (draw, position, r#move) = (find("draw_ship"), find("Position"), find("move_ship"));
+        let trail = find("trail_ship");
+        assert_eq!(nodes[draw]["kind"], "handler");
+        assert!(nodes[draw]["location"].as_str().unwrap().contains("ecs.rs"));
+        assert_eq!(nodes[position]["kind"], "state");
+        assert!(nodes[position].get("location").is_none());
+
+        let mut edges = json["edges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                (
+                    e["from"].as_u64().unwrap() as usize,
+                    e["to"].as_u64().unwrap() as usize,
+                    e["access"].as_str().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        edges.sort();
+        let mut expected = vec![
+            (r#move, position, "writes"),
+            (position, draw, "reads"),
+            (position, trail, "reads delayed"),
+        ];
+        expected.sort();
+        assert_eq!(edges, expected);
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph {"));
+        assert!(dot.contains(&format!("{move} -> {position} [label=\"writes\"];")));
+        assert!(dot.contains(&format!("{position} -> {draw} [label=\"reads\"];")));
+        assert!(dot.contains("draw_ship\\n"));
+    }
+
+    #[test]
+    fn test_error_policies() {
+        #[derive(Clone, Default)]
+        struct Count(u32);
+        impl State for Count {}
+
+        #[derive(Debug)]
+        struct Step(u32);
+        impl Event for Step {}
+
+        fn fail(ev: &Step) -> anyhow::Result<()> {
+            anyhow::ensure!(ev.0 % 2 == 1, "even step {}", ev.0);
+            Ok(())
+        }
+
+        fn count(_: &Step, mut count: Writer<'_, Count>) -> anyhow::Result<()> {
+            count.0 += 1;
+            Ok(())
+        }
+
+        fn build(policy: ErrorPolicy) -> (Reactor, StateContainer) {
+            let reactor = Reactor::builder()
+                .add(count)
+                .add(fail)
+                .error_policy(policy)
+                .build()
+                .unwrap();
+            let states = reactor.new_state_container();
+            (reactor, states)
+        }
+
+        let (reactor, states) = build(ErrorPolicy::Continue);
+        let report = reactor.dispatch(&states, Step(2)).unwrap();
+        assert_eq!(report.events_processed, 1);
+        assert_eq!(report.handlers_run, 2);
+        assert!(!report.aborted);
+        assert_eq!(report.failures.len(), 1);
+        assert!(report.failures[0].handler.contains("fail"));
+        assert_eq!(report.failures[0].event, Step::id());
+        assert_eq!(report.failures[0].error.to_string(), "even step 2");
+        assert!(reactor
+            .dispatch(&states, Step(1))
+            .unwrap()
+            .failures
+            .is_empty());
+        assert_eq!(states.get::<Count>().unwrap().0, 2);
+
+        let (reactor, states) = build(ErrorPolicy::DisableAfter(2));
+        let report = reactor.dispatch(&states, Step(0)).unwrap();
+        assert!(!report.failures[0].disabled);
+        let report = reactor.dispatch(&states, Step(2)).unwrap();
+        assert!(report.failures[0].disabled);
+        let report = reactor.dispatch(&states, Step(4)).unwrap();
+        assert!(report.failures.is_empty());
+        assert_eq!(report.handlers_run, 1);
+        assert_eq!(states.get::<Count>().unwrap().0, 3);
+
+        let (reactor, states) = build(ErrorPolicy::Propagate);
+        let err = reactor.dispatch(&states, Step(2)).unwrap_err();
+        assert_eq!(err.kind, DispatchErrorKind::Handler);
+        assert!(err.report.aborted);
+        assert_eq!(err.to_string(), err.report.failures[0].to_string());
+        assert!(reactor.dispatch(&states, Step(3)).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "DisableAfter(0)")]
+    fn test_disable_after_zero() {
+        fn handler(_: &InitEvent) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        Reactor::builder()
+            .add(handler)
+            .error_policy(ErrorPolicy::DisableAfter(0));
+    }
+
+    #[test]
+    fn test_abort_cascade() {
+        #[derive(Clone, Default)]
+        struct Log(Vec<u32>);
+        impl State for Log {}
+
+        #[derive(Debug)]
+        struct Step(u32);
+        impl Event for Step {}
+
+        fn log(ev: &Step, mut log: Writer<'_, Log>, events: EventWriter<'_>) -> anyhow::Result<()> {
+            log.0.push(ev.0);
+            if ev.0 < 5 {
+                events.write(Step(ev.0 + 1));
+            }
+            Ok(())
+        }
+
+        fn fail(ev: &Step, _log: Reader<'_, Log>) -> anyhow::Result<()> {
+            anyhow::ensure!(ev.0 != 2, "step 2");
+            Ok(())
+        }
+
+        let run = |policy| {
+            let reactor = Reactor::builder()
+                .default_error_policy(policy)
+                .add(log)
+                .add(fail)
+                .build()
+                .unwrap();
+            let states = reactor.new_state_container();
+            let report = reactor.dispatch(&states, Step(0)).unwrap();
+            let log = states.get::<Log>().unwrap().0.clone();
+            (report, log)
+        };
+
+        let (report, log) = run(ErrorPolicy::Continue);
+        assert_eq!(log, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(report.events_processed, 6);
+        assert!(!report.aborted);
+
+        let (report, log) = run(ErrorPolicy::AbortCascade);
+        assert_eq!(log, [0, 1, 2]);
+        assert_eq!(report.events_processed, 3);
+        assert_eq!(report.handlers_run, 6);
+        assert_eq!(report.failures.len(), 1);
+        assert!(report.aborted);
+    }
+
+    #[test]
+    fn test_cascade_routing() {
+        #[derive(Clone, Default)]
+        struct Log(Vec<String>);
+        impl State for Log {}
+
+        #[derive(Debug)]
+        struct Fire(u32);
+        impl Event for Fire {}
+
+        #[derive(Debug)]
+        struct Hit(u32);
+        impl Event for Hit {}
+
+        fn fire(
+            ev: &Fire,
+            mut log: Writer<'_, Log>,
+            events: EventWriter<'_>,
+        ) -> anyhow::Result<()> {
+            log.0.push(format!("fire {}", ev.0));
+            events.write(Hit(ev.0));
+            Ok(())
+        }
+
+        fn hit(ev: &Hit, mut log: Writer<'_, Log>, events: EventWriter<'_>) -> anyhow::Result<()> {
+            log.0.push(format!("hit {}", ev.0));
+            if ev.0 > 0 {
+                events.write(Fire(ev.0 - 1));
+            }
+            Ok(())
+        }
+
+        let reactor = Reactor::builder().add(fire).add(hit).build().unwrap();
+        let states = reactor.new_state_container();
+        let report = reactor.dispatch(&states, Fire(1)).unwrap();
+        assert_eq!(report.events_processed, 4);
+        assert_eq!(
+            states.get::<Log>().unwrap().0,
+            ["fire 1", "hit 1", "fire 0", "hit 0"]
+        );
+    }
+
+    #[test]
+    fn test_cascade_limits() {
+        #[derive(Clone, Default)]
+        struct Count(u32);
+        impl State for Count {}
+
+        #[derive(Debug)]
+        struct Echo;
+        impl Event for Echo {}
+
+        fn echo(
+            _: &Echo,
+            mut count: Writer<'_, Count>,
+            events: EventWriter<'_>,
+        ) -> anyhow::Result<()> {
+            count.0 += 1;
+            events.write(Echo);
+            Ok(())
+        }
+
+        fn fan_out(_: &Echo, events: EventWriter<'_>) -> anyhow::Result<()> {
+            events.write(Echo);
+            Ok(())
+        }
+
+        let reactor = Reactor::builder()
+            .max_cascade_depth(10)
+            .add(echo)
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+        let err = reactor.dispatch(&states, Echo).unwrap_err();
+        assert_eq!(
+            err.kind,
+            DispatchErrorKind::CascadeDepth {
+                event: Echo::id(),
+                limit: 10
+            }
+        );
+        assert_eq!(err.report.events_processed, 11);
+        assert!(err.report.aborted);
+        assert_eq!(states.get::<Count>().unwrap().0, 11);
+
+        // Each `Echo` emits two more, so the budget runs out before the depth limit.
+        let reactor = Reactor::builder()
+            .event_budget(100)
+            .add(echo)
+            .add(fan_out)
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+        let err = reactor.dispatch(&states, Echo).unwrap_err();
+        assert_eq!(
+            err.kind,
+            DispatchErrorKind::EventBudget {
+                event: Echo::id(),
+                limit: 100
+            }
+        );
+        assert_eq!(states.get::<Count>().unwrap().0, 100);
+    }
+
+    #[test]
+    fn test_run_if() {
+        #[derive(Clone, Default)]
+        struct Armed(bool);
+        impl State for Armed {}
+
+        #[derive(Clone, Default)]
+        struct Log(Vec<&'static str>);
+        impl State for Log {}
+
+        #[derive(Debug)]
+        struct Fire(bool);
+        impl Event for Fire {}
+
+        fn arm(ev: &Fire, mut armed: Writer<'_, Armed>) -> anyhow::Result<()> {
+            armed.0 = ev.0;
+            Ok(())
+        }
+
+        fn fire(_: &Fire, mut log: Writer<'_, Log>) -> anyhow::Result<()> {
+            log.0.push("fire");
+            Ok(())
+        }
+
+        fn integrate(_: &Tick, mut log: Writer<'_, Log>) -> anyhow::Result<()> {
+            log.0.push("integrate");
+            Ok(())
+        }
+
+        fn is_armed(armed: Reader<'_, Armed>) -> bool {
+            armed.0
+        }
+
+        // `fire` is added first, but its condition reads `Armed`, so it runs after `arm`.
+        let reactor = Reactor::builder()
+            .add(fire)
+            .run_if(is_armed)
+            .add(arm)
+            .add(integrate)
+            .run_if(|clock: Reader<'_, SimClock>| !clock.is_paused())
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+
+        let report = reactor.dispatch(&states, Fire(false)).unwrap();
+        assert_eq!(report.handlers_run, 1);
+        reactor.dispatch(&states, Fire(true)).unwrap();
+        assert_eq!(states.get::<Log>().unwrap().0, ["fire"]);
+
+        states.get_mut::<SimClock>().unwrap().set_paused(true);
+        states.get_mut::<SimClock>().unwrap().step();
+        assert_eq!(
+            reactor
+                .run_frame(&states, std::time::Duration::ZERO)
+                .unwrap(),
+            1
+        );
+        states.get_mut::<SimClock>().unwrap().set_paused(false);
+        reactor
+            .run_frame(&states, std::time::Duration::from_secs(1))
+            .unwrap();
+        let log = states.get::<Log>().unwrap();
+        assert_eq!(log.0.len(), 1 + DEFAULT_MAX_CATCH_UP_TICKS as usize);
+    }
+
+    #[test]
+    fn test_run_if_reads_written_state() {
+        #[derive(Clone, Default)]
+        struct Fuel(u32);
+        impl State for Fuel {}
+
+        #[derive(Clone, Default)]
+        struct Log(Vec<u32>);
+        impl State for Log {}
+
+        #[derive(Debug)]
+        struct Burn;
+        impl Event for Burn {}
+
+        fn burn(_: &Burn, mut fuel: Writer<'_, Fuel>) -> anyhow::Result<()> {
+            fuel.0 -= 1;
+            Ok(())
+        }
+
+        fn log(_: &Burn, fuel: Reader<'_, Fuel>, mut log: Writer<'_, Log>) -> anyhow::Result<()> {
+            log.0.push(fuel.0);
+            Ok(())
+        }
+
+        // The condition reads the `State` its handler writes, which mustn't be reported
+        // as a cycle.
+        let reactor = Reactor::builder()
+            .add(log)
+            .add(burn)
+            .run_if(|fuel: Reader<'_, Fuel>| fuel.0 > 0)
+            .insert_state(Fuel(2))
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+        for _ in 0..3 {
+            reactor.dispatch(&states, Burn).unwrap();
+        }
+        assert_eq!(states.get::<Log>().unwrap().0, [1, 0, 0]);
+    }
+
+    #[test]
+    fn test_run_if_queries_written_entities() {
+        #[derive(Clone, Debug)]
+        struct Tank;
+        impl Component for Tank {}
+
+        #[derive(Debug)]
+        struct Build;
+        impl Event for Build {}
+
+        fn build(_: &Build, mut entities: Writer<'_, EntityState>) -> anyhow::Result<()> {
+            let tank = entities.spawn();
+            entities.insert(tank, Tank)?;
+            Ok(())
+        }
+
+        // The condition queries the `EntityState` its handler writes, so it borrows the
+        // entities only while it's evaluated.
+        let reactor = Reactor::builder()
+            .add(build)
+            .run_if(|tanks: Query<'_, &Tank>| tanks.iter().count() < 2)
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+        for _ in 0..3 {
+            let report = reactor.dispatch(&states, Build).unwrap();
+            assert!(report.failures.is_empty());
+        }
+        assert_eq!(states.get::<EntityState>().unwrap().entities().count(), 2);
+    }
+
+    #[test]
+    fn test_labels() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Debug)]
+        struct Step;
+        impl Event for Step {}
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let push = |name: &'static str| {
+            let log = log.clone();
+            move |_: &Step| {
+                log.lock().unwrap().push(name);
+                Ok(())
+            }
+        };
+
+        for mode in [DispatchMode::Serial, DispatchMode::Parallel] {
+            let reactor = Reactor::builder()
+                .dispatch_mode(mode)
+                .add(push("render"))
+                .after("physics")
+                .add(push("gravity"))
+                .label("physics")
+                .after("input")
+                .add(push("collisions"))
+                .label("physics")
+                .add(push("keyboard"))
+                .label("input")
+                .add(push("audio"))
+                .before("input")
+                .build()
+                .unwrap();
+
+            log.lock().unwrap().clear();
+            reactor
+                .dispatch(&reactor.new_state_container(), Step)
+                .unwrap();
+            let log = log.lock().unwrap();
+            let pos = |name| log.iter().position(|n| *n == name).unwrap();
+            assert_eq!(log.len(), 5);
+            assert!(pos("audio") < pos("keyboard"));
+            assert!(pos("keyboard") < pos("gravity"));
+            assert!(pos("gravity") < pos("render"));
+            assert!(pos("collisions") < pos("render"));
+        }
+
+        let err = match Reactor::builder()
+            .add(push("keyboard"))
+            .label("input")
+            .after("physics")
+            .add(push("gravity"))
+            .label("physics")
+            .after("input")
+            .build()
+        {
+            Ok(_) => panic!("Expected a cycle"),
+            Err(err) => err.to_string(),
+        };
+        assert!(err.contains("Label input"), "{err}");
+        assert!(err.contains("Label physics"), "{err}");
+    }
+
+    #[test]
+    fn test_change_detection() {
+        #[derive(Clone, Default)]
+        struct Target(f64);
+        impl State for Target {}
+
+        #[derive(Clone, Default)]
+        struct Renders(u32);
+        impl State for Renders {}
+
+        #[derive(Clone, Default)]
+        struct Reports(Vec<bool>);
+        impl State for Reports {}
+
+        #[derive(Debug)]
+        struct SetTarget(f64);
+        impl Event for SetTarget {}
+
+        #[derive(Debug)]
+        struct Peek;
+        impl Event for Peek {}
+
+        #[derive(Debug)]
+        struct Refresh;
+        impl Event for Refresh {}
+
+        fn set_target(ev: &SetTarget, mut target: Writer<'_, Target>) -> anyhow::Result<()> {
+            target.0 = ev.0;
+            Ok(())
+        }
+
+        fn peek(_: &Peek, target: Writer<'_, Target>) -> anyhow::Result<()> {
+            assert!(target.0 >= 0.0);
+            Ok(())
+        }
+
+        fn render(
+            _: &Refresh,
+            _target: Reader<'_, Target>,
+            mut renders: Writer<'_, Renders>,
+        ) -> anyhow::Result<()> {
+            renders.0 += 1;
+            Ok(())
+        }
+
+        fn report(
+            _: &Refresh,
+            target: Changed<Target>,
+            mut reports: Writer<'_, Reports>,
+        ) -> anyhow::Result<()> {
+            reports.0.push(target.is_changed());
+            Ok(())
+        }
+
+        let reactor = Reactor::builder()
+            .add(set_target)
+            .add(peek)
+            .add(render)
+            .run_if_changed()
+            .add(report)
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+
+        // Everything counts as changed on the first run.
+        reactor.dispatch(&states, Refresh).unwrap();
+        let report = reactor.dispatch(&states, Refresh).unwrap();
+        assert_eq!(report.handlers_run, 1);
+
+        reactor.dispatch(&states, SetTarget(1.0)).unwrap();
+        reactor.dispatch(&states, Refresh).unwrap();
+
+        // Dereferencing a `Writer` immutably doesn't mark the `State` changed.
+        reactor.dispatch(&states, Peek).unwrap();
+        reactor.dispatch(&states, Refresh).unwrap();
+
+        states.get_mut::<Target>().unwrap().0 = 2.0;
+        reactor.dispatch(&states, Refresh).unwrap();
+
+        assert_eq!(states.get::<Renders>().unwrap().0, 3);
+        assert_eq!(
+            states.get::<Reports>().unwrap().0,
+            [true, false, true, false, true]
+        );
+    }
+
+    #[test]
+    fn test_derive() {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize, State)]
+        #[state(name = "fuel", version = 2)]
+        struct Fuel(f64);
+
+        #[derive(Clone, Default, Debug, State)]
+        struct Log(Vec<f64>);
+
+        #[derive(Debug, Serialize, Deserialize, Event)]
+        #[event(name = "burn")]
+        struct Burn(f64);
+
+        #[derive(Debug, Topic)]
+        struct Burned(f64);
+
+        struct Engine;
+
+        #[handler_group]
+        impl Engine {
+            #[handler(after = "engine")]
+            fn log(
+                _: &Burn,
+                burned: Subscriber<'_, Burned>,
+                mut log: Writer<'_, Log>,
+            ) -> anyhow::Result<()> {
+                log.0.extend(burned.iter().map(|b| b.0));
+                Ok(())
+            }
+
+            #[handler(label = "engine", error_policy = ErrorPolicy::Propagate)]
+            fn burn(
+                ev: &Burn,
+                mut fuel: Writer<'_, Fuel>,
+                burned: Publisher<'_, Burned>,
+            ) -> anyhow::Result<()> {
+                let amount = Self::clamp(ev.0, fuel.0);
+                fuel.0 -= amount;
+                burned.publish(Burned(amount));
+                Ok(())
+            }
+
+            fn clamp(amount: f64, available: f64) -> f64 {
+                amount.min(available)
+            }
+        }
+
+        assert_eq!(<Fuel as SerializableState>::NAME, "fuel");
+        assert_eq!(<Fuel as SerializableState>::VERSION, 2);
+        assert_eq!(<Burn as SerializableEvent>::NAME, "burn");
+
+        let reactor = Reactor::builder().add_group::<Engine>().build().unwrap();
+        let states = reactor.new_state_container();
+        states.get_mut::<Fuel>().unwrap().0 = 5.0;
+        for amount in [3.0, 3.0, 3.0] {
+            reactor.dispatch(&states, Burn(amount)).unwrap();
+        }
+
+        assert_eq!(*states.get::<Fuel>().unwrap(), Fuel(0.0));
+        assert_eq!(states.get::<Log>().unwrap().0, [3.0, 2.0, 0.0]);
+    }
+
+    #[test]
+    fn test_many_worlds() {
+        #[derive(Clone, Default, State)]
+        struct Total(u64);
+
+        #[derive(Clone, Default, State)]
+        struct Seed(u64);
+
+        #[derive(Debug, Event)]
+        struct Step(u64);
+
+        fn add(
+            ev: &Step,
+            seed: Reader<'_, Seed>,
+            mut total: Writer<'_, Total>,
+        ) -> anyhow::Result<()> {
+            total.0 += ev.0 * seed.0;
+            Ok(())
+        }
+
+        fn fail(_: &Step, seed: Reader<'_, Seed>) -> anyhow::Result<()> {
+            anyhow::ensure!(seed.0.is_multiple_of(2), "odd seed {}", seed.0);
+            Ok(())
+        }
+
+        let reactor = Reactor::builder()
+            .add(add)
+            .add(fail)
+            .error_policy(ErrorPolicy::DisableAfter(1))
+            .dispatch_mode(DispatchMode::Parallel)
+            .build()
+            .unwrap();
+
+        let totals = std::thread::scope(|scope| {
+            let worlds = (0..100)
+                .map(|seed| {
+                    let reactor = &reactor;
+                    scope.spawn(move || {
+                        let states = reactor.new_state_container();
+                        states.get_mut::<Seed>().unwrap().0 = seed;
+                        let mut failures = 0;
+                        for step in 1..=50 {
+                            failures += reactor
+                                .dispatch(&states, Step(step))
+                                .unwrap()
+                                .failures
+                                .len();
+                        }
+                        let total = states.get::<Total>().unwrap().0;
+                        (total, failures)
+                    })
+                })
+                .collect::<Vec<_>>();
+            worlds
+                .into_iter()
+                .map(|world| world.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        // Failures in one world don't disable the handler in the others.
+        for (seed, (total, failures)) in totals.into_iter().enumerate() {
+            assert_eq!(total, 1275 * seed as u64);
+            assert_eq!(failures, seed % 2);
+        }
+    }
 }
@@ -0,0 +1,264 @@
+//! [`SimClock`] and related types.
+
+use std::time::Duration;
+
+use super::reactor::{DispatchError, Reactor};
+use super::schedule::Tick;
+use super::state::StateContainer;
+use super::State;
+
+/// Default for [`SimClock::timestep`], in seconds.
+pub const DEFAULT_TIMESTEP: f64 = 1.0 / 60.0;
+
+/// Default for [`SimClock::max_catch_up_ticks`].
+pub const DEFAULT_MAX_CATCH_UP_TICKS: u32 = 8;
+
+/// `State` which converts real frame time into fixed-length [`Tick`]s of simulated time.
+///
+/// Each frame, the real time elapsed is multiplied by the time warp and added to an
+/// accumulator, and a `Tick` is produced for every whole timestep in it. To keep
+/// slow frames from snowballing, at most [`SimClock::max_catch_up_ticks`] are produced
+/// per frame and any remaining backlog is dropped.
+///
+/// Every [`StateContainer`] created by a [`Reactor`] has a `SimClock`, which is driven
+/// by [`Reactor::run_frame`].
+#[derive(Clone, Debug, State)]
+pub struct SimClock {
+    /// Simulated time per tick, in seconds.
+    timestep: f64,
+    /// Simulated seconds per real second.
+    warp: f64,
+    /// True if time doesn't advance, other than by single steps.
+    paused: bool,
+    /// Single steps requested while paused.
+    pending_steps: u32,
+    /// Maximum ticks produced by one frame.
+    max_catch_up_ticks: u32,
+    /// Simulated time which hasn't been consumed by a tick yet, in seconds.
+    accumulator: f64,
+    /// Simulated time at the end of the most recent tick, in seconds.
+    time: f64,
+    /// Number of ticks produced.
+    ticks: u64,
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        SimClock::new(DEFAULT_TIMESTEP)
+    }
+}
+
+impl SimClock {
+    /// Construct a clock at time zero which produces ticks of `timestep` seconds.
+    pub fn new(timestep: f64) -> SimClock {
+        assert!(timestep > 0.0, "Timestep must be positive");
+        SimClock {
+            timestep,
+            warp: 1.0,
+            paused: false,
+            pending_steps: 0,
+            max_catch_up_ticks: DEFAULT_MAX_CATCH_UP_TICKS,
+            accumulator: 0.0,
+            time: 0.0,
+            ticks: 0,
+        }
+    }
+
+    /// Simulated time per tick, in seconds.
+    pub fn timestep(&self) -> f64 {
+        self.timestep
+    }
+
+    /// Simulated time at the end of the most recent tick, in seconds.
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Number of ticks produced since time zero.
+    pub fn tick_count(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Fraction of a timestep accumulated towards the next tick, for interpolating
+    /// between the previous and current tick when rendering.
+    pub fn interpolation(&self) -> f64 {
+        self.accumulator / self.timestep
+    }
+
+    /// Simulated seconds per real second.
+    pub fn warp(&self) -> f64 {
+        self.warp
+    }
+
+    /// Set the simulated seconds per real second.
+    pub fn set_warp(&mut self, warp: f64) {
+        assert!(warp >= 0.0, "Time warp must not be negative");
+        self.warp = warp;
+    }
+
+    /// Maximum ticks produced by one frame.
+    pub fn max_catch_up_ticks(&self) -> u32 {
+        self.max_catch_up_ticks
+    }
+
+    /// Set the maximum ticks produced by one frame.
+    pub fn set_max_catch_up_ticks(&mut self, ticks: u32) {
+        self.max_catch_up_ticks = ticks;
+    }
+
+    /// Returns true if the clock is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pause or resume the clock. Pausing discards any partial timestep.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.accumulator = 0.0;
+        self.pending_steps = 0;
+    }
+
+    /// Produce a single tick on the next frame. Does nothing unless paused.
+    pub fn step(&mut self) {
+        if self.paused {
+            self.pending_steps += 1;
+        }
+    }
+
+    /// Advance by `frame_time` of real time, and return the ticks to dispatch.
+    pub fn advance(&mut self, frame_time: Duration) -> Vec<Tick> {
+        let due = if self.paused {
+            std::mem::take(&mut self.pending_steps)
+        } else {
+            self.accumulator += frame_time.as_secs_f64() * self.warp;
+            (self.accumulator / self.timestep).floor() as u32
+        };
+
+        let count = due.min(self.max_catch_up_ticks);
+        if !self.paused {
+            self.accumulator -= count as f64 * self.timestep;
+            if count < due {
+                self.accumulator %= self.timestep;
+            }
+        }
+
+        (0..count)
+            .map(|_| {
+                self.ticks += 1;
+                self.time += self.timestep;
+                Tick {
+                    time: self.time,
+                    dt: self.timestep,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Reactor {
+    /// Advance the [`SimClock`] in `states` by `frame_time` of real time, and
+    /// dispatch the resulting [`Tick`]s. Returns the number of ticks dispatched.
+    ///
+    /// Stops at the first tick which fails to dispatch.
+    pub fn run_frame(
+        &self,
+        states: &StateContainer,
+        frame_time: Duration,
+    ) -> Result<usize, DispatchError> {
+        let ticks = match states.get_mut::<SimClock>() {
+            Some(mut clock) => clock.advance(frame_time),
+            None => return Ok(0),
+        };
+
+        for tick in &ticks {
+            self.dispatch(states, *tick)?;
+        }
+        Ok(ticks.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecs::{Event, EventWriter, Writer};
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_fixed_timestep() {
+        let mut clock = SimClock::new(0.1);
+        assert!(clock.advance(millis(50)).is_empty());
+        let ticks = clock.advance(millis(170));
+        assert_eq!(ticks.len(), 2);
+        assert!((ticks[1].time - 0.2).abs() < 1e-9);
+        assert_eq!(ticks[1].dt, 0.1);
+        assert!((clock.interpolation() - 0.2).abs() < 1e-9);
+
+        clock.set_warp(10.0);
+        assert_eq!(clock.advance(millis(50)).len(), 5);
+        assert_eq!(clock.tick_count(), 7);
+
+        // A long frame is capped, and the backlog is dropped.
+        assert_eq!(clock.advance(Duration::from_secs(10)).len(), 8);
+        assert!(clock.interpolation() < 1.0);
+        assert_eq!(clock.advance(millis(0)).len(), 0);
+    }
+
+    #[test]
+    fn test_pause_and_step() {
+        let mut clock = SimClock::new(0.1);
+        clock.set_paused(true);
+        assert!(clock.advance(millis(500)).is_empty());
+
+        clock.step();
+        clock.step();
+        assert_eq!(clock.advance(millis(0)).len(), 2);
+        assert!(clock.advance(millis(500)).is_empty());
+
+        clock.set_paused(false);
+        clock.step();
+        assert_eq!(clock.advance(millis(100)).len(), 1);
+        assert!((clock.time() - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        #[derive(Clone, Default)]
+        struct Elapsed(f64, u32);
+        impl State for Elapsed {}
+
+        #[derive(Debug)]
+        struct Alarm;
+        impl Event for Alarm {}
+
+        fn tick(
+            ev: &Tick,
+            mut elapsed: Writer<'_, Elapsed>,
+            events: EventWriter<'_>,
+        ) -> anyhow::Result<()> {
+            if elapsed.0 == 0.0 {
+                events.write_after(0.15, Alarm);
+            }
+            elapsed.0 += ev.dt;
+            Ok(())
+        }
+
+        fn alarm(_: &Alarm, mut elapsed: Writer<'_, Elapsed>) -> anyhow::Result<()> {
+            elapsed.1 += 1;
+            Ok(())
+        }
+
+        let reactor = Reactor::builder().add(tick).add(alarm).build().unwrap();
+        let states = reactor.new_state_container();
+        *states.get_mut::<SimClock>().unwrap() = SimClock::new(0.1);
+
+        assert_eq!(reactor.run_frame(&states, millis(250)).unwrap(), 2);
+        assert_eq!(states.get::<Elapsed>().unwrap().1, 0);
+        assert_eq!(reactor.run_frame(&states, millis(100)).unwrap(), 1);
+        let elapsed = states.get::<Elapsed>().unwrap();
+        assert!((elapsed.0 - 0.3).abs() < 1e-9);
+        assert_eq!(elapsed.1, 1);
+    }
+}
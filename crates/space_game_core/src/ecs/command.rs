@@ -0,0 +1,334 @@
+//! [`Commands`] and related types.
+
+use std::sync::Mutex;
+
+use super::entity::{Component, EntityId, EntityState, MissingEntityError};
+use super::handler::{Context, Dependency, HandlerFnArg, HandlerFnArgBuilder};
+use super::state::State;
+
+/// Type-erased operation on the [`EntityState`].
+type Command = Box<dyn FnOnce(&mut EntityState) -> anyhow::Result<()> + Send>;
+
+/// Interior-mutability queue used to store pending [`Commands`].
+#[derive(Default)]
+pub struct CommandQueue {
+    /// Pending commands, in the order they were queued.
+    commands: Mutex<Vec<Command>>,
+    /// `EntityId`s reserved by [`Commands::spawn`] for the pending commands.
+    reserved: Mutex<Vec<EntityId>>,
+}
+
+impl CommandQueue {
+    /// Construct an empty queue.
+    pub fn new() -> CommandQueue {
+        Default::default()
+    }
+
+    /// Push to the back of the queue.
+    fn push(&self, command: impl FnOnce(&mut EntityState) -> anyhow::Result<()> + Send + 'static) {
+        self.commands.lock().unwrap().push(Box::new(command));
+    }
+
+    /// Reserve an `EntityId` from `entities` for a command in this queue.
+    fn reserve(&self, entities: &EntityState) -> EntityId {
+        let entity = entities.reserve();
+        self.reserved.lock().unwrap().push(entity);
+        entity
+    }
+
+    /// Move every command from `other` to the back of this queue, preserving their order.
+    pub fn append(&self, other: CommandQueue) {
+        let mut commands = other.commands.into_inner().unwrap();
+        self.commands.lock().unwrap().append(&mut commands);
+        let mut reserved = other.reserved.into_inner().unwrap();
+        self.reserved.lock().unwrap().append(&mut reserved);
+    }
+
+    /// Drop every pending command without applying it, and release the `EntityId`s
+    /// reserved for them from `entities`.
+    pub fn discard(self, entities: &EntityState) {
+        for entity in self.reserved.into_inner().unwrap().into_iter().rev() {
+            entities.release(entity);
+        }
+    }
+
+    /// Returns true if there are no pending commands.
+    pub fn is_empty(&self) -> bool {
+        self.commands.lock().unwrap().is_empty()
+    }
+
+    /// Apply every pending command to `entities` in the order they were queued.
+    ///
+    /// Commands which fail don't prevent later commands from being applied. Their errors
+    /// are returned in order.
+    pub fn apply(&self, entities: &mut EntityState) -> Vec<anyhow::Error> {
+        self.reserved.lock().unwrap().clear();
+        let commands = std::mem::take(&mut *self.commands.lock().unwrap());
+        commands
+            .into_iter()
+            .filter_map(|command| command(entities).err())
+            .collect()
+    }
+}
+
+/// Handler argument used to spawn and despawn entities, and to insert and remove their
+/// `Component`s.
+///
+/// Operations are queued and applied to the [`EntityState`] once every handler for
+/// the current event has run, before any events written in response are dispatched.
+/// [`Commands::spawn`] returns the new `EntityId` immediately, so further operations on
+/// the entity can be queued by the same handler.
+///
+/// If the handler returns an error, every operation it queued is discarded and the
+/// `EntityId`s it reserved are released, so a failing handler never leaves
+/// partially-built entities behind.
+///
+/// Handlers which take `Commands` never run concurrently with each other, so they
+/// reserve the same `EntityId`s in every [`DispatchMode`](super::DispatchMode).
+pub struct Commands<'c> {
+    /// The `EntityState` which `EntityId`s are reserved from.
+    entities: &'c EntityState,
+    /// Queue of pending operations.
+    queue: &'c CommandQueue,
+}
+
+impl<'c> Commands<'c> {
+    /// Queue the creation of an entity with no `Component`s, and return its `EntityId`.
+    pub fn spawn(&self) -> EntityId {
+        let entity = self.queue.reserve(self.entities);
+        self.queue
+            .push(move |entities| Ok(entities.spawn_reserved(entity)?));
+        entity
+    }
+
+    /// Queue the destruction of `entity` and all of its `Component`s, along with its
+    /// descendants in the hierarchy.
+    pub fn despawn(&self, entity: EntityId) {
+        self.queue.push(move |entities| {
+            if entities.despawn(entity) {
+                Ok(())
+            } else {
+                Err(MissingEntityError(entity).into())
+            }
+        });
+    }
+
+    /// Queue attaching `component` to `entity`, replacing any existing value.
+    pub fn insert<C: Component>(&self, entity: EntityId, component: C) {
+        self.queue.push(move |entities| {
+            entities.insert(entity, component)?;
+            Ok(())
+        });
+    }
+
+    /// Queue attaching `child` to `parent`, like [`EntityState::set_parent`].
+    pub fn set_parent(&self, child: EntityId, parent: EntityId) {
+        self.queue.push(move |entities| {
+            entities.set_parent(child, parent)?;
+            Ok(())
+        });
+    }
+
+    /// Queue detaching `child` from its parent.
+    pub fn remove_parent(&self, child: EntityId) {
+        self.queue.push(move |entities| {
+            if !entities.contains(child) {
+                return Err(MissingEntityError(child).into());
+            }
+            entities.remove_parent(child);
+            Ok(())
+        });
+    }
+
+    /// Queue detaching `Component` `C` from `entity`.
+    pub fn remove<C: Component>(&self, entity: EntityId) {
+        self.queue.push(move |entities| {
+            if !entities.contains(entity) {
+                return Err(MissingEntityError(entity).into());
+            }
+            entities.remove::<C>(entity);
+            Ok(())
+        });
+    }
+}
+
+impl<'c> HandlerFnArg for Commands<'c> {
+    type Builder = CommandsBuilder;
+
+    fn dependencies(out: &mut Vec<Dependency>) {
+        // Commands reserve `EntityId`s through a shared borrow of the `EntityState`.
+        out.push(Dependency::ReadState(EntityState::id()));
+        out.push(Dependency::ReserveEntities);
+    }
+}
+
+#[doc(hidden)]
+pub struct CommandsBuilder;
+
+impl<'c> HandlerFnArgBuilder<'c> for CommandsBuilder {
+    type Arg = Commands<'c>;
+
+    fn build(context: &'c Context) -> anyhow::Result<Commands<'c>> {
+        let entities = context.entities.ok_or_else(|| {
+            anyhow::format_err!("Missing state `{}` for Commands", EntityState::id())
+        })?;
+
+        Ok(Commands {
+            entities,
+            queue: context.commands,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use slotmap::Key;
+
+    use super::*;
+    use crate::ecs::{DispatchMode, Event, Reactor};
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Ship(&'static str);
+    impl Component for Ship {}
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Orbit(f64);
+    impl Component for Orbit {}
+
+    #[derive(Debug)]
+    struct Launch(&'static str);
+    impl Event for Launch {}
+
+    #[derive(Debug)]
+    struct Crash(EntityId);
+    impl Event for Crash {}
+
+    fn launch(ev: &Launch, commands: Commands<'_>) -> anyhow::Result<()> {
+        let ship = commands.spawn();
+        commands.insert(ship, Ship(ev.0));
+        commands.insert(ship, Orbit(100.0));
+        Ok(())
+    }
+
+    fn crash(ev: &Crash, commands: Commands<'_>) -> anyhow::Result<()> {
+        commands.remove::<Orbit>(ev.0);
+        commands.despawn(ev.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_commands() {
+        let reactor = Reactor::builder().add(launch).add(crash).build().unwrap();
+
+        let states = reactor.new_state_container();
+        reactor.dispatch(&states, Launch("Vostok")).unwrap();
+        reactor.dispatch(&states, Launch("Mercury")).unwrap();
+
+        let ships = {
+            let entities = states.get::<EntityState>().unwrap();
+            let mut ships = entities
+                .entities()
+                .map(|e| {
+                    assert_eq!(entities.get::<Orbit>(e).as_deref(), Some(&Orbit(100.0)));
+                    (entities.get::<Ship>(e).unwrap().clone(), e)
+                })
+                .collect::<Vec<_>>();
+            ships.sort_by_key(|(ship, _)| ship.0);
+            ships
+        };
+        assert_eq!(ships.len(), 2);
+        assert_eq!(ships[0].0, Ship("Mercury"));
+
+        reactor.dispatch(&states, Crash(ships[0].1)).unwrap();
+        let entities = states.get::<EntityState>().unwrap();
+        assert!(!entities.contains(ships[0].1));
+        assert!(entities.contains(ships[1].1));
+        assert_eq!(entities.entities().count(), 1);
+    }
+
+    #[test]
+    fn test_failed_handler_commands_discarded() {
+        fn launch_and_fail(ev: &Launch, commands: Commands<'_>) -> anyhow::Result<()> {
+            let ship = commands.spawn();
+            commands.insert(ship, Ship(ev.0));
+            anyhow::bail!("Launch aborted")
+        }
+
+        for mode in [DispatchMode::Serial, DispatchMode::Parallel] {
+            let reactor = Reactor::builder()
+                .dispatch_mode(mode)
+                .add(launch_and_fail)
+                .add(launch)
+                .build()
+                .unwrap();
+            let states = reactor.new_state_container();
+            let report = reactor.dispatch(&states, Launch("Vostok")).unwrap();
+            assert_eq!(report.failures.len(), 1);
+
+            let entities = states.get::<EntityState>().unwrap();
+            let ships = entities.entities().collect::<Vec<_>>();
+            assert_eq!(ships.len(), 1);
+            assert!(entities.has::<Orbit>(ships[0]));
+        }
+    }
+
+    #[test]
+    fn test_failed_handler_ids_released() {
+        fn launch_and_fail(_: &Launch, commands: Commands<'_>) -> anyhow::Result<()> {
+            commands.spawn();
+            anyhow::bail!("Launch aborted")
+        }
+
+        /// Index of the slot an `EntityId` was allocated from, ignoring its version.
+        fn slot(entity: EntityId) -> u32 {
+            entity.data().as_ffi() as u32
+        }
+
+        let expected = {
+            let mut entities = EntityState::default();
+            [(); 2].map(|_| slot(entities.spawn()))
+        };
+        for mode in [DispatchMode::Serial, DispatchMode::Parallel] {
+            let reactor = Reactor::builder()
+                .dispatch_mode(mode)
+                .add(launch_and_fail)
+                .add(launch)
+                .build()
+                .unwrap();
+            let states = reactor.new_state_container();
+            for name in ["Vostok", "Mercury"] {
+                let report = reactor.dispatch(&states, Launch(name)).unwrap();
+                assert_eq!(report.failures.len(), 1);
+            }
+
+            // The failed handler's reservations are released before `launch` runs, so
+            // the ships reuse their slots.
+            let entities = states.get::<EntityState>().unwrap();
+            let mut slots = entities.entities().map(slot).collect::<Vec<_>>();
+            slots.sort();
+            assert_eq!(slots, expected);
+        }
+    }
+
+    #[test]
+    fn test_despawn_reserved() {
+        let mut state = EntityState::default();
+        let queue = CommandQueue::new();
+        let commands = Commands {
+            entities: &state,
+            queue: &queue,
+        };
+
+        let entity = commands.spawn();
+        commands.insert(entity, Orbit(1.0));
+        commands.despawn(entity);
+        commands.insert(entity, Orbit(2.0));
+        assert!(!state.contains(entity));
+
+        let errors = queue.apply(&mut state);
+        assert_eq!(errors.len(), 1);
+        assert!(!state.contains(entity));
+        assert!(queue.is_empty());
+        assert!(state.spawn_reserved(entity).is_err());
+    }
+}
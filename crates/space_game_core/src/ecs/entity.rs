@@ -1,43 +1,973 @@
-use slotmap::{new_key_type, SlotMap};
+//! Entities, [`Component`]s and the [`EntityState`] which stores them.
 
-use super::{HandlerGroup, State, Subscriber, Topic, Writer};
+use std::any::{type_name, Any, TypeId};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug, Display};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, format_err};
+use atomic_refcell::{AtomicRef, AtomicRefCell, AtomicRefMut};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use slotmap::{new_key_type, Key, KeyData, SecondaryMap, SlotMap};
+use thiserror::Error;
+
+use super::event::{AnyEvent, Event, EventId};
+use super::hierarchy::{Children, GlobalTransform, Parent, Transform};
+use super::lifecycle::{on_add, on_remove, Lifecycle, OnAdd, OnDespawn, OnRemove};
+use super::reactor::{HandlerGroup, ReactorBuilder};
+use super::save::SaveError;
+use super::state::next_change_tick;
+use super::{State, Subscriber, Topic, Writer};
 
 new_key_type! {
+    /// Identifies an entity in an [`EntityState`].
     pub struct EntityId;
+    /// Identifies an [`Archetype`] in an [`EntityState`].
     pub struct ArchetypeId;
 }
 
-#[derive(Debug, Clone)]
-pub struct Archetype;
+/// Trait for types which can be attached to entities.
+pub trait Component: Clone + Send + Sync + 'static {
+    /// Return the `ComponentId` of this type.
+    fn id() -> ComponentId {
+        ComponentId {
+            id: TypeId::of::<Self>(),
+            name: type_name::<Self>(),
+            new_column_fn: || Box::new(Vec::<Self>::new()),
+            on_add_fn: on_add::<Self>,
+            on_remove_fn: on_remove::<Self>,
+            on_remove_id_fn: OnRemove::<Self>::id,
+        }
+    }
+}
+
+/// ID for a type which implements `Component`.
+#[derive(Eq, Clone, Debug)]
+pub struct ComponentId {
+    /// `TypeId` for the `Component` type.
+    id: TypeId,
+    /// `type_name` for the `Component` type.
+    name: &'static str,
+    /// Constructs an empty column for this `Component`.
+    new_column_fn: fn() -> Box<dyn AnyColumn>,
+    /// Constructs an `OnAdd` event for this `Component`.
+    on_add_fn: fn(EntityId) -> AnyEvent,
+    /// Constructs an `OnRemove` event for this `Component` from its removed value.
+    on_remove_fn: fn(EntityId, Box<dyn Any + Send + Sync>) -> AnyEvent,
+    /// Returns the `EventId` of `OnRemove` for this `Component`.
+    on_remove_id_fn: fn() -> EventId,
+}
+
+impl ComponentId {
+    /// Get the function which constructs an `OnAdd` event for this `Component`.
+    pub(super) fn on_add_fn(&self) -> fn(EntityId) -> AnyEvent {
+        self.on_add_fn
+    }
+
+    /// Get the function which constructs an `OnRemove` event for this `Component`.
+    pub(super) fn on_remove_fn(&self) -> fn(EntityId, Box<dyn Any + Send + Sync>) -> AnyEvent {
+        self.on_remove_fn
+    }
+}
+
+impl PartialEq for ComponentId {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Hash for ComponentId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl PartialOrd for ComponentId {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ComponentId {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
+impl Display for ComponentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+/// Object-safe trait for a column of `Component`s, implemented by `Vec<C>`.
+trait AnyColumn: Send + Sync {
+    /// Returns `self` as an [`Any`].
+    fn as_any(&self) -> &dyn Any;
+    /// Returns `self` as an [`Any`] mutably.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// Clone `self` into a new box.
+    fn clone_column(&self) -> Box<dyn AnyColumn>;
+    /// Remove the value at `row`, replacing it with the last value.
+    fn swap_remove(&mut self, row: usize) -> Box<dyn Any + Send + Sync>;
+    /// Remove the value at `row` like [`AnyColumn::swap_remove`] and push it onto `dst`.
+    ///
+    /// Panics if `dst` is not a column of the same type.
+    fn move_row(&mut self, row: usize, dst: &mut dyn AnyColumn);
+    /// Number of values in the column.
+    fn len(&self) -> usize;
+}
+
+impl<C: Component> AnyColumn for Vec<C> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn clone_column(&self) -> Box<dyn AnyColumn> {
+        Box::new(self.clone())
+    }
+
+    fn swap_remove(&mut self, row: usize) -> Box<dyn Any + Send + Sync> {
+        Box::new(Vec::swap_remove(self, row))
+    }
+
+    fn move_row(&mut self, row: usize, dst: &mut dyn AnyColumn) {
+        let dst = dst.as_any_mut().downcast_mut::<Vec<C>>().unwrap();
+        dst.push(Vec::swap_remove(self, row));
+    }
+
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+}
 
+/// Trait for `Component`s which can be saved and loaded as part of the [`EntityState`].
+pub trait SerializableComponent: Component + Serialize + DeserializeOwned {
+    /// Stable name identifying this `Component` in saved data.
+    ///
+    /// Unlike the [`ComponentId`], which is derived from the type, the name must stay
+    /// the same between builds so that older saves can still be loaded.
+    const NAME: &'static str;
+}
+
+/// Serializes a column of a [`SerializableComponent`].
+type SerializeColumnFn = fn(&dyn AnyColumn) -> bincode::Result<Vec<u8>>;
+
+/// Deserializes a column of a [`SerializableComponent`].
+type DeserializeColumnFn = fn(&[u8]) -> bincode::Result<Box<dyn AnyColumn>>;
+
+/// Set of [`SerializableComponent`]s which can be saved and loaded as part of the
+/// [`EntityState`], with
+/// [`StateRegistry::register_entities`](super::StateRegistry::register_entities).
+pub struct ComponentRegistry {
+    /// `ComponentId` and deserialization function for each stable name.
+    registrations: HashMap<&'static str, (ComponentId, DeserializeColumnFn)>,
+    /// Stable name and serialization function for each registered `Component`.
+    names: HashMap<ComponentId, (&'static str, SerializeColumnFn)>,
+}
+
+impl Default for ComponentRegistry {
+    fn default() -> Self {
+        ComponentRegistry::new()
+    }
+}
+
+impl ComponentRegistry {
+    /// Construct a registry with the `Component`s of the hierarchy: [`Parent`],
+    /// [`Children`], [`Transform`] and [`GlobalTransform`].
+    pub fn new() -> ComponentRegistry {
+        ComponentRegistry {
+            registrations: HashMap::new(),
+            names: HashMap::new(),
+        }
+        .register::<Parent>()
+        .register::<Children>()
+        .register::<Transform>()
+        .register::<GlobalTransform>()
+    }
+
+    /// Register `C` so that it is included when saving and loading the `EntityState`.
+    ///
+    /// Panics if another `Component` is already registered with the same name.
+    pub fn register<C: SerializableComponent>(mut self) -> Self {
+        assert!(
+            !self.registrations.contains_key(C::NAME),
+            "Component name `{}` is registered twice",
+            C::NAME
+        );
+
+        self.registrations
+            .insert(C::NAME, (C::id(), deserialize_column::<C>));
+        self.names.insert(C::id(), (C::NAME, serialize_column::<C>));
+        self
+    }
+
+    /// Get the stable name `id` was registered with.
+    pub fn name(&self, id: &ComponentId) -> Option<&'static str> {
+        self.names.get(id).map(|(name, _)| *name)
+    }
+}
+
+/// Implementation of [`SerializeColumnFn`].
+fn serialize_column<C: SerializableComponent>(column: &dyn AnyColumn) -> bincode::Result<Vec<u8>> {
+    bincode::serialize(column.as_any().downcast_ref::<Vec<C>>().unwrap())
+}
+
+/// Implementation of [`DeserializeColumnFn`].
+fn deserialize_column<C: SerializableComponent>(
+    data: &[u8],
+) -> bincode::Result<Box<dyn AnyColumn>> {
+    Ok(Box::new(bincode::deserialize::<Vec<C>>(data)?))
+}
+
+/// Table storing every entity which has exactly the same set of `Component`s.
+///
+/// Each `Component` is stored in its own column. Row `i` of every column belongs
+/// to the entity at `entities()[i]`. Columns can be borrowed independently, so
+/// handlers accessing different `Component`s can run in parallel.
+///
+/// Each column has a change tick, which is updated when values are inserted or
+/// accessed mutably.
+pub struct Archetype {
+    /// The `Component`s stored in this archetype, sorted.
+    components: Vec<ComponentId>,
+    /// Column for each `Component`.
+    columns: HashMap<ComponentId, AtomicRefCell<Box<dyn AnyColumn>>>,
+    /// Change tick of the most recent modification to each column.
+    change_ticks: HashMap<ComponentId, AtomicU64>,
+    /// The entity stored at each row.
+    entities: Vec<EntityId>,
+}
+
+impl Archetype {
+    /// Construct an empty archetype for a sorted set of `Component`s.
+    fn new(components: Vec<ComponentId>) -> Archetype {
+        let columns = components
+            .iter()
+            .map(|id| (id.clone(), AtomicRefCell::new((id.new_column_fn)())))
+            .collect();
+        let tick = next_change_tick();
+        let change_ticks = components
+            .iter()
+            .map(|id| (id.clone(), AtomicU64::new(tick)))
+            .collect();
+
+        Archetype {
+            components,
+            columns,
+            change_ticks,
+            entities: Vec::new(),
+        }
+    }
+
+    /// The `Component`s stored in this archetype, sorted.
+    pub fn components(&self) -> &[ComponentId] {
+        &self.components
+    }
+
+    /// The entities stored in this archetype, in row order.
+    pub fn entities(&self) -> &[EntityId] {
+        &self.entities
+    }
+
+    /// Returns true if this archetype stores `Component` `C`.
+    pub fn has<C: Component>(&self) -> bool {
+        self.columns.contains_key(&C::id())
+    }
+
+    /// Borrow the column for `Component` `C`, in row order.
+    ///
+    /// Panics if the column is currently borrowed mutably.
+    pub fn column<C: Component>(&self) -> Option<AtomicRef<'_, [C]>> {
+        let column = self.try_borrow_column::<C>()?.unwrap();
+        Some(AtomicRef::map(column, |c| c.as_slice()))
+    }
+
+    /// Get the column for `Component` `C` mutably, in row order, and mark it changed.
+    pub fn column_mut<C: Component>(&mut self) -> Option<&mut [C]> {
+        let column = self.columns.get_mut(&C::id())?.get_mut();
+        *self.change_ticks.get_mut(&C::id()).unwrap().get_mut() = next_change_tick();
+        Some(column.as_any_mut().downcast_mut::<Vec<C>>().unwrap())
+    }
+
+    /// Get the change tick of the most recent modification to the column for `C`.
+    pub fn change_tick<C: Component>(&self) -> Option<u64> {
+        self.change_tick_by_id(&C::id())
+    }
+
+    /// Get the change tick of the most recent modification to the column for `id`.
+    pub(super) fn change_tick_by_id(&self, id: &ComponentId) -> Option<u64> {
+        Some(self.change_ticks.get(id)?.load(Ordering::Relaxed))
+    }
+
+    /// Get the change tick of the column for `C`, so that it can be marked changed
+    /// while the column is borrowed.
+    pub(super) fn change_tick_cell<C: Component>(&self) -> Option<&AtomicU64> {
+        self.change_ticks.get(&C::id())
+    }
+
+    /// Try to borrow the column for `Component` `C`.
+    ///
+    /// Returns `None` if there is no such column, or an error if it is already borrowed mutably.
+    pub(super) fn try_borrow_column<C: Component>(
+        &self,
+    ) -> Option<anyhow::Result<AtomicRef<'_, Vec<C>>>> {
+        let column = self.columns.get(&C::id())?;
+        Some(match column.try_borrow() {
+            Ok(column) => Ok(AtomicRef::map(column, |c| {
+                c.as_any().downcast_ref::<Vec<C>>().unwrap()
+            })),
+            Err(_) => Err(format_err!("Component `{}` is already borrowed", C::id())),
+        })
+    }
+
+    /// Try to borrow the column for `Component` `C` mutably.
+    ///
+    /// Returns `None` if there is no such column, or an error if it is already borrowed.
+    pub(super) fn try_borrow_column_mut<C: Component>(
+        &self,
+    ) -> Option<anyhow::Result<AtomicRefMut<'_, Vec<C>>>> {
+        let column = self.columns.get(&C::id())?;
+        Some(match column.try_borrow_mut() {
+            Ok(column) => Ok(AtomicRefMut::map(column, |c| {
+                c.as_any_mut().downcast_mut::<Vec<C>>().unwrap()
+            })),
+            Err(_) => Err(format_err!("Component `{}` is already borrowed", C::id())),
+        })
+    }
+
+    /// Typed access to the column for `C`, which must be present.
+    fn column_vec_mut<C: Component>(&mut self) -> &mut Vec<C> {
+        self.columns
+            .get_mut(&C::id())
+            .unwrap()
+            .get_mut()
+            .as_any_mut()
+            .downcast_mut()
+            .unwrap()
+    }
+}
+
+impl Clone for Archetype {
+    fn clone(&self) -> Self {
+        Archetype {
+            components: self.components.clone(),
+            columns: self
+                .columns
+                .iter()
+                .map(|(id, column)| {
+                    (
+                        id.clone(),
+                        AtomicRefCell::new(column.borrow().clone_column()),
+                    )
+                })
+                .collect(),
+            change_ticks: self
+                .change_ticks
+                .iter()
+                .map(|(id, tick)| (id.clone(), AtomicU64::new(tick.load(Ordering::Relaxed))))
+                .collect(),
+            entities: self.entities.clone(),
+        }
+    }
+}
+
+impl Debug for Archetype {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Archetype")
+            .field("components", &self.components)
+            .field("entities", &self.entities)
+            .finish()
+    }
+}
+
+/// Location of an entity's `Component`s.
+#[derive(Clone, Copy, Debug)]
+struct EntityLocation {
+    /// Archetype storing the entity.
+    archetype: ArchetypeId,
+    /// Row within the archetype.
+    row: usize,
+}
+
+/// Indicates that an operation referred to an entity which doesn't exist.
+#[derive(Error, Debug)]
+#[error("Entity {0:?} does not exist")]
+pub struct MissingEntityError(pub EntityId);
+
+/// `Topic` which creates an entity in an [`Archetype`] with no `Component`s, for
+/// handlers in the [`EntityState`] handler group.
+#[deprecated(note = "Use `Commands::spawn`, which returns the new `EntityId`")]
 #[derive(Debug)]
 pub struct CreateEntity(ArchetypeId);
+#[allow(deprecated)]
 impl Topic for CreateEntity {}
 
+/// `Topic` which despawns an entity, for handlers in the [`EntityState`] handler group.
+#[deprecated(note = "Use `Commands::despawn`")]
 #[derive(Debug)]
 pub struct DestroyEntity(EntityId);
+#[allow(deprecated)]
 impl Topic for DestroyEntity {}
 
-#[derive(Default, Clone)]
+/// Allocates the `EntityId`s of an [`EntityState`], reusing the slots of despawned
+/// entities.
+///
+/// Unlike a `SlotMap`, the order free slots are reused in is saved along with the
+/// entities, so a loaded `EntityState` allocates the same `EntityId`s as the original.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+struct EntityAllocator {
+    /// Version of each slot, starting from the slot with index 1. Slots in use have
+    /// odd versions, like the keys of a `SlotMap`.
+    versions: Vec<u32>,
+    /// Position of each free slot in `versions`, with the next to be reused last.
+    free: Vec<u32>,
+}
+
+impl EntityAllocator {
+    /// Split `entity` into the position of its slot in `versions`, and its version.
+    fn slot(entity: EntityId) -> (usize, u32) {
+        let ffi = entity.data().as_ffi();
+        ((ffi as u32 as usize).wrapping_sub(1), (ffi >> 32) as u32)
+    }
+
+    /// Allocate an `EntityId`, reusing the most recently freed slot if there is one.
+    fn allocate(&mut self) -> EntityId {
+        let pos = self.free.pop().unwrap_or_else(|| {
+            self.versions.push(0);
+            self.versions.len() as u32 - 1
+        });
+        let version = &mut self.versions[pos as usize];
+        *version = version.wrapping_add(1);
+        KeyData::from_ffi(u64::from(*version) << 32 | u64::from(pos + 1)).into()
+    }
+
+    /// Returns true if `entity` has been allocated and not freed.
+    fn contains(&self, entity: EntityId) -> bool {
+        let (pos, version) = Self::slot(entity);
+        version % 2 == 1 && self.versions.get(pos) == Some(&version)
+    }
+
+    /// Free `entity`'s slot so that it can be reused. Does nothing if it isn't allocated.
+    fn free(&mut self, entity: EntityId) {
+        if self.contains(entity) {
+            let (pos, _) = Self::slot(entity);
+            self.versions[pos] = self.versions[pos].wrapping_add(1);
+            self.free.push(pos as u32);
+        }
+    }
+}
+
+/// Layout of an [`EntityState`] in saved data.
+#[derive(Serialize, Deserialize)]
+pub(super) struct SavedEntities {
+    /// Every `EntityId` which is in use, and the order free slots are reused in.
+    allocator: EntityAllocator,
+    /// Every non-empty archetype, in order of the stable names of its `Component`s.
+    archetypes: Vec<SavedArchetype>,
+}
+
+/// Layout of an [`Archetype`] in saved data.
+#[derive(Serialize, Deserialize)]
+struct SavedArchetype {
+    /// The entity stored at each row.
+    entities: Vec<EntityId>,
+    /// Column for each `Component`, in order of stable name.
+    columns: Vec<EncodedColumn>,
+}
+
+/// Column of a [`SerializableComponent`] encoded with its stable name.
+#[derive(Serialize, Deserialize)]
+struct EncodedColumn {
+    /// Stable name of the `Component`.
+    name: String,
+    /// The column, encoded with `bincode`.
+    data: Vec<u8>,
+}
+
+/// `State` which stores every entity and its `Component`s.
+///
+/// Entities with the same set of `Component`s share an [`Archetype`]. Inserting or
+/// removing a `Component` moves the entity to the matching archetype.
+///
+/// Handlers usually modify entities through [`Commands`](super::Commands), which can
+/// reserve `EntityId`s while the `EntityState` is shared with other handlers.
+///
+/// Adding and removing `Component`s and despawning entities is recorded, and the
+/// [`Reactor`](super::Reactor) sends the matching [`OnAdd`](super::OnAdd),
+/// [`OnRemove`](super::OnRemove) and [`OnDespawn`](super::OnDespawn) events once the
+/// event during which the change was made has been handled.
+#[derive(Default, Debug, State)]
 pub struct EntityState {
-    entity_map: SlotMap<EntityId, ArchetypeId>,
-    #[allow(unused)]
+    /// Every `EntityId` which is in use, including reserved IDs which haven't been spawned.
+    allocator: Mutex<EntityAllocator>,
+    /// Location of each spawned entity.
+    entity_map: SecondaryMap<EntityId, EntityLocation>,
+    /// Every archetype which has been used so far.
     archetype_map: SlotMap<ArchetypeId, Archetype>,
+    /// Archetype for each sorted set of `Component`s.
+    archetype_index: HashMap<Vec<ComponentId>, ArchetypeId>,
+    /// Changes which haven't been sent as lifecycle events yet.
+    lifecycle: Mutex<Vec<Lifecycle>>,
+    /// Lifecycle events which have observers. Changes are only recorded if their
+    /// event is observed.
+    observed: Arc<HashSet<EventId>>,
+}
+impl Clone for EntityState {
+    fn clone(&self) -> Self {
+        EntityState {
+            allocator: Mutex::new(self.allocator.lock().unwrap().clone()),
+            entity_map: self.entity_map.clone(),
+            archetype_map: self.archetype_map.clone(),
+            archetype_index: self.archetype_index.clone(),
+            // Pending lifecycle events belong to the original, which sends them.
+            lifecycle: Default::default(),
+            observed: self.observed.clone(),
+        }
+    }
 }
-impl State for EntityState {}
 
+impl EntityState {
+    /// Create an entity with no `Component`s.
+    pub fn spawn(&mut self) -> EntityId {
+        let entity = self.allocator.get_mut().unwrap().allocate();
+        self.spawn_reserved(entity).unwrap();
+        entity
+    }
+
+    /// Reserve an `EntityId` without creating the entity.
+    ///
+    /// The entity doesn't exist until it is passed to [`EntityState::spawn_reserved`].
+    pub(super) fn reserve(&self) -> EntityId {
+        self.allocator.lock().unwrap().allocate()
+    }
+
+    /// Release an `EntityId` returned by [`EntityState::reserve`] which was never
+    /// spawned, so that its slot can be reused.
+    pub(super) fn release(&self, entity: EntityId) {
+        if !self.entity_map.contains_key(entity) {
+            self.allocator.lock().unwrap().free(entity);
+        }
+    }
+
+    /// Create an entity with no `Component`s from an `EntityId` returned by
+    /// [`EntityState::reserve`]. Does nothing if the entity already exists.
+    pub(super) fn spawn_reserved(&mut self, entity: EntityId) -> Result<(), MissingEntityError> {
+        if !self.allocator.get_mut().unwrap().contains(entity) {
+            return Err(MissingEntityError(entity));
+        }
+        if self.entity_map.contains_key(entity) {
+            return Ok(());
+        }
+
+        let archetype = self.archetype_for(Vec::new());
+        let arch = &mut self.archetype_map[archetype];
+        let row = arch.entities.len();
+        arch.entities.push(entity);
+        self.entity_map
+            .insert(entity, EntityLocation { archetype, row });
+        Ok(())
+    }
+
+    /// Destroy an entity and all of its `Component`s, along with its descendants in the
+    /// hierarchy. Returns false if it didn't exist.
+    pub fn despawn(&mut self, entity: EntityId) -> bool {
+        if !self.contains(entity) {
+            return false;
+        }
+
+        self.remove_parent(entity);
+        for descendant in self.descendants(entity) {
+            self.despawn_single(descendant);
+        }
+        self.despawn_single(entity)
+    }
+
+    /// Destroy an entity and all of its `Component`s, ignoring the hierarchy.
+    fn despawn_single(&mut self, entity: EntityId) -> bool {
+        let location = match self.entity_map.remove(entity) {
+            Some(location) => location,
+            None => return false,
+        };
+        self.allocator.get_mut().unwrap().free(entity);
+
+        // Components are removed in sorted order, so their events are deterministic.
+        let arch = &mut self.archetype_map[location.archetype];
+        let lifecycle = self.lifecycle.get_mut().unwrap();
+        for id in &arch.components {
+            let value = arch
+                .columns
+                .get_mut(id)
+                .unwrap()
+                .get_mut()
+                .swap_remove(location.row);
+            if self.observed.contains(&(id.on_remove_id_fn)()) {
+                lifecycle.push(Lifecycle::Removed(entity, id.clone(), value));
+            }
+        }
+        if self.observed.contains(&OnDespawn::id()) {
+            lifecycle.push(Lifecycle::Despawned(entity));
+        }
+        self.remove_row(location);
+        true
+    }
+
+    /// Create an entity in `archetype`, which must not have any `Component`s.
+    fn spawn_in(&mut self, archetype: ArchetypeId) -> anyhow::Result<EntityId> {
+        match self.archetype_map.get(archetype) {
+            Some(arch) if arch.components.is_empty() => Ok(self.spawn()),
+            Some(_) => bail!("Can't create an entity in an archetype with components"),
+            None => bail!("Archetype {archetype:?} does not exist"),
+        }
+    }
+
+    /// Returns true if `entity` exists.
+    pub fn contains(&self, entity: EntityId) -> bool {
+        self.entity_map.contains_key(entity)
+    }
+
+    /// Iterate over every entity.
+    pub fn entities(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.entity_map.keys()
+    }
+
+    /// Iterate over every non-empty archetype.
+    pub fn archetypes(&self) -> impl Iterator<Item = &Archetype> {
+        self.archetype_map
+            .values()
+            .filter(|arch| !arch.entities.is_empty())
+    }
+
+    /// Iterate over every non-empty archetype along with its ID.
+    pub(super) fn archetypes_with_ids(&self) -> impl Iterator<Item = (ArchetypeId, &Archetype)> {
+        self.archetype_map
+            .iter()
+            .filter(|(_, arch)| !arch.entities.is_empty())
+    }
+
+    /// Get an archetype by its ID.
+    pub(super) fn archetype(&self, id: ArchetypeId) -> &Archetype {
+        &self.archetype_map[id]
+    }
+
+    /// Get the archetype and row storing `entity`.
+    pub(super) fn location(&self, entity: EntityId) -> Option<(ArchetypeId, usize)> {
+        let location = self.entity_map.get(entity)?;
+        Some((location.archetype, location.row))
+    }
+
+    /// Returns true if `entity` has `Component` `C`.
+    pub fn has<C: Component>(&self, entity: EntityId) -> bool {
+        self.entity_map
+            .get(entity)
+            .is_some_and(|loc| self.archetype_map[loc.archetype].has::<C>())
+    }
+
+    /// Get a reference to `entity`'s `Component` `C`.
+    ///
+    /// Panics if the `Component` is currently borrowed mutably by a [`Query`](super::Query).
+    /// Handlers which read the `EntityState` through a [`Reader`](super::Reader) run
+    /// after every handler which writes a `Component`, so this can't happen within a
+    /// handler.
+    pub fn get<C: Component>(&self, entity: EntityId) -> Option<AtomicRef<'_, C>> {
+        let location = self.entity_map.get(entity)?;
+        let arch = &self.archetype_map[location.archetype];
+        let column = arch.column::<C>()?;
+        Some(AtomicRef::map(column, |c| &c[location.row]))
+    }
+
+    /// Get a mutable reference to `entity`'s `Component` `C`, and mark it changed.
+    pub fn get_mut<C: Component>(&mut self, entity: EntityId) -> Option<&mut C> {
+        let location = self.entity_map.get(entity)?;
+        let arch = &mut self.archetype_map[location.archetype];
+        Some(&mut arch.column_mut::<C>()?[location.row])
+    }
+
+    /// Attach `component` to `entity`, returning the previous value if it already had one.
+    pub fn insert<C: Component>(
+        &mut self,
+        entity: EntityId,
+        component: C,
+    ) -> Result<Option<C>, MissingEntityError> {
+        if let Some(existing) = self.get_mut::<C>(entity) {
+            return Ok(Some(std::mem::replace(existing, component)));
+        }
+
+        let location = *self
+            .entity_map
+            .get(entity)
+            .ok_or(MissingEntityError(entity))?;
+
+        let mut components = self.archetype_map[location.archetype].components.clone();
+        components.push(C::id());
+        components.sort();
+        let target = self.archetype_for(components);
+
+        self.move_entity(entity, target);
+        let arch = &mut self.archetype_map[target];
+        arch.column_vec_mut::<C>().push(component);
+        *arch.change_ticks.get_mut(&C::id()).unwrap().get_mut() = next_change_tick();
+        if self.observed.contains(&OnAdd::<C>::id()) {
+            self.lifecycle
+                .get_mut()
+                .unwrap()
+                .push(Lifecycle::Added(entity, C::id()));
+        }
+        Ok(None)
+    }
+
+    /// Detach `Component` `C` from `entity` and return it.
+    ///
+    /// Returns `None` if the entity doesn't exist or doesn't have the `Component`.
+    pub fn remove<C: Component>(&mut self, entity: EntityId) -> Option<C> {
+        let location = *self.entity_map.get(entity)?;
+        let arch = &self.archetype_map[location.archetype];
+        if !arch.has::<C>() {
+            return None;
+        }
+
+        let mut components = arch.components.clone();
+        components.retain(|id| *id != C::id());
+        let target = self.archetype_for(components);
+
+        let removed = self.move_entity(entity, target);
+        let (_, value) = removed.into_iter().next().unwrap();
+        let value = *value.downcast::<C>().unwrap();
+        if self.observed.contains(&OnRemove::<C>::id()) {
+            self.lifecycle.get_mut().unwrap().push(Lifecycle::Removed(
+                entity,
+                C::id(),
+                Box::new(value.clone()),
+            ));
+        }
+        Some(value)
+    }
+
+    /// Set the lifecycle events which have observers. Changes whose event isn't in
+    /// `observed` aren't recorded, since nothing would handle them.
+    pub(super) fn set_observed(&mut self, observed: Arc<HashSet<EventId>>) {
+        self.observed = observed;
+    }
+
+    /// Get the lifecycle events which have observers.
+    pub(super) fn observed(&self) -> Arc<HashSet<EventId>> {
+        self.observed.clone()
+    }
+
+    /// Convert to the layout used in saved data, encoding every `Component` with
+    /// `components`.
+    ///
+    /// Fails with [`SaveError::UnregisteredComponent`] if an entity has a `Component`
+    /// which isn't registered.
+    pub(super) fn to_saved(
+        &self,
+        components: &ComponentRegistry,
+    ) -> Result<SavedEntities, SaveError> {
+        let mut archetypes = self
+            .archetypes()
+            .map(|arch| {
+                let mut columns = arch
+                    .components
+                    .iter()
+                    .map(|id| {
+                        let (name, serialize) = components
+                            .names
+                            .get(id)
+                            .ok_or_else(|| SaveError::UnregisteredComponent(id.clone()))?;
+                        Ok(EncodedColumn {
+                            name: name.to_string(),
+                            data: serialize(&**arch.columns[id].borrow())?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, SaveError>>()?;
+                columns.sort_by(|a, b| a.name.cmp(&b.name));
+                Ok(SavedArchetype {
+                    entities: arch.entities.clone(),
+                    columns,
+                })
+            })
+            .collect::<Result<Vec<_>, SaveError>>()?;
+        archetypes.sort_by_cached_key(|arch| {
+            arch.columns
+                .iter()
+                .map(|column| column.name.clone())
+                .collect::<Vec<_>>()
+        });
+
+        Ok(SavedEntities {
+            allocator: self.allocator.lock().unwrap().clone(),
+            archetypes,
+        })
+    }
+
+    /// Convert from the layout used in saved data, decoding every `Component` with
+    /// `components`.
+    pub(super) fn from_saved(
+        saved: SavedEntities,
+        components: &ComponentRegistry,
+    ) -> anyhow::Result<EntityState> {
+        let mut state = EntityState {
+            allocator: Mutex::new(saved.allocator),
+            ..Default::default()
+        };
+        let allocator = state.allocator.get_mut().unwrap();
+
+        for saved_arch in saved.archetypes {
+            let mut columns = saved_arch
+                .columns
+                .iter()
+                .map(|column| {
+                    let (id, deserialize) = components
+                        .registrations
+                        .get(column.name.as_str())
+                        .ok_or_else(|| format_err!("Unknown component `{}`", column.name))?;
+                    let data = deserialize(&column.data)?;
+                    if data.len() != saved_arch.entities.len() {
+                        bail!(
+                            "Column for component `{}` has the wrong length",
+                            column.name
+                        );
+                    }
+                    Ok((id.clone(), data))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            columns.sort_by(|a, b| a.0.cmp(&b.0));
+
+            let ids = columns.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+            if ids.windows(2).any(|pair| pair[0] == pair[1]) {
+                bail!("Archetype has the same component twice");
+            }
+            let archetype = match state.archetype_index.get(&ids) {
+                Some(_) => bail!("Archetype {ids:?} is saved twice"),
+                None => {
+                    let id = state.archetype_map.insert(Archetype::new(ids.clone()));
+                    state.archetype_index.insert(ids, id);
+                    id
+                }
+            };
+
+            let arch = &mut state.archetype_map[archetype];
+            for (id, data) in columns {
+                *arch.columns.get_mut(&id).unwrap().get_mut() = data;
+            }
+            for (row, &entity) in saved_arch.entities.iter().enumerate() {
+                if !allocator.contains(entity) || state.entity_map.contains_key(entity) {
+                    bail!("Entity {entity:?} is saved twice or was never allocated");
+                }
+                state
+                    .entity_map
+                    .insert(entity, EntityLocation { archetype, row });
+            }
+            arch.entities = saved_arch.entities;
+        }
+        Ok(state)
+    }
+
+    /// Take the changes which haven't been sent as lifecycle events yet, in the order
+    /// they were made.
+    pub(super) fn take_lifecycle(&self) -> Vec<Lifecycle> {
+        std::mem::take(&mut *self.lifecycle.lock().unwrap())
+    }
+
+    /// Returns true if any non-empty archetype's column for `id` changed after `tick`.
+    pub(super) fn component_changed_since(&self, id: &ComponentId, tick: u64) -> bool {
+        self.archetypes()
+            .filter_map(|arch| arch.change_tick_by_id(id))
+            .any(|changed| changed > tick)
+    }
+
+    /// Returns true if any column of any non-empty archetype changed after `tick`.
+    pub(super) fn any_component_changed_since(&self, tick: u64) -> bool {
+        self.archetypes()
+            .flat_map(|arch| arch.change_ticks.values())
+            .any(|changed| changed.load(Ordering::Relaxed) > tick)
+    }
+
+    /// Get or create the archetype for a sorted set of `Component`s.
+    fn archetype_for(&mut self, components: Vec<ComponentId>) -> ArchetypeId {
+        if let Some(&id) = self.archetype_index.get(&components) {
+            return id;
+        }
+
+        let id = self
+            .archetype_map
+            .insert(Archetype::new(components.clone()));
+        self.archetype_index.insert(components, id);
+        id
+    }
+
+    /// Move `entity` from its current archetype to `target`.
+    ///
+    /// `Component`s present in both archetypes are moved, and `Component`s missing from `target`
+    /// are returned. The caller must push a value to each column in `target` which was
+    /// missing from the original archetype.
+    fn move_entity(
+        &mut self,
+        entity: EntityId,
+        target: ArchetypeId,
+    ) -> Vec<(ComponentId, Box<dyn Any + Send + Sync>)> {
+        let location = self.entity_map[entity];
+        let [src, dst] = self
+            .archetype_map
+            .get_disjoint_mut([location.archetype, target])
+            .unwrap();
+
+        let mut removed = Vec::new();
+        for (id, column) in &mut src.columns {
+            let column = column.get_mut();
+            match dst.columns.get_mut(id) {
+                Some(dst_column) => column.move_row(location.row, dst_column.get_mut().as_mut()),
+                None => removed.push((id.clone(), column.swap_remove(location.row))),
+            }
+        }
+
+        let row = dst.entities.len();
+        dst.entities.push(entity);
+        self.remove_row(location);
+        self.entity_map[entity] = EntityLocation {
+            archetype: target,
+            row,
+        };
+
+        removed
+    }
+
+    /// Remove the entity at `location` from its archetype's entity list, after its
+    /// `Component`s have been swap-removed, and update the location of the entity
+    /// which took its place.
+    fn remove_row(&mut self, location: EntityLocation) {
+        let arch = &mut self.archetype_map[location.archetype];
+        arch.entities.swap_remove(location.row);
+        if let Some(&moved) = arch.entities.get(location.row) {
+            self.entity_map[moved].row = location.row;
+        }
+    }
+}
+
+/// Applies the deprecated [`CreateEntity`] and [`DestroyEntity`] topics.
+///
+/// Deprecated in favor of [`Commands`](super::Commands), which is applied without
+/// adding a handler.
+#[allow(deprecated)]
 impl HandlerGroup for EntityState {
-    fn add_group(builder: super::reactor::ReactorBuilder) -> super::reactor::ReactorBuilder {
+    fn add_group(builder: ReactorBuilder) -> ReactorBuilder {
         builder.add_global(
             |creates: Subscriber<CreateEntity>,
              destroys: Subscriber<DestroyEntity>,
              mut state: Writer<EntityState>|
              -> anyhow::Result<()> {
                 for destroy in destroys.iter() {
-                    state.entity_map.remove(destroy.0);
+                    state.despawn(destroy.0);
                 }
                 for create in creates.iter() {
-                    state.entity_map.insert(create.0);
+                    state.spawn_in(create.0)?;
                 }
 
                 Ok(())
@@ -45,3 +975,121 @@ impl HandlerGroup for EntityState {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Position(f64);
+    impl Component for Position {}
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Velocity(f64);
+    impl Component for Velocity {}
+
+    #[test]
+    fn test_insert_remove() {
+        let mut state = EntityState::default();
+        let a = state.spawn();
+        let b = state.spawn();
+        let c = state.spawn();
+
+        for (idx, entity) in [a, b, c].into_iter().enumerate() {
+            assert_eq!(state.insert(entity, Position(idx as f64)).unwrap(), None);
+        }
+        assert_eq!(state.insert(a, Velocity(1.0)).unwrap(), None);
+        assert_eq!(state.insert(a, Velocity(2.0)).unwrap(), Some(Velocity(1.0)));
+        assert_eq!(state.insert(c, Velocity(3.0)).unwrap(), None);
+
+        assert_eq!(state.get::<Position>(a).as_deref(), Some(&Position(0.0)));
+        assert_eq!(state.get::<Position>(b).as_deref(), Some(&Position(1.0)));
+        assert_eq!(state.get::<Position>(c).as_deref(), Some(&Position(2.0)));
+        assert_eq!(state.get::<Velocity>(a).as_deref(), Some(&Velocity(2.0)));
+        assert_eq!(state.get::<Velocity>(b).as_deref(), None);
+        assert_eq!(state.get::<Velocity>(c).as_deref(), Some(&Velocity(3.0)));
+
+        assert_eq!(state.remove::<Position>(a), Some(Position(0.0)));
+        assert_eq!(state.remove::<Position>(a), None);
+        assert!(!state.has::<Position>(a));
+        assert_eq!(state.get::<Velocity>(a).as_deref(), Some(&Velocity(2.0)));
+        assert_eq!(state.get::<Velocity>(c).as_deref(), Some(&Velocity(3.0)));
+
+        assert!(state.despawn(c));
+        assert!(!state.despawn(c));
+        assert!(!state.contains(c));
+        assert_eq!(state.get::<Velocity>(c).as_deref(), None);
+        assert_eq!(state.get::<Velocity>(a).as_deref(), Some(&Velocity(2.0)));
+        assert_eq!(state.get::<Position>(b).as_deref(), Some(&Position(1.0)));
+
+        let positions = state
+            .archetypes()
+            .filter_map(|arch| arch.column::<Position>())
+            .flat_map(|column| column.to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(positions, vec![Position(1.0)]);
+
+        state.get_mut::<Velocity>(a).unwrap().0 = 5.0;
+        assert_eq!(
+            state.clone().get::<Velocity>(a).as_deref(),
+            Some(&Velocity(5.0))
+        );
+        assert!(matches!(
+            state.insert(c, Position(0.0)),
+            Err(MissingEntityError(_))
+        ));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_topics() {
+        use crate::ecs::{Event, Publisher, Reactor};
+
+        #[derive(Debug)]
+        struct Create(ArchetypeId);
+        impl Event for Create {}
+
+        #[derive(Debug)]
+        struct Destroy(EntityId);
+        impl Event for Destroy {}
+
+        fn create(ev: &Create, creates: Publisher<'_, CreateEntity>) -> anyhow::Result<()> {
+            creates.publish(CreateEntity(ev.0));
+            Ok(())
+        }
+
+        fn destroy(ev: &Destroy, destroys: Publisher<'_, DestroyEntity>) -> anyhow::Result<()> {
+            destroys.publish(DestroyEntity(ev.0));
+            Ok(())
+        }
+
+        let reactor = Reactor::builder()
+            .add_group::<EntityState>()
+            .add(create)
+            .add(destroy)
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+        let empty = {
+            let mut entities = states.get_mut::<EntityState>().unwrap();
+            entities.spawn();
+            entities.archetype_index[&Vec::new()]
+        };
+
+        reactor.dispatch(&states, Create(empty)).unwrap();
+        let entities = states
+            .get::<EntityState>()
+            .unwrap()
+            .entities()
+            .collect::<Vec<_>>();
+        assert_eq!(entities.len(), 2);
+
+        reactor.dispatch(&states, Destroy(entities[0])).unwrap();
+        let remaining = states
+            .get::<EntityState>()
+            .unwrap()
+            .entities()
+            .collect::<Vec<_>>();
+        assert_eq!(remaining, [entities[1]]);
+    }
+}
@@ -1,15 +1,17 @@
 //! [`Event`] and related types.
 
 use std::any::{type_name, Any, TypeId};
-use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::fmt::{self, Debug, Display};
 use std::hash::Hash;
+use std::sync::{Arc, Mutex};
 
+use super::entity::EntityId;
 use super::handler::{Context, Dependency, HandlerFnArg, HandlerFnArgBuilder};
+use super::schedule::{HandleAllocator, ScheduleHandle, ScheduleOp};
 
 /// Trait for types which can be dispatched via the [`Reactor`].
-pub trait Event: Debug + 'static {
+pub trait Event: Debug + Send + Sync + 'static {
     /// Return the `EventId` for this type.
     fn id() -> EventId {
         EventId {
@@ -19,7 +21,22 @@ pub trait Event: Debug + 'static {
     }
 }
 
+/// Trait for `Event`s which are directed at a single entity, such as a collision
+/// with it or a request to dock with it.
+///
+/// Handlers added with [`ReactorBuilder::add_entity`](super::ReactorBuilder::add_entity)
+/// can fetch the target's `Component`s through a [`Target`](super::Target), and only
+/// run for targets which have them.
+pub trait EntityEvent: Event {
+    /// The entity this event is directed at.
+    fn target(&self) -> EntityId;
+}
+
 /// Identifier of a type which implements [`Event`]
+///
+/// `EventId`s are derived from `TypeId`s, which aren't stable between builds or
+/// targets. Events which are stored or sent over the network are identified by the
+/// stable name of their [`SerializableEvent`](super::SerializableEvent) instead.
 #[derive(Eq, Clone, Debug)]
 pub struct EventId {
     /// `TypeId` for the `Event` type.
@@ -47,10 +64,13 @@ impl Display for EventId {
 }
 
 /// Dynamically-typed container for a value that implement [`Event`]
-pub struct AnyEvent(Box<dyn AnyEventInner>);
+///
+/// Cloning an `AnyEvent` shares the underlying value.
+#[derive(Clone)]
+pub struct AnyEvent(Arc<dyn AnyEventInner>);
 
 /// Object-safe trait used inside [`AnyEvent`]
-trait AnyEventInner {
+trait AnyEventInner: Send + Sync {
     /// Returns `self` as an [`Any`]
     fn as_any(&self) -> &dyn Any;
     /// Return the [`EventId`] of `self`.
@@ -76,7 +96,7 @@ impl<E: Event + Sized> AnyEventInner for E {
 impl AnyEvent {
     /// Wrap a type implementing [`Event`].
     pub fn new<E: Event>(ev: E) -> Self {
-        Self(Box::new(ev))
+        Self(Arc::new(ev))
     }
 
     /// Return the [`EventId`] of the underlying type.
@@ -98,7 +118,12 @@ impl Debug for AnyEvent {
 
 /// Interior-mutability queue used to store pending events.
 #[derive(Default)]
-pub struct EventQueue(RefCell<VecDeque<AnyEvent>>);
+pub struct EventQueue {
+    /// Events to dispatch immediately.
+    events: Mutex<VecDeque<AnyEvent>>,
+    /// Changes to the [`Scheduler`](super::Scheduler), in the order they were made.
+    scheduled: Mutex<Vec<ScheduleOp>>,
+}
 
 impl EventQueue {
     /// Construct an empty queue.
@@ -108,22 +133,73 @@ impl EventQueue {
 
     /// Pop from the front of the queue.
     pub fn pop(&self) -> Option<AnyEvent> {
-        self.0.borrow_mut().pop_front()
+        self.events.lock().unwrap().pop_front()
     }
 
     /// Push to the back of the queue.
     pub fn push(&self, ev: AnyEvent) {
-        self.0.borrow_mut().push_back(ev);
+        self.events.lock().unwrap().push_back(ev);
+    }
+
+    /// Push a change to the `Scheduler`.
+    pub fn push_scheduled(&self, op: ScheduleOp) {
+        self.scheduled.lock().unwrap().push(op);
+    }
+
+    /// Remove every pending change to the `Scheduler`.
+    pub fn take_scheduled(&self) -> Vec<ScheduleOp> {
+        std::mem::take(&mut *self.scheduled.lock().unwrap())
+    }
+
+    /// Move every event from `other` to the back of this queue, preserving their order.
+    pub fn append(&self, other: EventQueue) {
+        let mut events = other.events.into_inner().unwrap();
+        self.events.lock().unwrap().append(&mut events);
+        let mut scheduled = other.scheduled.into_inner().unwrap();
+        self.scheduled.lock().unwrap().append(&mut scheduled);
     }
 }
 
 /// Handler argument used to write events.
-pub struct EventWriter<'e>(&'e EventQueue);
+///
+/// Events can also be scheduled for a later simulated time. Scheduled events are
+/// stored in the [`Scheduler`](super::Scheduler) and delivered while dispatching
+/// the [`Tick`](super::Tick) which reaches their time.
+pub struct EventWriter<'e> {
+    /// Queue written to.
+    queue: &'e EventQueue,
+    /// Allocates handles for scheduled events.
+    handles: &'e HandleAllocator,
+}
 
 impl<'e> EventWriter<'e> {
     /// Write an event.
     pub fn write<E: Event>(&self, e: E) {
-        self.0.push(AnyEvent::new(e));
+        self.queue.push(AnyEvent::new(e));
+    }
+
+    /// Schedule an event for the simulated time `time`, in seconds.
+    ///
+    /// If `time` has already passed, the event is delivered on the next `Tick`.
+    pub fn write_at<E: Event>(&self, time: f64, e: E) -> ScheduleHandle {
+        let handle = self.handles.allocate();
+        self.queue
+            .push_scheduled(ScheduleOp::At(time, handle, AnyEvent::new(e)));
+        handle
+    }
+
+    /// Schedule an event for `delay` seconds of simulated time after the current time.
+    pub fn write_after<E: Event>(&self, delay: f64, e: E) -> ScheduleHandle {
+        let handle = self.handles.allocate();
+        self.queue
+            .push_scheduled(ScheduleOp::After(delay, handle, AnyEvent::new(e)));
+        handle
+    }
+
+    /// Cancel an event scheduled with [`EventWriter::write_at`] or
+    /// [`EventWriter::write_after`]. Does nothing if it has already been delivered.
+    pub fn cancel(&self, handle: ScheduleHandle) {
+        self.queue.push_scheduled(ScheduleOp::Cancel(handle));
     }
 }
 
@@ -140,6 +216,9 @@ impl<'c> HandlerFnArgBuilder<'c> for EventWriterBuilder {
     type Arg = EventWriter<'c>;
 
     fn build(context: &'c Context) -> anyhow::Result<EventWriter<'c>> {
-        Ok(EventWriter(context.queue))
+        Ok(EventWriter {
+            queue: context.queue,
+            handles: &context.handles,
+        })
     }
 }
@@ -1,18 +1,54 @@
+use std::any::type_name;
 use std::fmt::{Debug, Display};
 use std::panic::Location;
 
 use anyhow::bail;
 use impl_trait_for_tuples::impl_for_tuples;
 
-use super::event::{AnyEvent, Event, EventQueue};
-use super::state::{StateContainer, StateId};
+use super::command::CommandQueue;
+use super::entity::{ComponentId, EntityId, EntityState};
+use super::event::{AnyEvent, EntityEvent, Event, EventQueue};
+use super::reactor::ErrorPolicy;
+use super::schedule::HandleAllocator;
+use super::state::{State, StateContainer, StateId};
 use super::topic::{TopicContainer, TopicId};
 
+/// Type-erased handler function.
+type HandlerFnBox = Box<dyn Fn(&Context) -> anyhow::Result<()> + Send + Sync>;
+
+/// Type-erased run condition function.
+type ConditionFnBox = Box<dyn Fn(&Context) -> anyhow::Result<bool> + Send + Sync>;
+
+/// Returns the entity an event is directed at, if the event is an [`EntityEvent`].
+pub type TargetFn = fn(&AnyEvent) -> Option<EntityId>;
+
 pub struct Handler {
     dependencies: Vec<Dependency>,
-    fn_box: Box<dyn Fn(&Context) -> anyhow::Result<()>>,
+    fn_box: HandlerFnBox,
     name: Option<String>,
     location: Location<'static>,
+    /// Overrides the reactor's default `ErrorPolicy` for this handler.
+    error_policy: Option<ErrorPolicy>,
+    /// Conditions which must all hold for the handler to run.
+    conditions: Vec<ConditionFnBox>,
+    /// True if one of the conditions reads the `EntityState`.
+    conditions_read_entities: bool,
+    /// True if the handler is skipped when none of its read dependencies changed.
+    only_if_changed: bool,
+    /// Returns false if the handler's arguments can't be fetched for a call.
+    args_should_run: fn(&Context) -> anyhow::Result<bool>,
+    /// Finds the target entity of the handler's event, if it was added as an entity handler.
+    target_fn: Option<TargetFn>,
+    /// True if the handler or one of its conditions takes a [`Target`](super::Target).
+    requires_target: bool,
+}
+
+/// Read-only function which decides whether a [`Handler`] runs.
+pub struct Condition {
+    dependencies: Vec<Dependency>,
+    fn_box: ConditionFnBox,
+    /// True if the condition takes a [`Target`](super::Target).
+    requires_target: bool,
 }
 
 /// Represents a dependency that a `Handler` can have.
@@ -28,6 +64,20 @@ pub enum Dependency {
     SubscribeTopic(TopicId),
     /// Dependency on publishing to a `Topic`.
     PublishTopic(TopicId),
+    /// Dependency on reading a `Component` of entities.
+    ReadComponent(ComponentId),
+    /// Dependency on writing a `Component` of entities.
+    WriteComponent(ComponentId),
+    /// Dependency on reading any `Component` of entities, through the `EntityState`.
+    ReadAllComponents,
+    /// Dependency on reserving `EntityId`s, through [`Commands`](super::Commands).
+    ReserveEntities,
+    /// Membership of an ordering label.
+    Label(String),
+    /// Dependency on running after every handler with a label.
+    After(String),
+    /// Dependency on running before every handler with a label.
+    Before(String),
 }
 
 impl Dependency {
@@ -39,6 +89,13 @@ impl Dependency {
             _ => None,
         }
     }
+
+    pub fn component_id(&self) -> Option<&ComponentId> {
+        match self {
+            Dependency::ReadComponent(id) | Dependency::WriteComponent(id) => Some(id),
+            _ => None,
+        }
+    }
 }
 
 impl Debug for Handler {
@@ -48,6 +105,9 @@ impl Debug for Handler {
             .field("fn_box", &())
             .field("name", &self.name)
             .field("location", &self.location)
+            .field("error_policy", &self.error_policy)
+            .field("conditions", &self.conditions.len())
+            .field("only_if_changed", &self.only_if_changed)
             .finish()
     }
 }
@@ -65,20 +125,45 @@ impl Display for Handler {
 
 pub struct Context<'a> {
     pub states: &'a StateContainer,
+    /// Shared borrow of the `EntityState`, present if the handler reads it.
+    pub entities: Option<&'a EntityState>,
     pub queue: &'a EventQueue,
+    /// Allocates handles for events the handler schedules.
+    pub handles: HandleAllocator,
+    pub commands: &'a CommandQueue,
     pub topics: &'a TopicContainer,
     pub event: &'a AnyEvent,
+    /// Entity the event is directed at, for handlers added with
+    /// [`ReactorBuilder::add_entity`](super::ReactorBuilder::add_entity).
+    pub target: Option<EntityId>,
+    /// Change tick of this run of the handler, used to mark what it changes.
+    pub tick: u64,
+    /// Change tick of the handler's previous run, or 0 if it hasn't run.
+    pub last_run: u64,
 }
 
 impl Handler {
     pub fn dependencies(&self) -> &[Dependency] {
-        &*self.dependencies
+        &self.dependencies
     }
 
     pub fn call(&self, context: &Context) -> anyhow::Result<()> {
         (self.fn_box)(context)
     }
 
+    /// Returns true if this handler reads the `EntityState`, such as through a
+    /// [`Query`](super::Query) or [`Commands`](super::Commands).
+    pub fn reads_entities(&self) -> bool {
+        self.dependencies
+            .contains(&Dependency::ReadState(EntityState::id()))
+    }
+
+    /// Returns true if any of the handler's conditions read the `EntityState`, even
+    /// if the handler itself writes it.
+    pub fn conditions_read_entities(&self) -> bool {
+        self.conditions_read_entities
+    }
+
     pub fn name(&self) -> Option<&str> {
         self.name.as_deref()
     }
@@ -86,6 +171,106 @@ impl Handler {
     pub fn location(&self) -> &Location<'static> {
         &self.location
     }
+
+    pub fn error_policy(&self) -> Option<ErrorPolicy> {
+        self.error_policy
+    }
+
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = Some(policy);
+    }
+
+    /// Add a dependency, such as an ordering label.
+    pub fn add_dependency(&mut self, dependency: Dependency) {
+        self.dependencies.push(dependency);
+    }
+
+    /// Only run the handler when `condition` holds. The condition's dependencies are
+    /// added to the handler's.
+    ///
+    /// Reads of a `State` or `Component` which the handler writes are dropped, since
+    /// the condition runs before the handler and the write already orders the handler
+    /// relative to every other reader.
+    pub fn add_condition(&mut self, condition: Condition) {
+        self.conditions_read_entities |= condition
+            .dependencies
+            .contains(&Dependency::ReadState(EntityState::id()));
+        for dependency in condition.dependencies {
+            let written = match &dependency {
+                Dependency::ReadState(id) => Dependency::WriteState(id.clone()),
+                Dependency::ReadComponent(id) => Dependency::WriteComponent(id.clone()),
+                _ => dependency.clone(),
+            };
+            if !self.dependencies.contains(&written) && !self.dependencies.contains(&dependency) {
+                self.dependencies.push(dependency);
+            }
+        }
+        self.conditions.push(condition.fn_box);
+        self.requires_target |= condition.requires_target;
+    }
+
+    /// Returns true if the handler only runs when one of its read dependencies changed
+    /// since its previous run.
+    pub fn only_if_changed(&self) -> bool {
+        self.only_if_changed
+    }
+
+    /// Only run the handler when one of its read dependencies changed since its
+    /// previous run.
+    pub fn set_only_if_changed(&mut self, only_if_changed: bool) {
+        self.only_if_changed = only_if_changed;
+    }
+
+    /// Returns true if any `State` or `Component` the handler reads changed after the
+    /// change tick `last_run`.
+    pub fn inputs_changed(
+        &self,
+        states: &StateContainer,
+        entities: Option<&EntityState>,
+        last_run: u64,
+    ) -> bool {
+        self.dependencies.iter().any(|dep| match dep {
+            Dependency::ReadState(id) | Dependency::ReadStateDelayed(id) => {
+                states.change_tick(id).is_some_and(|tick| tick > last_run)
+            }
+            Dependency::ReadComponent(id) => {
+                entities.is_some_and(|entities| entities.component_changed_since(id, last_run))
+            }
+            Dependency::ReadAllComponents => {
+                entities.is_some_and(|entities| entities.any_component_changed_since(last_run))
+            }
+            _ => false,
+        })
+    }
+
+    /// Direct the handler at the target entity of its `EntityEvent` `E`.
+    pub fn set_target<E: EntityEvent>(&mut self) {
+        self.target_fn = Some(|event| Some(event.downcast::<E>()?.target()));
+    }
+
+    /// Returns true if the handler takes a [`Target`](super::Target) but wasn't added
+    /// as an entity handler, so it would never run.
+    pub fn missing_target(&self) -> bool {
+        self.requires_target && self.target_fn.is_none()
+    }
+
+    /// Get the entity `event` is directed at, if the handler is an entity handler.
+    pub fn target(&self, event: &AnyEvent) -> Option<EntityId> {
+        self.target_fn.and_then(|target_fn| target_fn(event))
+    }
+
+    /// Evaluate the handler's conditions, returning true if they all hold.
+    pub fn should_run(&self, context: &Context) -> anyhow::Result<bool> {
+        if !(self.args_should_run)(context)? {
+            return Ok(false);
+        }
+        for condition in &self.conditions {
+            if !condition(context)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
 }
 
 pub trait EventHandlerFn<E, Args> {
@@ -98,12 +283,31 @@ pub trait HandlerFn<Args> {
     fn into_handler(self) -> Handler;
 }
 
+pub trait ConditionFn<Args> {
+    fn into_condition(self) -> Condition;
+}
+
 pub trait HandlerFnArg {
     type Builder: for<'c> HandlerFnArgBuilder<'c>;
 
     fn dependencies(out: &mut Vec<Dependency>);
+
+    /// Returns false if the handler should be skipped because this argument doesn't
+    /// apply to the call, such as a [`Target`](super::Target) which doesn't match.
+    fn should_run(_context: &Context) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+
+    /// Returns true if this argument can only be fetched for handlers added with
+    /// [`ReactorBuilder::add_entity`](super::ReactorBuilder::add_entity).
+    fn requires_target() -> bool {
+        false
+    }
 }
 
+/// [`HandlerFnArg`] which only reads, and so can be used by a [`Condition`].
+pub trait ReadOnlyHandlerFnArg: HandlerFnArg {}
+
 pub trait HandlerFnArgBuilder<'c> {
     type Arg: HandlerFnArg;
 
@@ -114,7 +318,7 @@ macro_rules! impl_handler_fn {
     ($($Args:ident),*) => {
         impl<$($Args,)* F> HandlerFn<($($Args,)*)> for F where
             $($Args: HandlerFnArg,)*
-            F: 'static,
+            F: Send + Sync + 'static,
             for<'f> &'f F: Fn($($Args,)*) -> anyhow::Result<()>,
             for<'f> &'f F: Fn($(<$Args::Builder as HandlerFnArgBuilder>::Arg,)*) -> anyhow::Result<()>,
         {
@@ -136,8 +340,15 @@ macro_rules! impl_handler_fn {
                     fn_box: Box::new(move |#[allow(unused)] context| {
                         make_fn(&self)($($Args::Builder::build(context)?,)*)
                     }),
-                    name: None,
+                    name: Some(type_name::<F>().to_owned()),
                     location: Location::caller().clone(),
+                    error_policy: None,
+                    conditions: Vec::new(),
+                    conditions_read_entities: false,
+                    only_if_changed: false,
+                    args_should_run: <($($Args,)*) as HandlerFnArg>::should_run,
+                    target_fn: None,
+                    requires_target: <($($Args,)*) as HandlerFnArg>::requires_target(),
                 }
             }
         }
@@ -145,7 +356,7 @@ macro_rules! impl_handler_fn {
         impl<E, $($Args,)* F> EventHandlerFn<E, ($($Args,)*)> for F where
             E: Event,
             $($Args: HandlerFnArg,)*
-            F: 'static,
+            F: Send + Sync + 'static,
             for<'f> &'f F: Fn(&E, $($Args,)*) -> anyhow::Result<()>,
             for<'f> &'f F: Fn(&E, $(<$Args::Builder as HandlerFnArgBuilder>::Arg,)*) -> anyhow::Result<()>,
         {
@@ -173,14 +384,60 @@ macro_rules! impl_handler_fn {
                             bail!("Handler called with invalid event: expected `{expected}` but given `{actual}`")
                         }
                     }),
-                    name: None,
+                    name: Some(type_name::<F>().to_owned()),
                     location: Location::caller().clone(),
+                    error_policy: None,
+                    conditions: Vec::new(),
+                    conditions_read_entities: false,
+                    only_if_changed: false,
+                    args_should_run: <($($Args,)*) as HandlerFnArg>::should_run,
+                    target_fn: None,
+                    requires_target: <($($Args,)*) as HandlerFnArg>::requires_target(),
+                }
+            }
+        }
+    }
+}
+
+macro_rules! impl_condition_fn {
+    ($($Args:ident),*) => {
+        impl<$($Args,)* F> ConditionFn<($($Args,)*)> for F where
+            $($Args: ReadOnlyHandlerFnArg,)*
+            F: Send + Sync + 'static,
+            for<'f> &'f F: Fn($($Args,)*) -> bool,
+            for<'f> &'f F: Fn($(<$Args::Builder as HandlerFnArgBuilder>::Arg,)*) -> bool,
+        {
+            fn into_condition(self) -> Condition {
+                fn make_fn<$($Args,)*>(
+                    f: impl Fn($($Args,)*) -> bool
+                ) -> impl Fn($($Args,)*) -> bool {
+                    f
+                }
+
+                Condition {
+                    dependencies: {
+                        #[allow(unused_mut)]
+                        let mut result = Vec::new();
+                        $($Args::dependencies(&mut result);)*
+                        result
+                    },
+                    fn_box: Box::new(move |#[allow(unused)] context| {
+                        Ok(make_fn(&self)($($Args::Builder::build(context)?,)*))
+                    }),
+                    requires_target: <($($Args,)*) as HandlerFnArg>::requires_target(),
                 }
             }
         }
     }
 }
 
+impl_condition_fn!();
+impl_condition_fn!(A1);
+impl_condition_fn!(A1, A2);
+impl_condition_fn!(A1, A2, A3);
+impl_condition_fn!(A1, A2, A3, A4);
+impl_condition_fn!(A1, A2, A3, A4, A5);
+
 impl_handler_fn!();
 impl_handler_fn!(A1);
 impl_handler_fn!(A1, A2);
@@ -195,8 +452,28 @@ impl HandlerFnArg for Tuple {
     fn dependencies(out: &mut Vec<Dependency>) {
         for_tuples!(#(Tuple::dependencies(out);)*);
     }
+
+    #[allow(unused_variables)]
+    fn should_run(context: &Context) -> anyhow::Result<bool> {
+        for_tuples!(#(
+            if !Tuple::should_run(context)? {
+                return Ok(false);
+            }
+        )*);
+        Ok(true)
+    }
+
+    fn requires_target() -> bool {
+        #[allow(unused_mut)]
+        let mut result = false;
+        for_tuples!(#( result |= Tuple::requires_target(); )*);
+        result
+    }
 }
 
+#[impl_for_tuples(5)]
+impl ReadOnlyHandlerFnArg for Tuple {}
+
 #[impl_for_tuples(5)]
 impl<'c> HandlerFnArgBuilder<'c> for Tuple {
     for_tuples!(where #(Tuple: HandlerFnArgBuilder<'c>)* );
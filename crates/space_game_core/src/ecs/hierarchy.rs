@@ -0,0 +1,344 @@
+//! Parent/child relationships between entities, and the [`Transform`]s which place
+//! children relative to their parents.
+
+use std::ops::Mul;
+
+use nalgebra::{UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::entity::{Component, EntityId, EntityState, MissingEntityError, SerializableComponent};
+
+/// `Component` which stores the parent of a child entity.
+///
+/// Set with [`EntityState::set_parent`], which keeps the parent's [`Children`] up to
+/// date, rather than inserting it directly.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Parent(EntityId);
+
+impl Component for Parent {}
+
+impl SerializableComponent for Parent {
+    const NAME: &'static str = "parent";
+}
+
+impl Parent {
+    /// The parent entity.
+    pub fn get(&self) -> EntityId {
+        self.0
+    }
+}
+
+/// `Component` which stores the children of a parent entity, in the order they were
+/// attached.
+#[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Children(Vec<EntityId>);
+
+impl Component for Children {}
+
+impl SerializableComponent for Children {
+    const NAME: &'static str = "children";
+}
+
+impl Children {
+    /// The child entities.
+    pub fn as_slice(&self) -> &[EntityId] {
+        &self.0
+    }
+
+    /// Iterate over the child entities.
+    pub fn iter(&self) -> impl Iterator<Item = EntityId> + '_ {
+        self.0.iter().copied()
+    }
+
+    /// Number of children.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true if there are no children.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Errors which can occur while changing the hierarchy.
+#[derive(Error, Debug)]
+pub enum HierarchyError {
+    /// Indicates that the child or parent doesn't exist.
+    #[error(transparent)]
+    MissingEntity(#[from] MissingEntityError),
+    /// Indicates that the parent is the child itself or one of its descendants.
+    #[error("Entity {parent:?} can't be the parent of {child:?}, which is its ancestor")]
+    Cycle {
+        /// The entity which would have been attached.
+        child: EntityId,
+        /// The entity it would have been attached to.
+        parent: EntityId,
+    },
+}
+
+/// `Component` which places an entity relative to its parent, or to the world if it
+/// has no parent.
+///
+/// For example, a moon's `Transform` holds its position relative to the planet it
+/// orbits. Entities without a `Transform` are treated as having the identity.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Transform {
+    /// Position in the parent's frame, in meters.
+    pub position: Vector3<f64>,
+    /// Orientation in the parent's frame.
+    pub rotation: UnitQuaternion<f64>,
+}
+
+impl Component for Transform {}
+
+impl SerializableComponent for Transform {
+    const NAME: &'static str = "transform";
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::IDENTITY
+    }
+}
+
+impl Transform {
+    /// Transform which doesn't move or rotate.
+    pub const IDENTITY: Transform = Transform {
+        position: Vector3::new(0.0, 0.0, 0.0),
+        rotation: UnitQuaternion::new_unchecked(nalgebra::Quaternion::new(1.0, 0.0, 0.0, 0.0)),
+    };
+
+    /// Construct a transform which moves by `position` without rotating.
+    pub fn from_position(position: Vector3<f64>) -> Transform {
+        Transform {
+            position,
+            ..Transform::IDENTITY
+        }
+    }
+
+    /// Map a point from this transform's frame into its parent's frame.
+    pub fn transform_point(&self, point: &Vector3<f64>) -> Vector3<f64> {
+        self.position + self.rotation * point
+    }
+}
+
+/// Composes a parent's transform with a child's local transform, so that
+/// `parent * local` maps points from the child's frame into the parent's parent frame.
+impl Mul for Transform {
+    type Output = Transform;
+
+    fn mul(self, local: Transform) -> Transform {
+        Transform {
+            position: self.transform_point(&local.position),
+            rotation: self.rotation * local.rotation,
+        }
+    }
+}
+
+/// `Component` which stores an entity's [`Transform`] relative to the world, as of the
+/// most recent call to [`EntityState::propagate_transforms`].
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct GlobalTransform(pub Transform);
+
+impl Component for GlobalTransform {}
+
+impl SerializableComponent for GlobalTransform {
+    const NAME: &'static str = "global_transform";
+}
+
+/// Iterator returned by [`EntityState::ancestors`].
+pub struct Ancestors<'a> {
+    /// The `EntityState` being walked.
+    entities: &'a EntityState,
+    /// The most recently returned entity, or the starting entity.
+    current: EntityId,
+}
+
+impl Iterator for Ancestors<'_> {
+    type Item = EntityId;
+
+    fn next(&mut self) -> Option<EntityId> {
+        self.current = self.entities.parent(self.current)?;
+        Some(self.current)
+    }
+}
+
+impl EntityState {
+    /// Get the parent of `entity`.
+    pub fn parent(&self, entity: EntityId) -> Option<EntityId> {
+        Some(self.get::<Parent>(entity)?.get())
+    }
+
+    /// Get the children of `entity`, in the order they were attached.
+    pub fn children(&self, entity: EntityId) -> Vec<EntityId> {
+        self.get::<Children>(entity)
+            .map(|children| children.0.clone())
+            .unwrap_or_default()
+    }
+
+    /// Iterate over the parent of `entity`, its parent, and so on up to the root.
+    pub fn ancestors(&self, entity: EntityId) -> Ancestors<'_> {
+        Ancestors {
+            entities: self,
+            current: entity,
+        }
+    }
+
+    /// Get the topmost ancestor of `entity`, or `entity` itself if it has no parent.
+    pub fn root(&self, entity: EntityId) -> EntityId {
+        self.ancestors(entity).last().unwrap_or(entity)
+    }
+
+    /// Get every descendant of `entity`, depth-first with parents before their children.
+    pub fn descendants(&self, entity: EntityId) -> Vec<EntityId> {
+        let mut result = Vec::new();
+        let mut stack = self.children(entity);
+        stack.reverse();
+        while let Some(next) = stack.pop() {
+            result.push(next);
+            stack.extend(self.children(next).into_iter().rev());
+        }
+        result
+    }
+
+    /// Attach `child` to `parent`, detaching it from its previous parent.
+    ///
+    /// Fails if either entity doesn't exist, or if `parent` is `child` or one of its
+    /// descendants.
+    pub fn set_parent(&mut self, child: EntityId, parent: EntityId) -> Result<(), HierarchyError> {
+        for entity in [child, parent] {
+            if !self.contains(entity) {
+                return Err(MissingEntityError(entity).into());
+            }
+        }
+        if parent == child || self.ancestors(parent).any(|e| e == child) {
+            return Err(HierarchyError::Cycle { child, parent });
+        }
+
+        self.remove_parent(child);
+        self.insert(child, Parent(parent))?;
+        match self.get_mut::<Children>(parent) {
+            Some(children) => children.0.push(child),
+            None => {
+                self.insert(parent, Children(vec![child]))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Detach `child` from its parent, and return the parent.
+    pub fn remove_parent(&mut self, child: EntityId) -> Option<EntityId> {
+        let parent = self.remove::<Parent>(child)?.get();
+        let children = self.get_mut::<Children>(parent)?;
+        children.0.retain(|&e| e != child);
+        if children.0.is_empty() {
+            self.remove::<Children>(parent);
+        }
+        Some(parent)
+    }
+
+    /// Get the [`Transform`] of `entity` relative to the world, by composing its
+    /// `Transform` with those of its ancestors.
+    pub fn world_transform(&self, entity: EntityId) -> Option<Transform> {
+        if !self.contains(entity) {
+            return None;
+        }
+
+        let local = |e| {
+            self.get::<Transform>(e)
+                .as_deref()
+                .copied()
+                .unwrap_or_default()
+        };
+        Some(
+            self.ancestors(entity)
+                .fold(local(entity), |world, ancestor| local(ancestor) * world),
+        )
+    }
+
+    /// Update the [`GlobalTransform`] of every entity with a [`Transform`].
+    pub fn propagate_transforms(&mut self) {
+        let updates = self
+            .entities()
+            .filter(|&entity| self.has::<Transform>(entity))
+            .map(|entity| (entity, self.world_transform(entity).unwrap()))
+            .collect::<Vec<_>>();
+
+        for (entity, world) in updates {
+            self.insert(entity, GlobalTransform(world)).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_near(a: Vector3<f64>, b: Vector3<f64>) {
+        assert!((a - b).norm() < 1e-9, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn test_hierarchy() {
+        let mut state = EntityState::default();
+        let [sun, earth, moon, station, ship] = [(); 5].map(|_| state.spawn());
+        state.set_parent(earth, sun).unwrap();
+        state.set_parent(moon, earth).unwrap();
+        state.set_parent(station, earth).unwrap();
+        state.set_parent(ship, station).unwrap();
+
+        assert_eq!(state.children(earth), [moon, station]);
+        assert_eq!(
+            state.ancestors(ship).collect::<Vec<_>>(),
+            [station, earth, sun]
+        );
+        assert_eq!(state.root(ship), sun);
+        assert_eq!(state.root(sun), sun);
+        assert_eq!(state.descendants(sun), [earth, moon, station, ship]);
+        assert!(matches!(
+            state.set_parent(sun, ship),
+            Err(HierarchyError::Cycle { .. })
+        ));
+
+        // Undocking moves the ship to the moon.
+        state.set_parent(ship, moon).unwrap();
+        assert!(!state.has::<Children>(station));
+        assert_eq!(state.children(moon), [ship]);
+
+        assert!(state.despawn(earth));
+        assert_eq!(state.entities().collect::<Vec<_>>(), [sun]);
+        assert!(state.children(sun).is_empty());
+    }
+
+    #[test]
+    fn test_transforms() {
+        let mut state = EntityState::default();
+        let [sun, earth, moon] = [(); 3].map(|_| state.spawn());
+        state.set_parent(earth, sun).unwrap();
+        state.set_parent(moon, earth).unwrap();
+
+        let quarter_turn =
+            UnitQuaternion::from_axis_angle(&Vector3::z_axis(), std::f64::consts::FRAC_PI_2);
+        state
+            .insert(
+                earth,
+                Transform {
+                    position: Vector3::new(100.0, 0.0, 0.0),
+                    rotation: quarter_turn,
+                },
+            )
+            .unwrap();
+        state
+            .insert(moon, Transform::from_position(Vector3::new(10.0, 0.0, 0.0)))
+            .unwrap();
+
+        let world = state.world_transform(moon).unwrap();
+        assert_near(world.position, Vector3::new(100.0, 10.0, 0.0));
+
+        state.propagate_transforms();
+        assert_eq!(state.get::<GlobalTransform>(moon).unwrap().0, world);
+        assert!(!state.has::<GlobalTransform>(sun));
+    }
+}
@@ -0,0 +1,287 @@
+//! Events for the lifecycle of entities and their `Component`s, which observer handlers
+//! added with [`ReactorBuilder::on_add`](super::ReactorBuilder::on_add) and related
+//! methods respond to.
+
+use std::any::{type_name, Any};
+use std::fmt::{self, Debug};
+use std::marker::PhantomData;
+
+use super::entity::{Component, ComponentId, EntityId};
+use super::event::{AnyEvent, EntityEvent, Event};
+
+/// `Event` sent when `Component` `C` is attached to an entity which didn't have one.
+///
+/// Replacing an existing value doesn't send an `OnAdd`. Observers can fetch the new
+/// value through a [`Target`](super::Target).
+pub struct OnAdd<C: Component> {
+    /// The entity the `Component` was attached to.
+    entity: EntityId,
+    /// Marker for the `Component` type.
+    component: PhantomData<C>,
+}
+
+impl<C: Component> OnAdd<C> {
+    /// The entity the `Component` was attached to.
+    pub fn entity(&self) -> EntityId {
+        self.entity
+    }
+}
+
+impl<C: Component> Event for OnAdd<C> {}
+
+impl<C: Component> EntityEvent for OnAdd<C> {
+    fn target(&self) -> EntityId {
+        self.entity
+    }
+}
+
+impl<C: Component> Debug for OnAdd<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OnAdd<{}>({:?})", type_name::<C>(), self.entity)
+    }
+}
+
+/// `Event` sent when `Component` `C` is detached from an entity, including when the
+/// entity is despawned.
+///
+/// The entity may no longer exist, so observers should use the removed value rather
+/// than a [`Target`](super::Target).
+pub struct OnRemove<C: Component> {
+    /// The entity the `Component` was detached from.
+    entity: EntityId,
+    /// The removed value.
+    component: C,
+}
+
+impl<C: Component> OnRemove<C> {
+    /// The entity the `Component` was detached from.
+    pub fn entity(&self) -> EntityId {
+        self.entity
+    }
+
+    /// The removed value.
+    pub fn component(&self) -> &C {
+        &self.component
+    }
+}
+
+impl<C: Component> Event for OnRemove<C> {}
+
+impl<C: Component> EntityEvent for OnRemove<C> {
+    fn target(&self) -> EntityId {
+        self.entity
+    }
+}
+
+impl<C: Component> Debug for OnRemove<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OnRemove<{}>({:?})", type_name::<C>(), self.entity)
+    }
+}
+
+/// `Event` sent when an entity is despawned, after the [`OnRemove`] events for each of
+/// its `Component`s.
+#[derive(Debug)]
+pub struct OnDespawn {
+    /// The entity which was despawned.
+    entity: EntityId,
+}
+
+impl OnDespawn {
+    /// The entity which was despawned.
+    pub fn entity(&self) -> EntityId {
+        self.entity
+    }
+}
+
+impl Event for OnDespawn {}
+
+impl EntityEvent for OnDespawn {
+    fn target(&self) -> EntityId {
+        self.entity
+    }
+}
+
+/// Change to the [`EntityState`](super::EntityState) which hasn't been sent as an
+/// event yet.
+pub(super) enum Lifecycle {
+    /// A `Component` was attached to an entity.
+    Added(EntityId, ComponentId),
+    /// A `Component` was detached from an entity, with its value.
+    Removed(EntityId, ComponentId, Box<dyn Any + Send + Sync>),
+    /// An entity was despawned.
+    Despawned(EntityId),
+}
+
+impl Lifecycle {
+    /// Convert to the matching lifecycle event.
+    pub(super) fn into_event(self) -> AnyEvent {
+        match self {
+            Lifecycle::Added(entity, id) => (id.on_add_fn())(entity),
+            Lifecycle::Removed(entity, id, value) => (id.on_remove_fn())(entity, value),
+            Lifecycle::Despawned(entity) => AnyEvent::new(OnDespawn { entity }),
+        }
+    }
+}
+
+impl Debug for Lifecycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lifecycle::Added(entity, id) => write!(f, "Added({entity:?}, {id})"),
+            Lifecycle::Removed(entity, id, _) => write!(f, "Removed({entity:?}, {id})"),
+            Lifecycle::Despawned(entity) => write!(f, "Despawned({entity:?})"),
+        }
+    }
+}
+
+/// Construct an [`OnAdd`] event for `Component` `C`.
+pub(super) fn on_add<C: Component>(entity: EntityId) -> AnyEvent {
+    AnyEvent::new(OnAdd::<C> {
+        entity,
+        component: PhantomData,
+    })
+}
+
+/// Construct an [`OnRemove`] event for `Component` `C` from its removed value.
+pub(super) fn on_remove<C: Component>(
+    entity: EntityId,
+    value: Box<dyn Any + Send + Sync>,
+) -> AnyEvent {
+    let component = *value.downcast::<C>().unwrap();
+    AnyEvent::new(OnRemove { entity, component })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecs::{Commands, EntityState, Event, Reactor, State, Target, Writer};
+
+    /// Handle to a mesh uploaded to the GPU.
+    #[derive(Clone, Debug, PartialEq)]
+    struct Mesh(u32);
+    impl Component for Mesh {}
+
+    /// Uploaded meshes, and every despawned entity.
+    #[derive(Clone, Default, State)]
+    struct Gpu {
+        meshes: Vec<u32>,
+        despawned: Vec<EntityId>,
+    }
+
+    #[derive(Debug, Event)]
+    struct Spawn(u32);
+
+    #[derive(Debug, Event)]
+    struct Despawn(EntityId);
+
+    fn spawn(ev: &Spawn, commands: Commands<'_>) -> anyhow::Result<()> {
+        let parent = commands.spawn();
+        let child = commands.spawn();
+        commands.insert(parent, Mesh(ev.0));
+        commands.insert(child, Mesh(ev.0 + 1));
+        commands.set_parent(child, parent);
+        Ok(())
+    }
+
+    fn despawn(ev: &Despawn, commands: Commands<'_>) -> anyhow::Result<()> {
+        commands.despawn(ev.0);
+        Ok(())
+    }
+
+    fn upload(
+        _: &OnAdd<Mesh>,
+        mesh: Target<'_, &Mesh>,
+        mut gpu: Writer<'_, Gpu>,
+    ) -> anyhow::Result<()> {
+        gpu.meshes.push(mesh.get().0);
+        Ok(())
+    }
+
+    fn free(ev: &OnRemove<Mesh>, mut gpu: Writer<'_, Gpu>) -> anyhow::Result<()> {
+        let mesh = ev.component().0;
+        gpu.meshes.retain(|&m| m != mesh);
+        Ok(())
+    }
+
+    fn log_despawn(ev: &OnDespawn, mut gpu: Writer<'_, Gpu>) -> anyhow::Result<()> {
+        gpu.despawned.push(ev.entity());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let reactor = Reactor::builder()
+            .add(spawn)
+            .add(despawn)
+            .on_add(upload)
+            .on_remove(free)
+            .on_despawn(log_despawn)
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+
+        reactor.dispatch(&states, Spawn(10)).unwrap();
+        reactor.dispatch(&states, Spawn(20)).unwrap();
+        assert_eq!(states.get::<Gpu>().unwrap().meshes, [10, 11, 20, 21]);
+
+        let parent = {
+            let entities = states.get::<EntityState>().unwrap();
+            let parent = entities
+                .entities()
+                .find(|&e| *entities.get::<Mesh>(e).unwrap() == Mesh(10));
+            parent.unwrap()
+        };
+        let child = states.get::<EntityState>().unwrap().children(parent)[0];
+
+        // Despawning the parent also despawns its child.
+        reactor.dispatch(&states, Despawn(parent)).unwrap();
+        let gpu = states.get::<Gpu>().unwrap();
+        assert_eq!(gpu.meshes, [20, 21]);
+        assert_eq!(gpu.despawned, [child, parent]);
+        drop(gpu);
+
+        // Changes made outside of a dispatch are observed during the next one.
+        let entity = {
+            let mut entities = states.get_mut::<EntityState>().unwrap();
+            let entity = entities.spawn();
+            entities.insert(entity, Mesh(30)).unwrap();
+            entities.insert(entity, Mesh(31)).unwrap();
+            entity
+        };
+        reactor.dispatch(&states, Despawn(entity)).unwrap();
+        let gpu = states.get::<Gpu>().unwrap();
+        assert_eq!(gpu.meshes, [20, 21]);
+        assert_eq!(gpu.despawned, [child, parent, entity]);
+    }
+
+    #[test]
+    fn test_lifecycle_unobserved() {
+        let modify = |states: &crate::ecs::StateContainer| {
+            let mut entities = states.get_mut::<EntityState>().unwrap();
+            let entity = entities.spawn();
+            entities.insert(entity, Mesh(1)).unwrap();
+            entities.remove::<Mesh>(entity);
+            entities.despawn(entity);
+        };
+
+        // Without observers, nothing is recorded, even outside of a dispatch.
+        let reactor = Reactor::builder().add(spawn).build().unwrap();
+        let states = reactor.new_state_container();
+        for _ in 0..3 {
+            modify(&states);
+        }
+        assert!(states
+            .get::<EntityState>()
+            .unwrap()
+            .take_lifecycle()
+            .is_empty());
+
+        // Only the changes which are observed are recorded.
+        let reactor = Reactor::builder().on_add(upload).build().unwrap();
+        let states = reactor.new_state_container();
+        modify(&states);
+        let lifecycle = states.get::<EntityState>().unwrap().take_lifecycle();
+        assert_eq!(format!("{lifecycle:?}").matches("Added").count(), 1);
+        assert_eq!(lifecycle.len(), 1);
+    }
+}
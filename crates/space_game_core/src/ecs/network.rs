@@ -0,0 +1,180 @@
+//! [`Handshake`] and related types, used to check that the client and server agree on
+//! the `Event`s they exchange.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::replay::EventRegistry;
+use super::schedule::Tick;
+
+/// Version of the messages exchanged between the client and server, including the
+/// [`Handshake`] itself.
+pub const NETWORK_PROTOCOL_VERSION: u32 = 1;
+
+/// Construct the [`EventRegistry`] of `Event`s exchanged between the client and server.
+///
+/// Both sides build their registry with this function, so their [`Handshake`]s only
+/// differ if they were built from different versions of the game. Every `Event` sent
+/// over the connection must be registered here.
+pub fn network_events() -> EventRegistry {
+    EventRegistry::new().register::<Tick>()
+}
+
+/// First message sent by each side of a connection, listing the stable names of the
+/// [`SerializableEvent`](super::SerializableEvent)s in its [`EventRegistry`].
+///
+/// `EventId`s are derived from `TypeId`s, which differ between builds and between the
+/// wasm and native targets, so events are sent as [`EncodedEvent`](super::EncodedEvent)s
+/// with their stable names. The handshake ensures both sides can decode every event
+/// the other may send.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Handshake {
+    /// The sender's [`NETWORK_PROTOCOL_VERSION`].
+    version: u32,
+    /// Sorted stable names of the sender's registered `Event`s.
+    events: Vec<String>,
+}
+
+/// Errors which can occur while exchanging a [`Handshake`].
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    /// Indicates that the handshake couldn't be decoded.
+    #[error("While decoding handshake: {0}")]
+    Binary(#[from] bincode::Error),
+    /// Indicates that the other side uses a different protocol version.
+    #[error("Protocol version {theirs} doesn't match {NETWORK_PROTOCOL_VERSION}")]
+    Version {
+        /// The other side's protocol version.
+        theirs: u32,
+    },
+    /// Indicates that the two sides registered different `Event`s.
+    #[error("Event registries differ: missing {missing:?}, unexpected {unexpected:?}")]
+    Mismatch {
+        /// Names registered on this side but not the other.
+        missing: Vec<String>,
+        /// Names registered on the other side but not this one.
+        unexpected: Vec<String>,
+    },
+}
+
+impl Handshake {
+    /// Construct the handshake for `registry`.
+    pub fn new(registry: &EventRegistry) -> Handshake {
+        Handshake {
+            version: NETWORK_PROTOCOL_VERSION,
+            events: registry.names().into_iter().map(str::to_owned).collect(),
+        }
+    }
+
+    /// Encode the handshake to send it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Decode a handshake received from the other side.
+    pub fn from_bytes(data: &[u8]) -> Result<Handshake, HandshakeError> {
+        Ok(bincode::deserialize(data)?)
+    }
+
+    /// Check that this handshake, received from the other side, matches `registry`.
+    pub fn check(&self, registry: &EventRegistry) -> Result<(), HandshakeError> {
+        if self.version != NETWORK_PROTOCOL_VERSION {
+            return Err(HandshakeError::Version {
+                theirs: self.version,
+            });
+        }
+
+        let ours = registry.names();
+        let missing = ours
+            .iter()
+            .filter(|name| !self.events.iter().any(|theirs| theirs == *name))
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let unexpected = self
+            .events
+            .iter()
+            .filter(|theirs| !ours.contains(&theirs.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() || !unexpected.is_empty() {
+            return Err(HandshakeError::Mismatch {
+                missing,
+                unexpected,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecs::{AnyEvent, EncodedEvent, Event, SerializableEvent};
+
+    #[derive(Clone, PartialEq, Debug, Serialize, Deserialize, Event)]
+    #[event(name = "dock")]
+    struct Dock {
+        station: u32,
+    }
+
+    #[derive(Clone, PartialEq, Debug, Serialize, Deserialize, Event)]
+    #[event(name = "undock")]
+    struct Undock;
+
+    #[derive(Debug, Event)]
+    struct Local;
+
+    #[test]
+    fn test_handshake() {
+        let client = EventRegistry::new().register::<Dock>().register::<Undock>();
+        let server = EventRegistry::new().register::<Undock>().register::<Dock>();
+
+        let received = Handshake::from_bytes(&Handshake::new(&client).to_bytes()).unwrap();
+        received.check(&server).unwrap();
+
+        let old_client = EventRegistry::new().register::<Dock>();
+        let err = Handshake::new(&old_client).check(&server).unwrap_err();
+        assert!(matches!(
+            err,
+            HandshakeError::Mismatch { missing, unexpected }
+                if missing == ["undock"] && unexpected.is_empty()
+        ));
+
+        let future = Handshake {
+            version: NETWORK_PROTOCOL_VERSION + 1,
+            ..Handshake::new(&client)
+        };
+        assert!(matches!(
+            future.check(&server),
+            Err(HandshakeError::Version { .. })
+        ));
+    }
+
+    #[test]
+    fn test_encode_events() {
+        let registry = EventRegistry::new().register::<Dock>();
+        let encoded = registry
+            .encode(&AnyEvent::new(Dock { station: 7 }))
+            .unwrap();
+        assert_eq!(encoded.name(), Dock::NAME);
+
+        let data = encoded.to_bytes();
+        let decoded = registry
+            .decode(&EncodedEvent::from_bytes(&data).unwrap())
+            .unwrap();
+        assert_eq!(decoded.downcast::<Dock>(), Some(&Dock { station: 7 }));
+
+        assert!(registry.encode(&AnyEvent::new(Local)).is_err());
+    }
+
+    #[test]
+    fn test_network_events() {
+        let registry = network_events();
+        let tick = Tick { time: 2.0, dt: 1.0 };
+        let data = registry.encode(&AnyEvent::new(tick)).unwrap().to_bytes();
+        let decoded = registry
+            .decode(&EncodedEvent::from_bytes(&data).unwrap())
+            .unwrap();
+        assert_eq!(decoded.downcast::<Tick>(), Some(&tick));
+    }
+}
@@ -0,0 +1,249 @@
+//! [`Plugin`] and related types.
+
+use std::collections::{HashMap, HashSet};
+
+use petgraph::algo::kosaraju_scc;
+use petgraph::graph::DiGraph;
+
+use super::reactor::{BuildReactorError, ReactorBuilder};
+
+/// Self-contained part of a game, such as physics or networking, which configures a
+/// [`ReactorBuilder`].
+///
+/// Plugins are added with [`ReactorBuilder::add_plugin`] and built by
+/// [`ReactorBuilder::build`], after every plugin they depend on. A plugin is only built
+/// once, even if it is added several times, such as by several plugins which share a
+/// helper plugin. Plugins which depend on a helper plugin add it from
+/// [`Plugin::add_dependencies`].
+pub trait Plugin: Send + Sync + 'static {
+    /// Unique name of the plugin, which other plugins refer to in their dependencies.
+    fn name(&self) -> &'static str;
+
+    /// Names of the plugins which must be built before this one.
+    fn dependencies(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    /// Add the plugins this one depends on to `builder`.
+    ///
+    /// Called when the plugin is added, so that its dependencies are available when
+    /// plugins are ordered, before any plugin is built.
+    fn add_dependencies(&self, builder: ReactorBuilder) -> ReactorBuilder {
+        builder
+    }
+
+    /// Add the plugin's handlers, default `State`s and configuration to `builder`.
+    fn build(&self, builder: ReactorBuilder) -> ReactorBuilder;
+}
+
+/// Order `pending` plugins so that each comes after its dependencies, which must either
+/// be pending or in `built`.
+pub(super) fn resolve_plugins(
+    pending: Vec<Box<dyn Plugin>>,
+    built: &HashSet<&'static str>,
+) -> Result<Vec<Box<dyn Plugin>>, BuildReactorError> {
+    let mut graph = DiGraph::<usize, ()>::new();
+    let nodes = (0..pending.len())
+        .map(|idx| graph.add_node(idx))
+        .collect::<Vec<_>>();
+    let by_name = pending
+        .iter()
+        .zip(&nodes)
+        .map(|(plugin, &node)| (plugin.name(), node))
+        .collect::<HashMap<_, _>>();
+
+    // Edges point from each plugin to its dependencies.
+    for (plugin, &node) in pending.iter().zip(&nodes) {
+        for dependency in plugin.dependencies() {
+            if let Some(&dependency_node) = by_name.get(dependency) {
+                graph.add_edge(node, dependency_node, ());
+            } else if !built.contains(dependency) {
+                return Err(BuildReactorError::MissingPlugin {
+                    plugin: plugin.name(),
+                    dependency,
+                });
+            }
+        }
+    }
+
+    // Strongly connected components are found in reverse topological order, so
+    // dependencies come first.
+    let mut order = Vec::new();
+    for scc in kosaraju_scc(&graph) {
+        if scc.len() > 1 || graph.contains_edge(scc[0], scc[0]) {
+            let names = scc
+                .iter()
+                .map(|&node| pending[graph[node]].name())
+                .collect();
+            return Err(BuildReactorError::PluginCycle(names));
+        }
+        order.push(graph[scc[0]]);
+    }
+
+    let mut pending = pending.into_iter().map(Some).collect::<Vec<_>>();
+    Ok(order
+        .into_iter()
+        .map(|idx| pending[idx].take().unwrap())
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecs::{InitEvent, Reactor, State, Writer};
+
+    #[derive(Clone, Default, State)]
+    struct Log(Vec<&'static str>);
+
+    #[derive(Clone, Default, State)]
+    struct Gravity(f64);
+
+    /// Plugin which logs its name on init, after its dependencies.
+    struct Logger {
+        name: &'static str,
+        dependencies: Vec<&'static str>,
+        adds: Option<fn() -> Logger>,
+    }
+
+    impl Logger {
+        fn new(name: &'static str, dependencies: &[&'static str]) -> Logger {
+            Logger {
+                name,
+                dependencies: dependencies.to_vec(),
+                adds: None,
+            }
+        }
+    }
+
+    impl Plugin for Logger {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn dependencies(&self) -> Vec<&'static str> {
+            self.dependencies.clone()
+        }
+
+        fn build(&self, mut builder: ReactorBuilder) -> ReactorBuilder {
+            if let Some(adds) = self.adds {
+                builder = builder.add_plugin(adds());
+            }
+            let name = self.name;
+            builder = builder
+                .add(move |_: &InitEvent, mut log: Writer<'_, Log>| {
+                    log.0.push(name);
+                    Ok(())
+                })
+                .label(name);
+            for dependency in &self.dependencies {
+                builder = builder.after(*dependency);
+            }
+            builder
+        }
+    }
+
+    struct Physics;
+
+    impl Plugin for Physics {
+        fn name(&self) -> &'static str {
+            "physics"
+        }
+
+        fn build(&self, builder: ReactorBuilder) -> ReactorBuilder {
+            builder
+                .insert_state(Gravity(9.8))
+                .add(|_: &InitEvent, mut log: Writer<'_, Log>| {
+                    log.0.push("physics");
+                    Ok(())
+                })
+                .label("physics")
+        }
+    }
+
+    /// Plugin which depends on `Physics`, and adds it.
+    struct Render(&'static str);
+
+    impl Plugin for Render {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn dependencies(&self) -> Vec<&'static str> {
+            vec!["physics"]
+        }
+
+        fn add_dependencies(&self, builder: ReactorBuilder) -> ReactorBuilder {
+            builder.add_plugin(Physics)
+        }
+
+        fn build(&self, builder: ReactorBuilder) -> ReactorBuilder {
+            Logger::new(self.0, &["physics"]).build(builder)
+        }
+    }
+
+    fn build_error(builder: ReactorBuilder) -> BuildReactorError {
+        match builder.build() {
+            Ok(_) => panic!("Expected an error"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn test_plugins() {
+        let render = Logger {
+            adds: Some(|| Logger::new("hud", &["render"])),
+            ..Logger::new("render", &["physics"])
+        };
+        let builder = Reactor::builder()
+            .add_plugin(render)
+            .add_plugin(Physics)
+            .add_plugin(Physics);
+        assert!(builder.has_plugin("physics"));
+        assert!(!builder.has_plugin("hud"));
+
+        let reactor = builder.build().unwrap();
+        let states = reactor.new_state_container();
+        assert_eq!(states.get::<Log>().unwrap().0, ["physics", "render", "hud"]);
+        assert_eq!(states.get::<Gravity>().unwrap().0, 9.8);
+    }
+
+    #[test]
+    fn test_plugin_adds_dependencies() {
+        // Both plugins add the `Physics` they depend on, which is built once, first.
+        let builder = Reactor::builder()
+            .add_plugin(Render("render"))
+            .add_plugin(Render("hud"));
+        assert!(builder.has_plugin("physics"));
+
+        let reactor = builder.build().unwrap();
+        let states = reactor.new_state_container();
+        let mut log = states.get::<Log>().unwrap().0.clone();
+        assert_eq!(log[0], "physics");
+        log.sort();
+        assert_eq!(log, ["hud", "physics", "render"]);
+    }
+
+    #[test]
+    fn test_plugin_errors() {
+        let err = build_error(Reactor::builder().add_plugin(Logger::new("render", &["physics"])));
+        assert!(matches!(
+            err,
+            BuildReactorError::MissingPlugin {
+                plugin: "render",
+                dependency: "physics"
+            }
+        ));
+
+        let err = build_error(
+            Reactor::builder()
+                .add_plugin(Physics)
+                .add_plugin(Logger::new("a", &["physics", "b"]))
+                .add_plugin(Logger::new("b", &["a"])),
+        );
+        let BuildReactorError::PluginCycle(mut names) = err else {
+            panic!("Expected a cycle, got {err}");
+        };
+        names.sort();
+        assert_eq!(names, ["a", "b"]);
+    }
+}
@@ -0,0 +1,936 @@
+//! [`Query`] and related types.
+
+use std::iter::Copied;
+use std::marker::PhantomData;
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::format_err;
+use atomic_refcell::{AtomicRef, AtomicRefMut};
+
+use super::entity::{Archetype, ArchetypeId, Component, EntityId, EntityState, MissingEntityError};
+use super::handler::{
+    Context, Dependency, HandlerFnArg, HandlerFnArgBuilder, ReadOnlyHandlerFnArg,
+};
+use super::state::{Changed, State};
+
+/// Trait for types which a [`Query`] can fetch for each entity.
+///
+/// Implemented for `&C`, `&mut C`, `Option<&C>` and `Option<&mut C>` where `C` is a
+/// [`Component`], for [`EntityId`], and for tuples of these.
+pub trait QueryData {
+    /// Value fetched for each entity.
+    type Item<'a>;
+    /// Columns borrowed from a single archetype.
+    type Borrow<'w>;
+    /// Iterator over the items of a single archetype.
+    type Iter<'a>: Iterator<Item = Self::Item<'a>>;
+
+    /// Append the [`Dependency`]s needed to fetch this data.
+    fn dependencies(out: &mut Vec<Dependency>);
+    /// Returns true if the entities in `archetype` have this data.
+    fn matches(archetype: &Archetype) -> bool;
+    /// Borrow the columns needed from a matching `archetype`. Columns accessed
+    /// mutably are marked changed at `tick`.
+    fn borrow(archetype: &Archetype, tick: u64) -> anyhow::Result<Self::Borrow<'_>>;
+    /// Iterate over every row of a borrowed archetype.
+    fn iter_mut<'a>(borrow: &'a mut Self::Borrow<'_>) -> Self::Iter<'a>;
+    /// Fetch a single row of a borrowed archetype.
+    fn fetch_mut<'a>(borrow: &'a mut Self::Borrow<'_>, row: usize) -> Self::Item<'a>;
+}
+
+/// [`QueryData`] which only reads, and so can be fetched through a shared reference.
+pub trait ReadOnlyQueryData: QueryData {
+    /// Iterate over every row of a borrowed archetype.
+    fn iter<'a>(borrow: &'a Self::Borrow<'_>) -> Self::Iter<'a>;
+    /// Fetch a single row of a borrowed archetype.
+    fn fetch<'a>(borrow: &'a Self::Borrow<'_>, row: usize) -> Self::Item<'a>;
+}
+
+/// Trait for types which restrict the entities matched by a [`Query`].
+///
+/// Implemented for [`With`], [`Without`], [`Changed`], and tuples of filters, which
+/// match if all of their elements match.
+pub trait QueryFilter {
+    /// Append the [`Dependency`]s needed to evaluate this filter.
+    fn dependencies(out: &mut Vec<Dependency>);
+    /// Returns true if the entities in `archetype` pass this filter, given the change
+    /// tick the handler last ran at.
+    fn matches(archetype: &Archetype, last_run: u64) -> bool;
+}
+
+/// [`QueryFilter`] which matches entities that have `Component` `C`.
+pub struct With<C>(PhantomData<C>);
+
+impl<C: Component> QueryFilter for With<C> {
+    fn dependencies(_out: &mut Vec<Dependency>) {}
+
+    fn matches(archetype: &Archetype, _last_run: u64) -> bool {
+        archetype.has::<C>()
+    }
+}
+
+/// [`QueryFilter`] which matches entities that don't have `Component` `C`.
+pub struct Without<C>(PhantomData<C>);
+
+impl<C: Component> QueryFilter for Without<C> {
+    fn dependencies(_out: &mut Vec<Dependency>) {}
+
+    fn matches(archetype: &Archetype, _last_run: u64) -> bool {
+        !archetype.has::<C>()
+    }
+}
+
+/// Matches entities whose archetype's column for `Component` `C` changed since the
+/// handler last ran. Changes are tracked per column, so every entity sharing an
+/// archetype with a changed entity also matches.
+impl<C: Component> QueryFilter for Changed<C> {
+    fn dependencies(out: &mut Vec<Dependency>) {
+        out.push(Dependency::ReadComponent(C::id()));
+    }
+
+    fn matches(archetype: &Archetype, last_run: u64) -> bool {
+        archetype
+            .change_tick::<C>()
+            .is_some_and(|tick| tick > last_run)
+    }
+}
+
+/// Handler argument used to access the `Component`s of every entity matching `Q` and `F`.
+///
+/// Declares a dependency on each `Component` it accesses rather than the whole
+/// [`EntityState`], so handlers are only ordered relative to handlers which touch
+/// the same `Component`s.
+pub struct Query<'w, Q: QueryData, F: QueryFilter = ()> {
+    /// The `EntityState` being queried.
+    entities: &'w EntityState,
+    /// Borrowed columns of every matching archetype.
+    archetypes: Vec<(ArchetypeId, Q::Borrow<'w>)>,
+    /// Marker for the filter type.
+    filter: PhantomData<F>,
+}
+
+impl<'w, Q: QueryData, F: QueryFilter> Query<'w, Q, F> {
+    /// Iterate over the data of every matching entity.
+    pub fn iter_mut(&mut self) -> QueryIterMut<'_, 'w, Q> {
+        QueryIterMut {
+            archetypes: self.archetypes.iter_mut(),
+            current: None,
+        }
+    }
+
+    /// Get the data of `entity`, if it matches.
+    pub fn get_mut(&mut self, entity: EntityId) -> Option<Q::Item<'_>> {
+        let (archetype, row) = self.entities.location(entity)?;
+        let (_, borrow) = self
+            .archetypes
+            .iter_mut()
+            .find(|(id, _)| *id == archetype)?;
+        Some(Q::fetch_mut(borrow, row))
+    }
+}
+
+impl<'w, Q: ReadOnlyQueryData, F: QueryFilter> Query<'w, Q, F> {
+    /// Iterate over the data of every matching entity.
+    pub fn iter(&self) -> QueryIter<'_, 'w, Q> {
+        QueryIter {
+            archetypes: self.archetypes.iter(),
+            current: None,
+        }
+    }
+
+    /// Get the data of `entity`, if it matches.
+    pub fn get(&self, entity: EntityId) -> Option<Q::Item<'_>> {
+        let (archetype, row) = self.entities.location(entity)?;
+        let (_, borrow) = self.archetypes.iter().find(|(id, _)| *id == archetype)?;
+        Some(Q::fetch(borrow, row))
+    }
+}
+
+/// Iterator returned by [`Query::iter_mut`].
+pub struct QueryIterMut<'a, 'w, Q: QueryData> {
+    /// Archetypes which haven't been iterated yet.
+    archetypes: slice::IterMut<'a, (ArchetypeId, Q::Borrow<'w>)>,
+    /// Iterator over the current archetype.
+    current: Option<Q::Iter<'a>>,
+}
+
+impl<'a, 'w, Q: QueryData> Iterator for QueryIterMut<'a, 'w, Q> {
+    type Item = Q::Item<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.current.as_mut().and_then(Iterator::next) {
+                return Some(item);
+            }
+
+            let (_, borrow) = self.archetypes.next()?;
+            self.current = Some(Q::iter_mut(borrow));
+        }
+    }
+}
+
+/// Iterator returned by [`Query::iter`].
+pub struct QueryIter<'a, 'w, Q: QueryData> {
+    /// Archetypes which haven't been iterated yet.
+    archetypes: slice::Iter<'a, (ArchetypeId, Q::Borrow<'w>)>,
+    /// Iterator over the current archetype.
+    current: Option<Q::Iter<'a>>,
+}
+
+impl<'a, 'w, Q: ReadOnlyQueryData> Iterator for QueryIter<'a, 'w, Q> {
+    type Item = Q::Item<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.current.as_mut().and_then(Iterator::next) {
+                return Some(item);
+            }
+
+            let (_, borrow) = self.archetypes.next()?;
+            self.current = Some(Q::iter(borrow));
+        }
+    }
+}
+
+impl<'w, Q: QueryData, F: QueryFilter> HandlerFnArg for Query<'w, Q, F> {
+    type Builder = QueryBuilder<Q, F>;
+
+    fn dependencies(out: &mut Vec<Dependency>) {
+        // Reading the `EntityState` orders queries after handlers which change
+        // its structure, such as by creating entities.
+        out.push(Dependency::ReadState(EntityState::id()));
+        Q::dependencies(out);
+        F::dependencies(out);
+    }
+}
+
+impl<'w, Q: ReadOnlyQueryData, F: QueryFilter> ReadOnlyHandlerFnArg for Query<'w, Q, F> {}
+
+#[doc(hidden)]
+pub struct QueryBuilder<Q, F>(PhantomData<(Q, F)>);
+
+impl<'c, Q: QueryData, F: QueryFilter> HandlerFnArgBuilder<'c> for QueryBuilder<Q, F> {
+    type Arg = Query<'c, Q, F>;
+
+    fn build(context: &'c Context) -> anyhow::Result<Query<'c, Q, F>> {
+        let entities = context
+            .entities
+            .ok_or_else(|| format_err!("Missing state `{}` for Query", EntityState::id()))?;
+
+        let archetypes = entities
+            .archetypes_with_ids()
+            .filter(|(_, archetype)| {
+                Q::matches(archetype) && F::matches(archetype, context.last_run)
+            })
+            .map(|(id, archetype)| Ok((id, Q::borrow(archetype, context.tick)?)))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Query {
+            entities,
+            archetypes,
+            filter: PhantomData,
+        })
+    }
+}
+
+/// Handler argument used to access the `Component`s of the target entity of an
+/// [`EntityEvent`](super::EntityEvent), for handlers added with
+/// [`ReactorBuilder::add_entity`](super::ReactorBuilder::add_entity).
+///
+/// The handler only runs if the target exists and matches `Q` and `F`, so handlers
+/// can subscribe to the events of entities with a given set of `Component`s.
+pub struct Target<'w, Q: QueryData, F: QueryFilter = ()> {
+    /// The target entity.
+    entity: EntityId,
+    /// Borrowed columns of the target's archetype.
+    borrow: Q::Borrow<'w>,
+    /// Row of the target within its archetype.
+    row: usize,
+    /// Marker for the filter type.
+    filter: PhantomData<F>,
+}
+
+impl<'w, Q: QueryData, F: QueryFilter> Target<'w, Q, F> {
+    /// The target entity.
+    pub fn entity(&self) -> EntityId {
+        self.entity
+    }
+
+    /// Get the data of the target entity.
+    pub fn get_mut(&mut self) -> Q::Item<'_> {
+        Q::fetch_mut(&mut self.borrow, self.row)
+    }
+}
+
+impl<'w, Q: ReadOnlyQueryData, F: QueryFilter> Target<'w, Q, F> {
+    /// Get the data of the target entity.
+    pub fn get(&self) -> Q::Item<'_> {
+        Q::fetch(&self.borrow, self.row)
+    }
+}
+
+impl<'w, Q: QueryData, F: QueryFilter> HandlerFnArg for Target<'w, Q, F> {
+    type Builder = TargetBuilder<Q, F>;
+
+    fn dependencies(out: &mut Vec<Dependency>) {
+        out.push(Dependency::ReadState(EntityState::id()));
+        Q::dependencies(out);
+        F::dependencies(out);
+    }
+
+    fn should_run(context: &Context) -> anyhow::Result<bool> {
+        let (Some(entities), Some(target)) = (context.entities, context.target) else {
+            return Ok(false);
+        };
+        Ok(entities.location(target).is_some_and(|(id, _)| {
+            let archetype = entities.archetype(id);
+            Q::matches(archetype) && F::matches(archetype, context.last_run)
+        }))
+    }
+
+    fn requires_target() -> bool {
+        true
+    }
+}
+
+impl<'w, Q: ReadOnlyQueryData, F: QueryFilter> ReadOnlyHandlerFnArg for Target<'w, Q, F> {}
+
+#[doc(hidden)]
+pub struct TargetBuilder<Q, F>(PhantomData<(Q, F)>);
+
+impl<'c, Q: QueryData, F: QueryFilter> HandlerFnArgBuilder<'c> for TargetBuilder<Q, F> {
+    type Arg = Target<'c, Q, F>;
+
+    fn build(context: &'c Context) -> anyhow::Result<Target<'c, Q, F>> {
+        let entities = context
+            .entities
+            .ok_or_else(|| format_err!("Missing state `{}` for Target", EntityState::id()))?;
+        let entity = context
+            .target
+            .ok_or_else(|| format_err!("Target used by a handler without a target entity"))?;
+        let (archetype, row) = entities
+            .location(entity)
+            .ok_or(MissingEntityError(entity))?;
+
+        Ok(Target {
+            entity,
+            borrow: Q::borrow(entities.archetype(archetype), context.tick)?,
+            row,
+            filter: PhantomData,
+        })
+    }
+}
+
+/// Error for a column which is missing from an archetype that should have matched.
+fn missing_column<C: Component>() -> anyhow::Error {
+    format_err!("Missing column for component `{}`", C::id())
+}
+
+impl<C: Component> QueryData for &C {
+    type Item<'a> = &'a C;
+    type Borrow<'w> = AtomicRef<'w, Vec<C>>;
+    type Iter<'a> = slice::Iter<'a, C>;
+
+    fn dependencies(out: &mut Vec<Dependency>) {
+        out.push(Dependency::ReadComponent(C::id()));
+    }
+
+    fn matches(archetype: &Archetype) -> bool {
+        archetype.has::<C>()
+    }
+
+    fn borrow(archetype: &Archetype, _tick: u64) -> anyhow::Result<Self::Borrow<'_>> {
+        archetype
+            .try_borrow_column()
+            .ok_or_else(missing_column::<C>)?
+    }
+
+    fn iter_mut<'a>(borrow: &'a mut Self::Borrow<'_>) -> Self::Iter<'a> {
+        Self::iter(borrow)
+    }
+
+    fn fetch_mut<'a>(borrow: &'a mut Self::Borrow<'_>, row: usize) -> Self::Item<'a> {
+        Self::fetch(borrow, row)
+    }
+}
+
+impl<C: Component> ReadOnlyQueryData for &C {
+    fn iter<'a>(borrow: &'a Self::Borrow<'_>) -> Self::Iter<'a> {
+        borrow.iter()
+    }
+
+    fn fetch<'a>(borrow: &'a Self::Borrow<'_>, row: usize) -> Self::Item<'a> {
+        &borrow[row]
+    }
+}
+
+impl<C: Component> QueryData for &mut C {
+    type Item<'a> = &'a mut C;
+    /// The column, its change tick, and the tick to mark it changed at.
+    type Borrow<'w> = (AtomicRefMut<'w, Vec<C>>, &'w AtomicU64, u64);
+    type Iter<'a> = slice::IterMut<'a, C>;
+
+    fn dependencies(out: &mut Vec<Dependency>) {
+        out.push(Dependency::WriteComponent(C::id()));
+    }
+
+    fn matches(archetype: &Archetype) -> bool {
+        archetype.has::<C>()
+    }
+
+    fn borrow(archetype: &Archetype, tick: u64) -> anyhow::Result<Self::Borrow<'_>> {
+        let column = archetype
+            .try_borrow_column_mut()
+            .ok_or_else(missing_column::<C>)??;
+        let change_tick = archetype
+            .change_tick_cell::<C>()
+            .ok_or_else(missing_column::<C>)?;
+        Ok((column, change_tick, tick))
+    }
+
+    fn iter_mut<'a>(borrow: &'a mut Self::Borrow<'_>) -> Self::Iter<'a> {
+        let (column, change_tick, tick) = borrow;
+        if !column.is_empty() {
+            change_tick.store(*tick, Ordering::Relaxed);
+        }
+        column.iter_mut()
+    }
+
+    fn fetch_mut<'a>(borrow: &'a mut Self::Borrow<'_>, row: usize) -> Self::Item<'a> {
+        let (column, change_tick, tick) = borrow;
+        change_tick.store(*tick, Ordering::Relaxed);
+        &mut column[row]
+    }
+}
+
+/// Iterator for optional [`QueryData`] which yields `None` for archetypes without the data.
+pub enum OptionalIter<I> {
+    /// The archetype has the data.
+    Present(I),
+    /// The archetype doesn't have the data. Holds the number of rows remaining.
+    Absent(usize),
+}
+
+impl<I: Iterator> Iterator for OptionalIter<I> {
+    type Item = Option<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            OptionalIter::Present(iter) => iter.next().map(Some),
+            OptionalIter::Absent(0) => None,
+            OptionalIter::Absent(remaining) => {
+                *remaining -= 1;
+                Some(None)
+            }
+        }
+    }
+}
+
+impl<T: QueryData> QueryData for Option<T> {
+    type Item<'a> = Option<T::Item<'a>>;
+    /// The borrowed data if present, and the number of rows in the archetype.
+    type Borrow<'w> = (Option<T::Borrow<'w>>, usize);
+    type Iter<'a> = OptionalIter<T::Iter<'a>>;
+
+    fn dependencies(out: &mut Vec<Dependency>) {
+        T::dependencies(out);
+    }
+
+    fn matches(_archetype: &Archetype) -> bool {
+        true
+    }
+
+    fn borrow(archetype: &Archetype, tick: u64) -> anyhow::Result<Self::Borrow<'_>> {
+        let borrow = if T::matches(archetype) {
+            Some(T::borrow(archetype, tick)?)
+        } else {
+            None
+        };
+
+        Ok((borrow, archetype.entities().len()))
+    }
+
+    fn iter_mut<'a>(borrow: &'a mut Self::Borrow<'_>) -> Self::Iter<'a> {
+        match borrow {
+            (Some(borrow), _) => OptionalIter::Present(T::iter_mut(borrow)),
+            (None, len) => OptionalIter::Absent(*len),
+        }
+    }
+
+    fn fetch_mut<'a>(borrow: &'a mut Self::Borrow<'_>, row: usize) -> Self::Item<'a> {
+        borrow.0.as_mut().map(|borrow| T::fetch_mut(borrow, row))
+    }
+}
+
+impl<T: ReadOnlyQueryData> ReadOnlyQueryData for Option<T> {
+    fn iter<'a>(borrow: &'a Self::Borrow<'_>) -> Self::Iter<'a> {
+        match borrow {
+            (Some(borrow), _) => OptionalIter::Present(T::iter(borrow)),
+            (None, len) => OptionalIter::Absent(*len),
+        }
+    }
+
+    fn fetch<'a>(borrow: &'a Self::Borrow<'_>, row: usize) -> Self::Item<'a> {
+        borrow.0.as_ref().map(|borrow| T::fetch(borrow, row))
+    }
+}
+
+impl QueryData for EntityId {
+    type Item<'a> = EntityId;
+    type Borrow<'w> = &'w [EntityId];
+    type Iter<'a> = Copied<slice::Iter<'a, EntityId>>;
+
+    fn dependencies(_out: &mut Vec<Dependency>) {}
+
+    fn matches(_archetype: &Archetype) -> bool {
+        true
+    }
+
+    fn borrow(archetype: &Archetype, _tick: u64) -> anyhow::Result<Self::Borrow<'_>> {
+        Ok(archetype.entities())
+    }
+
+    fn iter_mut<'a>(borrow: &'a mut Self::Borrow<'_>) -> Self::Iter<'a> {
+        Self::iter(borrow)
+    }
+
+    fn fetch_mut<'a>(borrow: &'a mut Self::Borrow<'_>, row: usize) -> Self::Item<'a> {
+        Self::fetch(borrow, row)
+    }
+}
+
+impl ReadOnlyQueryData for EntityId {
+    fn iter<'a>(borrow: &'a Self::Borrow<'_>) -> Self::Iter<'a> {
+        borrow.iter().copied()
+    }
+
+    fn fetch<'a>(borrow: &'a Self::Borrow<'_>, row: usize) -> Self::Item<'a> {
+        borrow[row]
+    }
+}
+
+impl QueryFilter for () {
+    fn dependencies(_out: &mut Vec<Dependency>) {}
+
+    fn matches(_archetype: &Archetype, _last_run: u64) -> bool {
+        true
+    }
+}
+
+/// Iterator for tuples of [`QueryData`], which advances each element in lockstep.
+pub struct TupleIter<T>(T);
+
+/// Implements [`QueryData`] and [`QueryFilter`] for a tuple of the given arity.
+macro_rules! impl_query_tuple {
+    ($($T:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$($T: Iterator),*> Iterator for TupleIter<($($T,)*)> {
+            type Item = ($($T::Item,)*);
+
+            fn next(&mut self) -> Option<Self::Item> {
+                let ($($T,)*) = &mut self.0;
+                Some(($($T.next()?,)*))
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<$($T: QueryData),*> QueryData for ($($T,)*) {
+            type Item<'a> = ($($T::Item<'a>,)*);
+            type Borrow<'w> = ($($T::Borrow<'w>,)*);
+            type Iter<'a> = TupleIter<($($T::Iter<'a>,)*)>;
+
+            fn dependencies(out: &mut Vec<Dependency>) {
+                $($T::dependencies(out);)*
+            }
+
+            fn matches(archetype: &Archetype) -> bool {
+                $($T::matches(archetype))&&*
+            }
+
+            fn borrow(archetype: &Archetype, tick: u64) -> anyhow::Result<Self::Borrow<'_>> {
+                Ok(($($T::borrow(archetype, tick)?,)*))
+            }
+
+            fn iter_mut<'a>(borrow: &'a mut Self::Borrow<'_>) -> Self::Iter<'a> {
+                let ($($T,)*) = borrow;
+                TupleIter(($($T::iter_mut($T),)*))
+            }
+
+            fn fetch_mut<'a>(borrow: &'a mut Self::Borrow<'_>, row: usize) -> Self::Item<'a> {
+                let ($($T,)*) = borrow;
+                ($($T::fetch_mut($T, row),)*)
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<$($T: ReadOnlyQueryData),*> ReadOnlyQueryData for ($($T,)*) {
+            fn iter<'a>(borrow: &'a Self::Borrow<'_>) -> Self::Iter<'a> {
+                let ($($T,)*) = borrow;
+                TupleIter(($($T::iter($T),)*))
+            }
+
+            fn fetch<'a>(borrow: &'a Self::Borrow<'_>, row: usize) -> Self::Item<'a> {
+                let ($($T,)*) = borrow;
+                ($($T::fetch($T, row),)*)
+            }
+        }
+
+        impl<$($T: QueryFilter),*> QueryFilter for ($($T,)*) {
+            fn dependencies(out: &mut Vec<Dependency>) {
+                $($T::dependencies(out);)*
+            }
+
+            fn matches(archetype: &Archetype, last_run: u64) -> bool {
+                $($T::matches(archetype, last_run))&&*
+            }
+        }
+    };
+}
+
+impl_query_tuple!(A1);
+impl_query_tuple!(A1, A2);
+impl_query_tuple!(A1, A2, A3);
+impl_query_tuple!(A1, A2, A3, A4);
+impl_query_tuple!(A1, A2, A3, A4, A5);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecs::{
+        BuildReactorError, DispatchMode, Event, InitEvent, Reactor, Reader, State, Writer,
+    };
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Position(f64);
+    impl Component for Position {}
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Velocity(f64);
+    impl Component for Velocity {}
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Frozen;
+    impl Component for Frozen {}
+
+    #[derive(Debug)]
+    struct Step;
+    impl Event for Step {}
+
+    fn spawn(_: &InitEvent, mut entities: Writer<'_, EntityState>) -> anyhow::Result<()> {
+        for i in 0..4 {
+            let entity = entities.spawn();
+            entities.insert(entity, Position(i as f64))?;
+            if i % 2 == 0 {
+                entities.insert(entity, Velocity(1.0))?;
+            }
+            if i == 3 {
+                entities.insert(entity, Frozen)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn integrate(
+        _: &Step,
+        mut query: Query<'_, (&mut Position, &Velocity), Without<Frozen>>,
+    ) -> anyhow::Result<()> {
+        for (position, velocity) in query.iter_mut() {
+            position.0 += velocity.0;
+        }
+        Ok(())
+    }
+
+    fn accelerate(_: &Step, mut query: Query<'_, &mut Velocity>) -> anyhow::Result<()> {
+        for velocity in query.iter_mut() {
+            velocity.0 *= 2.0;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_query() {
+        // `integrate` reads `Velocity`, so it must run after `accelerate` even
+        // though it was added first.
+        let reactor = Reactor::builder()
+            .add(spawn)
+            .add(integrate)
+            .add(accelerate)
+            .build()
+            .unwrap();
+
+        let states = reactor.new_state_container();
+        reactor.dispatch(&states, Step).unwrap();
+
+        let entities = states.get::<EntityState>().unwrap();
+        let mut positions = entities
+            .entities()
+            .map(|e| entities.get::<Position>(e).unwrap().0)
+            .collect::<Vec<_>>();
+        positions.sort_by(f64::total_cmp);
+        assert_eq!(positions, vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_query_get_and_optional() {
+        fn check(
+            _: &Step,
+            query: Query<'_, (EntityId, &Position, Option<&Velocity>)>,
+            frozen: Query<'_, EntityId, (With<Frozen>, With<Position>)>,
+        ) -> anyhow::Result<()> {
+            let mut with_velocity = query
+                .iter()
+                .filter(|(_, _, velocity)| velocity.is_some())
+                .map(|(_, position, _)| position.0)
+                .collect::<Vec<_>>();
+            with_velocity.sort_by(f64::total_cmp);
+            assert_eq!(with_velocity, vec![0.0, 2.0]);
+
+            let frozen = frozen.iter().collect::<Vec<_>>();
+            assert_eq!(frozen.len(), 1);
+            let (entity, position, velocity) = query.get(frozen[0]).unwrap();
+            assert_eq!(entity, frozen[0]);
+            assert_eq!(position, &Position(3.0));
+            assert_eq!(velocity, None);
+            Ok(())
+        }
+
+        let reactor = Reactor::builder().add(spawn).add(check).build().unwrap();
+        let states = reactor.new_state_container();
+        reactor.dispatch(&states, Step).unwrap();
+    }
+
+    #[test]
+    fn test_changed_filter() {
+        #[derive(Clone, Default)]
+        struct Moved(Vec<usize>);
+        impl State for Moved {}
+
+        #[derive(Debug)]
+        struct Check;
+        impl Event for Check {}
+
+        fn check(
+            _: &Check,
+            query: Query<'_, &Position, Changed<Position>>,
+            mut moved: Writer<'_, Moved>,
+        ) -> anyhow::Result<()> {
+            moved.0.push(query.iter().count());
+            Ok(())
+        }
+
+        let reactor = Reactor::builder()
+            .add(spawn)
+            .add(integrate)
+            .add(check)
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+
+        reactor.dispatch(&states, Check).unwrap();
+        reactor.dispatch(&states, Check).unwrap();
+
+        // Only the archetype with `Velocity` and without `Frozen` is integrated.
+        reactor.dispatch(&states, Step).unwrap();
+        reactor.dispatch(&states, Check).unwrap();
+        reactor.dispatch(&states, Check).unwrap();
+
+        assert_eq!(states.get::<Moved>().unwrap().0, [4, 0, 2, 0]);
+    }
+
+    #[test]
+    fn test_changed_filter_dependency() {
+        use std::sync::{Arc, Mutex};
+
+        // `moved` only uses `Position` in its filter, which must still order it after
+        // `integrate`.
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let moved = {
+            let log = log.clone();
+            move |_: &Step, query: Query<'_, EntityId, Changed<Position>>| {
+                log.lock().unwrap().push(("moved", query.iter().count()));
+                Ok(())
+            }
+        };
+        let integrate = {
+            let log = log.clone();
+            move |ev: &Step, query: Query<'_, (&mut Position, &Velocity), Without<Frozen>>| {
+                log.lock().unwrap().push(("integrate", 0));
+                integrate(ev, query)
+            }
+        };
+
+        let reactor = Reactor::builder()
+            .add(spawn)
+            .add(integrate)
+            .add(moved)
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+        reactor.dispatch(&states, Step).unwrap();
+        reactor.dispatch(&states, Step).unwrap();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                ("integrate", 0),
+                ("moved", 4),
+                ("integrate", 0),
+                ("moved", 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reader_after_query() {
+        #[derive(Clone, Default, State)]
+        struct Log(Vec<f64>);
+
+        // Reads `Position` through the `EntityState` rather than a `Query`, so it must
+        // still run after `integrate` rather than alongside it.
+        fn total(
+            _: &Step,
+            entities: Reader<'_, EntityState>,
+            mut log: Writer<'_, Log>,
+        ) -> anyhow::Result<()> {
+            let total = entities
+                .entities()
+                .map(|e| entities.get::<Position>(e).unwrap().0)
+                .sum();
+            log.0.push(total);
+            Ok(())
+        }
+
+        let reactor = Reactor::builder()
+            .dispatch_mode(DispatchMode::Parallel)
+            .add(spawn)
+            .add(total)
+            .add(integrate)
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+        for _ in 0..3 {
+            let report = reactor.dispatch(&states, Step).unwrap();
+            assert!(report.failures.is_empty());
+        }
+        assert_eq!(states.get::<Log>().unwrap().0, [8.0, 10.0, 12.0]);
+    }
+
+    #[test]
+    fn test_target() {
+        #[derive(Clone, Default, State)]
+        struct Log(Vec<f64>);
+
+        #[derive(Debug, Event)]
+        #[event(target = entity)]
+        struct Collision {
+            entity: EntityId,
+        }
+
+        fn bounce(
+            _: &Collision,
+            mut target: Target<'_, &mut Velocity, Without<Frozen>>,
+        ) -> anyhow::Result<()> {
+            target.get_mut().0 *= -1.0;
+            Ok(())
+        }
+
+        fn log_frozen(
+            _: &Collision,
+            target: Target<'_, &Position, With<Frozen>>,
+            mut log: Writer<'_, Log>,
+        ) -> anyhow::Result<()> {
+            log.0.push(target.get().0);
+            Ok(())
+        }
+
+        let reactor = Reactor::builder()
+            .add(spawn)
+            .add_entity::<Collision, _>(bounce)
+            .add_entity::<Collision, _>(log_frozen)
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+        let find = |x: f64| {
+            let entities = states.get::<EntityState>().unwrap();
+            let entity = entities
+                .entities()
+                .find(|&e| entities.get::<Position>(e).unwrap().0 == x);
+            entity.unwrap()
+        };
+        let (moving, still, frozen) = (find(0.0), find(1.0), find(3.0));
+
+        for entity in [moving, still, frozen, moving, frozen] {
+            reactor.dispatch(&states, Collision { entity }).unwrap();
+        }
+
+        let entities = states.get::<EntityState>().unwrap();
+        assert_eq!(entities.get::<Velocity>(moving).unwrap().0, 1.0);
+        assert_eq!(entities.get::<Velocity>(find(2.0)).unwrap().0, 1.0);
+        assert_eq!(states.get::<Log>().unwrap().0, [3.0, 3.0]);
+        drop(entities);
+
+        // Events targeting a missing entity are ignored.
+        let mut entities = states.get_mut::<EntityState>().unwrap();
+        entities.despawn(moving);
+        drop(entities);
+        reactor
+            .dispatch(&states, Collision { entity: moving })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_target_without_add_entity() {
+        #[derive(Debug, Event)]
+        #[event(target = entity)]
+        struct Collision {
+            entity: EntityId,
+        }
+
+        fn bounce(_: &Collision, mut target: Target<'_, &mut Velocity>) -> anyhow::Result<()> {
+            target.get_mut().0 *= -1.0;
+            Ok(())
+        }
+
+        // The handler would never run, since it has no target entity.
+        let result = Reactor::builder().add(bounce).build();
+        assert!(matches!(result, Err(BuildReactorError::MissingTarget(_))));
+
+        // Conditions which take a `Target` also need one.
+        let result = Reactor::builder()
+            .add(|_: &Collision| Ok(()))
+            .run_if(|target: Target<'_, &Velocity>| target.get().0 > 0.0)
+            .build();
+        assert!(matches!(result, Err(BuildReactorError::MissingTarget(_))));
+    }
+
+    #[test]
+    fn test_query_conflicting_borrow() {
+        fn conflicting(
+            _: &Step,
+            _a: Query<'_, &mut Position>,
+            _b: Query<'_, &Position>,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        let reactor = Reactor::builder().add(spawn).build().unwrap();
+        let states = reactor.new_state_container();
+        let entities = states.get::<EntityState>().unwrap();
+        let context = Context {
+            states: &states,
+            entities: Some(&entities),
+            queue: &Default::default(),
+            handles: crate::ecs::schedule::HandleAllocator::new(0),
+            commands: &Default::default(),
+            topics: &Default::default(),
+            event: &crate::ecs::AnyEvent::new(Step),
+            target: None,
+            tick: 1,
+            last_run: 0,
+        };
+
+        let handler = crate::ecs::EventHandlerFn::<Step, _>::into_handler(conflicting);
+        assert!(handler.call(&context).is_err());
+    }
+}
@@ -1,37 +1,209 @@
 //! [`Reactor`] and related types.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Display;
+use std::panic::Location;
+use std::sync::Arc;
 
-use log::error;
+use log::{error, warn};
 use petgraph::algo::kosaraju_scc;
-use petgraph::graph::DiGraph;
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::visit::EdgeRef;
+use rayon::prelude::*;
+use serde::Serialize;
 use thiserror::Error;
 
+use crate::ecs::clock::SimClock;
+use crate::ecs::command::CommandQueue;
+use crate::ecs::entity::{Component, ComponentId, EntityState};
 use crate::ecs::handler::Dependency;
 use crate::ecs::state::StateId;
 use crate::ecs::topic::TopicId;
 
-use super::event::{AnyEvent, Event, EventId, EventQueue};
-use super::handler::{Context, EventHandlerFn, Handler, HandlerFn};
-use super::state::StateContainer;
+use super::event::{AnyEvent, EntityEvent, EventId, EventQueue};
+use super::handler::{ConditionFn, Context, EventHandlerFn, Handler, HandlerFn};
+use super::lifecycle::{OnAdd, OnDespawn, OnRemove};
+use super::plugin::{resolve_plugins, Plugin};
+use super::schedule::{HandleAllocator, Scheduler, Tick};
+use super::state::{next_change_tick, AnyState, State, StateContainer};
+use super::testing::DispatchTrace;
 use super::topic::TopicContainer;
+use super::Event;
 
 /// `Event` which is fired at init time, which [`Handler`]s can use to initialize their state.
-#[derive(Debug)]
+#[derive(Debug, Event)]
 pub struct InitEvent;
-impl Event for InitEvent {}
+
+/// Controls how a [`Reactor`] executes the handlers for an event.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DispatchMode {
+    /// Run handlers one at a time on the calling thread.
+    #[default]
+    Serial,
+    /// Run independent handlers concurrently on the rayon thread pool.
+    ///
+    /// The results are identical to [`DispatchMode::Serial`].
+    Parallel,
+}
+
+/// Controls what a [`Reactor`] does when a handler returns an error.
+///
+/// Every failure is listed in the [`DispatchReport`] regardless of the policy.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ErrorPolicy {
+    /// Log the error and continue dispatching.
+    #[default]
+    Continue,
+    /// Log the error and stop dispatching. Events emitted so far are discarded.
+    ///
+    /// Handlers which don't depend on the failing handler may still run for the
+    /// current event, so that the result is the same in every [`DispatchMode`].
+    AbortCascade,
+    /// Log the error and continue dispatching, but stop calling the handler once it
+    /// has failed this many times with the same `StateContainer`. The limit must be at
+    /// least 1.
+    DisableAfter(u32),
+    /// Stop dispatching like [`ErrorPolicy::AbortCascade`], and return a
+    /// [`DispatchError`] from [`Reactor::dispatch`] instead of logging the error.
+    Propagate,
+}
+
+/// Describes a handler which returned an error during [`Reactor::dispatch`].
+#[derive(Debug)]
+pub struct HandlerFailure {
+    /// Name and location of the handler.
+    pub handler: String,
+    /// The `Event` being handled.
+    pub event: EventId,
+    /// The error returned by the handler.
+    pub error: anyhow::Error,
+    /// True if the handler was disabled by [`ErrorPolicy::DisableAfter`] due to this failure.
+    pub disabled: bool,
+}
+
+impl Display for HandlerFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Handler '{}' failed while handling {}: {}",
+            self.handler, self.event, self.error
+        )
+    }
+}
+
+/// Summary of a call to [`Reactor::dispatch`].
+#[derive(Debug, Default)]
+pub struct DispatchReport {
+    /// Every handler which failed, in the order they ran.
+    pub failures: Vec<HandlerFailure>,
+    /// Number of events processed, including the initial event.
+    pub events_processed: usize,
+    /// Number of handler calls, including calls which failed.
+    pub handlers_run: usize,
+    /// True if dispatch stopped early due to [`ErrorPolicy::AbortCascade`],
+    /// [`ErrorPolicy::Propagate`] or a [`DispatchError`].
+    pub aborted: bool,
+}
+
+/// Reason that [`Reactor::dispatch`] returned a [`DispatchError`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DispatchErrorKind {
+    /// A handler with [`ErrorPolicy::Propagate`] failed. The failure is the last one in
+    /// the report.
+    Handler,
+    /// `event` was emitted more than `limit` levels below the initial event.
+    CascadeDepth {
+        /// The `Event` which exceeded the limit.
+        event: EventId,
+        /// The maximum cascade depth of the `Reactor`.
+        limit: usize,
+    },
+    /// More than `limit` events would have been processed by a single dispatch.
+    EventBudget {
+        /// The `Event` which exceeded the limit.
+        event: EventId,
+        /// The event budget of the `Reactor`.
+        limit: usize,
+    },
+}
+
+/// Returned by [`Reactor::dispatch`] when it stops early because of an error.
+///
+/// Events which were still pending are discarded, but the cycle still ends.
+#[derive(Error, Debug)]
+pub struct DispatchError {
+    /// Reason that dispatch stopped.
+    pub kind: DispatchErrorKind,
+    /// Summary of the dispatch up to the error.
+    pub report: DispatchReport,
+}
+
+impl Display for DispatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            DispatchErrorKind::Handler => match self.report.failures.last() {
+                Some(failure) => Display::fmt(failure, f),
+                None => f.write_str("Dispatch failed"),
+            },
+            DispatchErrorKind::CascadeDepth { event, limit } => write!(
+                f,
+                "Cascade exceeded the maximum depth of {limit} at {event}; \
+                 a handler may be emitting events in an infinite loop"
+            ),
+            DispatchErrorKind::EventBudget { event, limit } => write!(
+                f,
+                "Dispatch exceeded the budget of {limit} events at {event}; \
+                 a handler may be emitting events in an infinite loop"
+            ),
+        }
+    }
+}
+
+/// Default for [`ReactorBuilder::max_cascade_depth`].
+pub const DEFAULT_MAX_CASCADE_DEPTH: usize = 256;
+
+/// Default for [`ReactorBuilder::event_budget`].
+pub const DEFAULT_EVENT_BUDGET: usize = 65536;
+
+/// Result of calling a single handler.
+enum HandlerOutcome {
+    /// The handler is disabled or its conditions don't hold, and it wasn't called.
+    Skipped,
+    /// The handler returned `Ok`.
+    Succeeded,
+    /// The handler returned an error.
+    Failed(anyhow::Error),
+}
 
 /// Stores a set of [`Handler`]s and executes them in response to [`Event`]s.
 ///
 /// `Handler`s are able to emit their own `Events`, which are dispatched
 /// similarly after the initial `Event`. If the `Handler` returns an error while
-/// handling any `Event`, it is logged but dispatch of that `Event` continues.
+/// handling any `Event`, it is handled according to its [`ErrorPolicy`].
+///
+/// A `Reactor` doesn't store any per-world data, so one `Reactor` can drive many
+/// independent worlds, each with its own [`StateContainer`]. Both are `Send + Sync`,
+/// so worlds can be dispatched concurrently from different threads or async tasks.
 pub struct Reactor {
     /// Handlers called by the Reactor.
     handlers: Vec<Handler>,
-    /// Handler indices to execute for each EventId.
-    event_dispatch_order: HashMap<EventId, Vec<usize>>,
+    /// `ErrorPolicy` for handlers which don't set their own.
+    error_policy: ErrorPolicy,
+    /// Handler indices to execute for each EventId, grouped into waves. Handlers
+    /// within a wave have no conflicting dependencies and may run concurrently.
+    event_dispatch_order: HashMap<EventId, Vec<Vec<usize>>>,
+    /// Dependency graph of the handlers for each EventId.
+    dependency_graphs: HashMap<EventId, DependencyGraph>,
+    /// Every `Topic` used by any handler.
+    topic_ids: HashSet<TopicId>,
+    /// How handlers within a wave are executed.
+    dispatch_mode: DispatchMode,
+    /// How far below the initial event a cascaded event may be emitted.
+    max_cascade_depth: usize,
+    /// Maximum number of events processed by a single dispatch.
+    event_budget: usize,
+    /// Initial values of `State`s which replace their `Default` in new containers.
+    default_states: Vec<AnyState>,
 }
 
 impl Reactor {
@@ -40,54 +212,410 @@ impl Reactor {
         ReactorBuilder::default()
     }
 
+    /// Get the [`DependencyGraph`] which determines the order handlers for `event` run in.
+    ///
+    /// Returns `None` if there are no handlers for the `Event`.
+    pub fn dependency_graph(&self, event: &EventId) -> Option<&DependencyGraph> {
+        self.dependency_graphs.get(event)
+    }
+
     /// Create a fresh [`StateContainer`] for use with this `Reactor`.
     ///
     /// This will automatically dispatch an [`InitEvent`] so that handlers
     /// can initialize their state.
     pub fn new_state_container(&self) -> StateContainer {
+        let dependencies = || self.handlers.iter().flat_map(|h| h.dependencies().iter());
         let states = StateContainer::new(
-            self.handlers
-                .iter()
-                .flat_map(|h| h.dependencies().iter())
+            dependencies()
                 .filter_map(|d| d.state_id().cloned())
+                .chain([Scheduler::id(), SimClock::id()])
+                .chain(self.default_states.iter().map(AnyState::id))
+                .collect::<HashSet<_>>(),
+            dependencies()
+                .filter_map(|d| match d {
+                    Dependency::ReadStateDelayed(id) => Some(id.clone()),
+                    _ => None,
+                })
                 .collect::<HashSet<_>>(),
         );
+        for state in &self.default_states {
+            states.replace(state.clone());
+        }
+
+        // Only changes which observers respond to are recorded as lifecycle events.
+        if let Some(mut entities) = states.get_mut::<EntityState>() {
+            let observed = self.event_dispatch_order.keys().cloned().collect();
+            entities.set_observed(Arc::new(observed));
+        }
 
-        self.dispatch(&states, InitEvent);
+        if let Err(err) = self.dispatch(&states, InitEvent) {
+            error!("{err}");
+        }
         states
     }
 
     /// Dispatch an event to all handlers and update the `states`.
-    pub fn dispatch<E: Event>(&self, states: &StateContainer, event: E) {
-        let topics = TopicContainer::new();
-
-        let queue = EventQueue::new();
-        queue.push(AnyEvent::new(event));
-        while let Some(event) = queue.pop() {
-            let dispatch_order = match self.event_dispatch_order.get(&E::id()) {
-                Some(handlers) => handlers,
-                None => continue,
+    ///
+    /// Events emitted by handlers are dispatched to the handlers for their own type,
+    /// in the order they were emitted. Once the event and every event emitted in
+    /// response to it have been handled, the cycle ends and
+    /// [`DelayedReader`](super::DelayedReader)s observe the new values. The cycle also
+    /// ends if dispatch stops early due to a handler's [`ErrorPolicy`], or because the
+    /// cascade exceeded the maximum depth or event budget.
+    pub fn dispatch<E: Event>(
+        &self,
+        states: &StateContainer,
+        event: E,
+    ) -> Result<DispatchReport, DispatchError> {
+        self.dispatch_any(states, AnyEvent::new(event))
+    }
+
+    /// Dispatch a dynamically-typed event, like [`Reactor::dispatch`].
+    pub fn dispatch_any(
+        &self,
+        states: &StateContainer,
+        event: AnyEvent,
+    ) -> Result<DispatchReport, DispatchError> {
+        self.dispatch_traced(states, event, None)
+    }
+
+    /// Dispatch a dynamically-typed event, like [`Reactor::dispatch`], and record every
+    /// event processed and message published in `trace`.
+    pub(super) fn dispatch_traced(
+        &self,
+        states: &StateContainer,
+        event: AnyEvent,
+        mut trace: Option<&mut DispatchTrace>,
+    ) -> Result<DispatchReport, DispatchError> {
+        let topics = TopicContainer::new(self.topic_ids.iter().cloned());
+
+        // Each pending event is stored with its depth below the initial event.
+        let mut pending = VecDeque::from([(event, 0)]);
+        let commands = CommandQueue::new();
+        let mut report = DispatchReport::default();
+        let mut error = None;
+        while let Some((event, depth)) = pending.pop_front() {
+            if depth > self.max_cascade_depth {
+                error = Some(DispatchErrorKind::CascadeDepth {
+                    event: event.id(),
+                    limit: self.max_cascade_depth,
+                });
+                break;
+            }
+            if report.events_processed == self.event_budget {
+                error = Some(DispatchErrorKind::EventBudget {
+                    event: event.id(),
+                    limit: self.event_budget,
+                });
+                break;
+            }
+
+            report.events_processed += 1;
+            let tick = event.downcast::<Tick>();
+            if let Some(tick) = tick {
+                if let Some(mut scheduler) = states.get_mut::<Scheduler>() {
+                    scheduler.advance(tick.time);
+                }
+            }
+
+            let waves = self
+                .event_dispatch_order
+                .get(&event.id())
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+
+            // Events emitted while handling `event`, which are one level deeper.
+            let queue = EventQueue::new();
+
+            // Each handler allocates `ScheduleHandle`s for its call from the call
+            // reserved at its index, so they don't depend on the order handlers run in.
+            let first_call = match states.get_mut_untracked::<Scheduler>() {
+                Some((mut scheduler, _)) => scheduler.reserve_calls(self.handlers.len() as u64),
+                None => 0,
             };
 
             topics.clear();
-            let context = Context {
-                states,
-                queue: &queue,
-                topics: &topics,
-                event: &event,
-            };
+            for wave in waves {
+                let outcomes = if self.dispatch_mode == DispatchMode::Parallel && wave.len() > 1 {
+                    // Each handler gets its own queues, which are then appended in serial
+                    // order so that emitted events and commands are in a deterministic order.
+                    // Commands queued by a handler which failed are discarded.
+                    let results = wave
+                        .par_iter()
+                        .map(|&idx| {
+                            let handler_queue = EventQueue::new();
+                            let (outcome, handler_commands) = self.call_handler(
+                                idx,
+                                first_call,
+                                states,
+                                &handler_queue,
+                                &topics,
+                                &event,
+                            );
+                            (handler_queue, handler_commands, outcome)
+                        })
+                        .collect::<Vec<_>>();
 
-            for &idx in dispatch_order {
-                let handler = &self.handlers[idx];
-                match handler.call(&context) {
-                    Ok(()) => {}
-                    Err(err) => {
-                        error!("Handler '{handler}' failed while handling {event:?}: {err}");
+                    let mut outcomes = Vec::new();
+                    for (handler_queue, handler_commands, outcome) in results {
+                        queue.append(handler_queue);
+                        Self::merge_commands(states, &commands, handler_commands, &outcome);
+                        outcomes.push(outcome);
+                    }
+                    outcomes
+                } else {
+                    wave.iter()
+                        .map(|&idx| {
+                            let (outcome, handler_commands) =
+                                self.call_handler(idx, first_call, states, &queue, &topics, &event);
+                            Self::merge_commands(states, &commands, handler_commands, &outcome);
+                            outcome
+                        })
+                        .collect()
+                };
+
+                // Outcomes are handled in serial order, so failures are reported and
+                // counted identically in every `DispatchMode`.
+                for (&idx, outcome) in wave.iter().zip(outcomes) {
+                    if self.handle_outcome(idx, states, outcome, &event, &mut report) {
+                        error = Some(DispatchErrorKind::Handler);
                     }
                 }
+                if report.aborted {
+                    break;
+                }
+            }
+
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.events.push(event.clone());
+                trace.topics.extend(topics.take());
             }
+
+            // Apply commands once every handler has run, so that events written
+            // alongside them observe the changes.
+            if !commands.is_empty() {
+                let mut entities = states
+                    .get_mut::<EntityState>()
+                    .expect("Commands used without an EntityState");
+                for err in commands.apply(&mut entities) {
+                    error!("Command failed while handling {event:?}: {err}");
+                }
+            }
+
+            if report.aborted {
+                break;
+            }
+
+            // Lifecycle events come before other events emitted in response, and are
+            // dropped if nothing observes them.
+            if let Some(entities) = states.get::<EntityState>() {
+                for lifecycle in entities.take_lifecycle() {
+                    let lifecycle = lifecycle.into_event();
+                    if self.event_dispatch_order.contains_key(&lifecycle.id()) {
+                        pending.push_back((lifecycle, depth + 1));
+                    }
+                }
+            }
+            while let Some(emitted) = queue.pop() {
+                pending.push_back((emitted, depth + 1));
+            }
+
+            // Scheduled events which are due are delivered after the events emitted
+            // in response to the `Tick`.
+            let scheduled = queue.take_scheduled();
+            let has_scheduled = !scheduled.is_empty();
+            if has_scheduled || tick.is_some() {
+                if let Some(mut scheduler) = states.get_mut::<Scheduler>() {
+                    scheduler.apply(scheduled);
+                    if tick.is_some() {
+                        while let Some(due) = scheduler.pop_due() {
+                            pending.push_back((due, depth + 1));
+                        }
+                    }
+                } else if has_scheduled {
+                    error!("Events scheduled while handling {event:?} without a Scheduler");
+                }
+            }
+        }
+
+        states.end_cycle();
+        match error {
+            Some(kind) => {
+                report.aborted = true;
+                Err(DispatchError { kind, report })
+            }
+            None => Ok(report),
+        }
+    }
+
+    /// Get the `ErrorPolicy` of the handler at `idx`.
+    fn error_policy(&self, idx: usize) -> ErrorPolicy {
+        self.handlers[idx]
+            .error_policy()
+            .unwrap_or(self.error_policy)
+    }
+
+    /// Record the outcome of calling the handler at `idx` in `report`, and apply its
+    /// `ErrorPolicy` if it failed. Returns true if the failure should be propagated.
+    fn handle_outcome(
+        &self,
+        idx: usize,
+        states: &StateContainer,
+        outcome: HandlerOutcome,
+        event: &AnyEvent,
+        report: &mut DispatchReport,
+    ) -> bool {
+        let error = match outcome {
+            HandlerOutcome::Skipped => return false,
+            HandlerOutcome::Succeeded => {
+                report.handlers_run += 1;
+                return false;
+            }
+            HandlerOutcome::Failed(error) => error,
+        };
+
+        report.handlers_run += 1;
+        let handler = &self.handlers[idx];
+        let policy = self.error_policy(idx);
+        let failures = states.record_failure(idx);
+        let disabled = matches!(policy, ErrorPolicy::DisableAfter(limit) if failures == limit);
+
+        if policy != ErrorPolicy::Propagate {
+            error!("Handler '{handler}' failed while handling {event:?}: {error}");
+        }
+        if disabled {
+            warn!("Handler '{handler}' disabled after failing {failures} times");
+        }
+
+        report.failures.push(HandlerFailure {
+            handler: handler.to_string(),
+            event: event.id(),
+            error,
+            disabled,
+        });
+
+        match policy {
+            ErrorPolicy::AbortCascade => report.aborted = true,
+            ErrorPolicy::Propagate => {
+                report.aborted = true;
+                return true;
+            }
+            ErrorPolicy::Continue | ErrorPolicy::DisableAfter(_) => {}
+        }
+        false
+    }
+
+    /// Append the commands a handler queued to `commands`, or discard them and release
+    /// the `EntityId`s they reserved if the handler failed.
+    fn merge_commands(
+        states: &StateContainer,
+        commands: &CommandQueue,
+        handler_commands: CommandQueue,
+        outcome: &HandlerOutcome,
+    ) {
+        if !matches!(outcome, HandlerOutcome::Failed(_)) {
+            commands.append(handler_commands);
+        } else if let Some(entities) = states.get::<EntityState>() {
+            handler_commands.discard(&entities);
         }
     }
+
+    /// Call the handler at `idx`, unless it has been disabled, and return the commands
+    /// it queued.
+    ///
+    /// `first_call` is the first of the calls reserved with the `Scheduler` for
+    /// handling `event`.
+    fn call_handler(
+        &self,
+        idx: usize,
+        first_call: u64,
+        states: &StateContainer,
+        queue: &EventQueue,
+        topics: &TopicContainer,
+        event: &AnyEvent,
+    ) -> (HandlerOutcome, CommandQueue) {
+        let commands = CommandQueue::new();
+        let handler = &self.handlers[idx];
+        if let ErrorPolicy::DisableAfter(limit) = self.error_policy(idx) {
+            if states.failure_count(idx) >= limit {
+                return (HandlerOutcome::Skipped, commands);
+            }
+        }
+
+        // Conditions which access entities share a borrow of the `EntityState` while
+        // they're evaluated, even if the handler itself writes it.
+        let last_run = states.last_run(idx);
+        let tick = next_change_tick();
+        let should_run = {
+            let entities = if handler.reads_entities() || handler.conditions_read_entities() {
+                states.get::<EntityState>()
+            } else {
+                None
+            };
+            if handler.only_if_changed()
+                && last_run != 0
+                && !handler.inputs_changed(states, entities.as_deref(), last_run)
+            {
+                return (HandlerOutcome::Skipped, commands);
+            }
+
+            handler.should_run(&Context {
+                states,
+                entities: entities.as_deref(),
+                queue,
+                handles: HandleAllocator::new(first_call + idx as u64),
+                commands: &commands,
+                topics,
+                event,
+                target: handler.target(event),
+                tick,
+                last_run,
+            })
+        };
+
+        let outcome = match should_run {
+            Ok(true) => {
+                states.set_last_run(idx, tick);
+
+                // Handlers which access entities share a borrow of the `EntityState` for
+                // the duration of the call, and borrow individual columns or reserve IDs
+                // through it.
+                let entities = if handler.reads_entities() {
+                    states.get::<EntityState>()
+                } else {
+                    None
+                };
+                let context = Context {
+                    states,
+                    entities: entities.as_deref(),
+                    queue,
+                    handles: HandleAllocator::new(first_call + idx as u64),
+                    commands: &commands,
+                    topics,
+                    event,
+                    target: handler.target(event),
+                    tick,
+                    last_run,
+                };
+                match handler.call(&context) {
+                    Ok(()) => HandlerOutcome::Succeeded,
+                    Err(err) => HandlerOutcome::Failed(err),
+                }
+            }
+            Ok(false) => HandlerOutcome::Skipped,
+            Err(err) => HandlerOutcome::Failed(err),
+        };
+        (outcome, commands)
+    }
+}
+
+/// Panic if `policy` is invalid. `ErrorPolicy::DisableAfter(0)` would disable the
+/// handler before it ever ran.
+#[track_caller]
+fn check_error_policy(policy: ErrorPolicy) {
+    if policy == ErrorPolicy::DisableAfter(0) {
+        panic!("ErrorPolicy::DisableAfter(0) would disable the handler before it runs");
+    }
 }
 
 /// Builder type for [`Reactor`].
@@ -97,6 +625,22 @@ pub struct ReactorBuilder {
     global_handlers: Vec<Handler>,
     /// Handlers to dispatch in response to a specific event.
     event_handlers: HashMap<EventId, Vec<Handler>>,
+    /// How the built `Reactor` executes handlers.
+    dispatch_mode: DispatchMode,
+    /// `ErrorPolicy` for handlers which don't set their own.
+    error_policy: ErrorPolicy,
+    /// Event of the most recently added handler, or `Some(None)` if it is a global handler.
+    last_added: Option<Option<EventId>>,
+    /// Maximum cascade depth, if not the default.
+    max_cascade_depth: Option<usize>,
+    /// Event budget, if not the default.
+    event_budget: Option<usize>,
+    /// Plugins which haven't been built yet.
+    plugins: Vec<Box<dyn Plugin>>,
+    /// Name of every plugin which has been added.
+    plugin_names: HashSet<&'static str>,
+    /// Initial values of `State`s, by `StateId`.
+    default_states: HashMap<StateId, AnyState>,
 }
 
 /// Errors which can occur while building the reactor.
@@ -105,64 +649,311 @@ pub enum BuildReactorError {
     /// Indicates that the handlers for the given [`EventId`] have a circular dependency.
     #[error("While analyzing handlers for {0}: {1}")]
     Cycle(EventId, #[source] CyclicDependenciesError),
+    /// Indicates that a [`Plugin`] depends on a plugin which wasn't added.
+    #[error("Plugin `{plugin}` depends on `{dependency}`, which wasn't added")]
+    MissingPlugin {
+        /// Name of the plugin with the dependency.
+        plugin: &'static str,
+        /// Name of the missing plugin.
+        dependency: &'static str,
+    },
+    /// Indicates that the given [`Plugin`]s depend on each other.
+    #[error("Circular dependency between plugins: {}", .0.join(", "))]
+    PluginCycle(Vec<&'static str>),
+    /// Indicates that the given handler takes a [`Target`](super::Target), but wasn't
+    /// added with [`ReactorBuilder::add_entity`].
+    #[error("Handler '{0}' takes a Target, but wasn't added with add_entity")]
+    MissingTarget(String),
 }
 
-/// TODO
+/// Set of handlers which are added to a [`ReactorBuilder`] together.
+///
+/// Usually implemented with the [`handler_group`](super::handler_group) attribute,
+/// which adds every function marked `#[handler]` in an `impl` block.
 pub trait HandlerGroup {
-    /// TODO
+    /// Add the group's handlers to `builder`.
     fn add_group(builder: ReactorBuilder) -> ReactorBuilder;
 }
 
 impl ReactorBuilder {
     /// TODO
+    #[track_caller]
     pub fn add<E: Event, Args>(mut self, f: impl EventHandlerFn<E, Args>) -> Self {
         self.event_handlers
             .entry(E::id())
             .or_default()
             .push(f.into_handler());
+        self.last_added = Some(Some(E::id()));
         self
     }
 
+    /// Add a handler for the [`EntityEvent`] `E`, which is directed at the event's
+    /// target entity.
+    ///
+    /// The handler can fetch the target's `Component`s through a [`Target`](super::Target)
+    /// argument, in which case it only runs when the target exists and matches it.
+    #[track_caller]
+    pub fn add_entity<E: EntityEvent, Args>(self, f: impl EventHandlerFn<E, Args>) -> Self {
+        let mut builder = self.add(f);
+        builder.last_handler().set_target::<E>();
+        builder
+    }
+
+    /// Add an observer which runs when `Component` `C` is attached to an entity.
+    #[track_caller]
+    pub fn on_add<C: Component, Args>(self, f: impl EventHandlerFn<OnAdd<C>, Args>) -> Self {
+        self.add_entity::<OnAdd<C>, Args>(f)
+    }
+
+    /// Add an observer which runs when `Component` `C` is detached from an entity,
+    /// including when the entity is despawned.
+    #[track_caller]
+    pub fn on_remove<C: Component, Args>(self, f: impl EventHandlerFn<OnRemove<C>, Args>) -> Self {
+        self.add_entity::<OnRemove<C>, Args>(f)
+    }
+
+    /// Add an observer which runs when an entity is despawned.
+    #[track_caller]
+    pub fn on_despawn<Args>(self, f: impl EventHandlerFn<OnDespawn, Args>) -> Self {
+        self.add_entity::<OnDespawn, Args>(f)
+    }
+
     /// TODO
+    #[track_caller]
     pub fn add_global<Args>(mut self, f: impl HandlerFn<Args>) -> Self {
         self.global_handlers.push(f.into_handler());
+        self.last_added = Some(None);
         self
     }
 
-    /// TODO
+    /// Add every handler in the [`HandlerGroup`] `G`.
     pub fn add_group<G: HandlerGroup>(self) -> ReactorBuilder {
         G::add_group(self)
     }
 
+    /// Add `plugin`, which is built along with the [`Reactor`], and the plugins it adds
+    /// with [`Plugin::add_dependencies`]. Does nothing if a plugin with the same name
+    /// was already added.
+    pub fn add_plugin(mut self, plugin: impl Plugin) -> Self {
+        if self.plugin_names.insert(plugin.name()) {
+            self = plugin.add_dependencies(self);
+            self.plugins.push(Box::new(plugin));
+        }
+        self
+    }
+
+    /// Returns true if a plugin named `name` has been added.
+    pub fn has_plugin(&self, name: &str) -> bool {
+        self.plugin_names.contains(name)
+    }
+
+    /// Initialize `State` `S` to `state` rather than its `Default` in each
+    /// [`StateContainer`] created by the built [`Reactor`]. The value is set before
+    /// the [`InitEvent`] is dispatched, and replaces any previous value for `S`.
+    pub fn insert_state<S: State>(mut self, state: S) -> Self {
+        self.default_states.insert(S::id(), AnyState::new(state));
+        self
+    }
+
+    /// Set how the built [`Reactor`] executes handlers. Defaults to [`DispatchMode::Serial`].
+    pub fn dispatch_mode(mut self, mode: DispatchMode) -> Self {
+        self.dispatch_mode = mode;
+        self
+    }
+
+    /// Set the [`ErrorPolicy`] for handlers which don't set their own with
+    /// [`ReactorBuilder::error_policy`]. Defaults to [`ErrorPolicy::Continue`].
+    ///
+    /// Panics if `policy` is `ErrorPolicy::DisableAfter(0)`.
+    #[track_caller]
+    pub fn default_error_policy(mut self, policy: ErrorPolicy) -> Self {
+        check_error_policy(policy);
+        self.error_policy = policy;
+        self
+    }
+
+    /// Set how far below the initial event a cascaded event may be emitted before
+    /// [`Reactor::dispatch`] stops with [`DispatchErrorKind::CascadeDepth`]. Events
+    /// emitted by handlers for the initial event have a depth of 1.
+    ///
+    /// Defaults to [`DEFAULT_MAX_CASCADE_DEPTH`].
+    pub fn max_cascade_depth(mut self, depth: usize) -> Self {
+        self.max_cascade_depth = Some(depth);
+        self
+    }
+
+    /// Set how many events, including the initial event, a single call to
+    /// [`Reactor::dispatch`] may process before it stops with
+    /// [`DispatchErrorKind::EventBudget`].
+    ///
+    /// Defaults to [`DEFAULT_EVENT_BUDGET`].
+    pub fn event_budget(mut self, budget: usize) -> Self {
+        self.event_budget = Some(budget);
+        self
+    }
+
+    /// Set the [`ErrorPolicy`] of the most recently added handler.
+    ///
+    /// Panics if no handler has been added, or if `policy` is
+    /// `ErrorPolicy::DisableAfter(0)`.
+    #[track_caller]
+    pub fn error_policy(mut self, policy: ErrorPolicy) -> Self {
+        check_error_policy(policy);
+        self.last_handler().set_error_policy(policy);
+        self
+    }
+
+    /// Only run the most recently added handler when `condition` returns true.
+    ///
+    /// The condition is a function which takes read-only handler arguments, such as
+    /// [`Reader`](super::Reader)s, and is evaluated immediately before the handler would
+    /// run. Its arguments count as dependencies of the handler, so it observes the same
+    /// values the handler would. Calling `run_if` more than once requires every
+    /// condition to hold.
+    ///
+    /// Panics if no handler has been added.
+    #[track_caller]
+    pub fn run_if<Args>(mut self, condition: impl ConditionFn<Args>) -> Self {
+        self.last_handler()
+            .add_condition(condition.into_condition());
+        self
+    }
+
+    /// Only run the most recently added handler when one of the `State`s or
+    /// `Component`s it reads changed since its previous run. It always runs the first
+    /// time.
+    ///
+    /// Reads count through [`Reader`](super::Reader)s, [`DelayedReader`](super::DelayedReader)s,
+    /// [`Changed`](super::Changed) and the read-only parts of [`Query`](super::Query)s.
+    /// Use it for handlers which recompute a result from their inputs, rather than
+    /// ones which respond to the event itself.
+    ///
+    /// Panics if no handler has been added.
+    #[track_caller]
+    pub fn run_if_changed(mut self) -> Self {
+        self.last_handler().set_only_if_changed(true);
+        self
+    }
+
+    /// Add the most recently added handler to the ordering label `label`.
+    ///
+    /// Labels order handlers which don't share any `State`s or `Topic`s, through
+    /// [`ReactorBuilder::after`] and [`ReactorBuilder::before`]. A handler may have
+    /// several labels.
+    ///
+    /// Panics if no handler has been added.
+    #[track_caller]
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.last_handler()
+            .add_dependency(Dependency::Label(label.into()));
+        self
+    }
+
+    /// Run the most recently added handler after every handler with `label`.
+    ///
+    /// Panics if no handler has been added.
+    #[track_caller]
+    pub fn after(mut self, label: impl Into<String>) -> Self {
+        self.last_handler()
+            .add_dependency(Dependency::After(label.into()));
+        self
+    }
+
+    /// Run the most recently added handler before every handler with `label`.
+    ///
+    /// Panics if no handler has been added.
+    #[track_caller]
+    pub fn before(mut self, label: impl Into<String>) -> Self {
+        self.last_handler()
+            .add_dependency(Dependency::Before(label.into()));
+        self
+    }
+
+    /// Get the most recently added handler, so that it can be configured.
+    #[track_caller]
+    fn last_handler(&mut self) -> &mut Handler {
+        let handlers = match &self.last_added {
+            Some(Some(event_id)) => self.event_handlers.get_mut(event_id).unwrap(),
+            Some(None) => &mut self.global_handlers,
+            None => panic!("No handler has been added to configure"),
+        };
+        handlers.last_mut().unwrap()
+    }
+
     /// Build the [`Reactor`].
-    pub fn build(self) -> Result<Reactor, BuildReactorError> {
+    ///
+    /// Plugins are built first, each after the plugins it depends on. Plugins added
+    /// while building are built once the current plugins have been.
+    pub fn build(mut self) -> Result<Reactor, BuildReactorError> {
+        let mut built = HashSet::new();
+        loop {
+            let pending = std::mem::take(&mut self.plugins);
+            if pending.is_empty() {
+                break;
+            }
+            for plugin in resolve_plugins(pending, &built)? {
+                self = plugin.build(self);
+                built.insert(plugin.name());
+            }
+        }
+
+        if let Some(handler) = self
+            .global_handlers
+            .iter()
+            .chain(self.event_handlers.values().flatten())
+            .find(|handler| handler.missing_target())
+        {
+            return Err(BuildReactorError::MissingTarget(handler.to_string()));
+        }
+
         let mut event_dispatch_order = HashMap::new();
-        for (event_id, handlers) in &self.event_handlers {
-            let all_handlers = self
-                .global_handlers
+        let mut dependency_graphs = HashMap::new();
+        let end_of_global_handlers = self.global_handlers.len();
+        let mut handlers = self.global_handlers;
+        for (event_id, event_handlers) in self.event_handlers {
+            let all_handlers = handlers[..end_of_global_handlers]
                 .iter()
-                .chain(handlers)
+                .chain(&event_handlers)
                 .collect::<Vec<_>>();
 
-            let mut order = compute_execution_order(&all_handlers)
+            let graph = DependencyGraph::new(&all_handlers);
+            let order = graph
+                .execution_order()
                 .map_err(|err| BuildReactorError::Cycle(event_id.clone(), err))?;
+            let mut waves = compute_execution_waves(&all_handlers, &order);
 
-            let offset = all_handlers.len();
-            let end_of_global_handlers = self.global_handlers.len();
-            for idx in &mut order {
+            // Event handlers are appended after all previously added handlers, so
+            // shift their indices past the global handlers to their final position.
+            let offset = handlers.len() - end_of_global_handlers;
+            for idx in waves.iter_mut().flatten() {
                 if *idx >= end_of_global_handlers {
                     *idx += offset;
                 }
             }
-            event_dispatch_order.insert(event_id.clone(), order);
+            event_dispatch_order.insert(event_id.clone(), waves);
+            dependency_graphs.insert(event_id, graph);
+            handlers.extend(event_handlers);
         }
 
-        let mut handlers = self.global_handlers;
-        handlers.extend(self.event_handlers.into_values().flatten());
+        let topic_ids = handlers
+            .iter()
+            .flat_map(|h| h.dependencies())
+            .filter_map(|d| match d {
+                Dependency::PublishTopic(id) | Dependency::SubscribeTopic(id) => Some(id.clone()),
+                _ => None,
+            })
+            .collect();
 
         Ok(Reactor {
             handlers,
+            error_policy: self.error_policy,
             event_dispatch_order,
+            dependency_graphs,
+            topic_ids,
+            dispatch_mode: self.dispatch_mode,
+            max_cascade_depth: self.max_cascade_depth.unwrap_or(DEFAULT_MAX_CASCADE_DEPTH),
+            event_budget: self.event_budget.unwrap_or(DEFAULT_EVENT_BUDGET),
+            default_states: self.default_states.into_values().collect(),
         })
     }
 }
@@ -185,98 +976,381 @@ impl Display for CyclicDependenciesError {
     }
 }
 
-/// TODO
-fn compute_execution_order(handlers: &[&Handler]) -> Result<Vec<usize>, CyclicDependenciesError> {
-    /// Node type for the dependency graph.
-    enum Node {
-        /// Node represents the handler at the given index in `handlers`.
-        Handler(usize),
-        /// Node represents a `State`.
-        State(StateId),
-        /// Node represents a `Topic`.
-        Topic(TopicId),
-    }
-
-    // First, we construct the nodes of the graph. As we go, populate `HashMap`s for fast
-    // retrieval of nodes their ID.
-    let mut graph = DiGraph::<Node, ()>::new();
-    let mut handler_nodes = Vec::new();
-    let mut state_nodes = HashMap::new();
-    let mut topic_nodes = HashMap::new();
-
-    for (idx, handler) in handlers.iter().enumerate() {
-        // Build a node for this handler.
-        handler_nodes.push(graph.add_node(Node::Handler(idx)));
-
-        // Check each dependency and build nodes if they refer to things
-        // we don't already have nodes for.
-        for dep in handler.dependencies() {
-            match dep {
-                Dependency::ReadState(id)
-                | Dependency::ReadStateDelayed(id)
-                | Dependency::WriteState(id) => {
-                    state_nodes
-                        .entry(id.clone())
-                        .or_insert_with(|| graph.add_node(Node::State(id.clone())));
-                }
-                Dependency::PublishTopic(id) | Dependency::SubscribeTopic(id) => {
-                    topic_nodes
-                        .entry(id.clone())
-                        .or_insert_with(|| graph.add_node(Node::Topic(id.clone())));
+/// Node type for the dependency graph.
+enum Node {
+    /// Node represents the handler at the given index in the handlers for the event.
+    Handler(usize),
+    /// Node represents a `State`.
+    State(StateId),
+    /// Node represents a `Topic`.
+    Topic(TopicId),
+    /// Node represents a `Component`.
+    Component(ComponentId),
+    /// Node which runs after every handler with a label.
+    Label(String),
+    /// Node which runs before every handler with a label. Only present if a handler
+    /// must run before the label.
+    LabelStart(String),
+}
+
+/// Graph of the dependencies between the handlers for an `Event` and the `State`s,
+/// `Topic`s and `Component`s they access, along with the labels which order them. It
+/// determines their execution order.
+///
+/// Use [`DependencyGraph::to_dot`] or [`DependencyGraph::to_json`] to inspect it.
+pub struct DependencyGraph {
+    /// Edges point from dependee to dependency, and are weighted by the `Dependency`
+    /// which created them.
+    graph: DiGraph<Node, Dependency>,
+    /// Handler and `State` nodes of each delayed read, which doesn't order the handler
+    /// and so isn't an edge of the graph.
+    delayed_reads: Vec<(NodeIndex, NodeIndex)>,
+    /// Name and `Location` of each handler, indexed like [`Node::Handler`].
+    handlers: Vec<(String, Location<'static>)>,
+}
+
+impl DependencyGraph {
+    /// Build the graph for `handlers`.
+    fn new(handlers: &[&Handler]) -> DependencyGraph {
+        // First, we construct the nodes of the graph. As we go, populate `HashMap`s for fast
+        // retrieval of nodes their ID.
+        let mut graph = DiGraph::<Node, Dependency>::new();
+        let mut handler_nodes = Vec::new();
+        let mut state_nodes = HashMap::new();
+        let mut topic_nodes = HashMap::new();
+        let mut component_nodes = HashMap::new();
+        let mut label_nodes = HashMap::new();
+        let mut label_start_nodes = HashMap::new();
+        let mut delayed_reads = Vec::new();
+
+        for (idx, handler) in handlers.iter().enumerate() {
+            // Build a node for this handler.
+            handler_nodes.push(graph.add_node(Node::Handler(idx)));
+
+            // Check each dependency and build nodes if they refer to things
+            // we don't already have nodes for.
+            for dep in handler.dependencies() {
+                match dep {
+                    Dependency::ReadState(id)
+                    | Dependency::ReadStateDelayed(id)
+                    | Dependency::WriteState(id) => {
+                        state_nodes
+                            .entry(id.clone())
+                            .or_insert_with(|| graph.add_node(Node::State(id.clone())));
+                    }
+                    Dependency::PublishTopic(id) | Dependency::SubscribeTopic(id) => {
+                        topic_nodes
+                            .entry(id.clone())
+                            .or_insert_with(|| graph.add_node(Node::Topic(id.clone())));
+                    }
+                    Dependency::ReadComponent(id) | Dependency::WriteComponent(id) => {
+                        component_nodes
+                            .entry(id.clone())
+                            .or_insert_with(|| graph.add_node(Node::Component(id.clone())));
+                    }
+                    Dependency::Label(name) | Dependency::After(name) => {
+                        label_nodes
+                            .entry(name.clone())
+                            .or_insert_with(|| graph.add_node(Node::Label(name.clone())));
+                    }
+                    Dependency::Before(name) => {
+                        label_start_nodes
+                            .entry(name.clone())
+                            .or_insert_with(|| graph.add_node(Node::LabelStart(name.clone())));
+                    }
+                    Dependency::ReadAllComponents | Dependency::ReserveEntities => {}
                 }
             }
         }
+
+        // Next, populate incoming and outgoing edges for each handler. Edges point from dependee to dependency.
+        for (idx, handler) in handlers.iter().enumerate() {
+            let handler_node = handler_nodes[idx];
+            for dep in handler.dependencies() {
+                let (from, to) = match dep {
+                    Dependency::ReadState(id) => (handler_node, state_nodes[id]),
+                    Dependency::ReadStateDelayed(id) => {
+                        // Delayed readers observe the previous cycle's value, so they
+                        // don't need to be ordered relative to writers.
+                        delayed_reads.push((handler_node, state_nodes[id]));
+                        continue;
+                    }
+                    Dependency::WriteState(id) => (state_nodes[id], handler_node),
+                    Dependency::SubscribeTopic(id) => (handler_node, topic_nodes[id]),
+                    Dependency::PublishTopic(id) => (topic_nodes[id], handler_node),
+                    Dependency::ReadComponent(id) => (handler_node, component_nodes[id]),
+                    Dependency::WriteComponent(id) => (component_nodes[id], handler_node),
+                    Dependency::ReadAllComponents => {
+                        // Reading the `EntityState` directly may borrow any `Component`,
+                        // so order the handler after every writer of one.
+                        let mut components = component_nodes.values().copied().collect::<Vec<_>>();
+                        components.sort();
+                        for component in components {
+                            graph.add_edge(handler_node, component, dep.clone());
+                        }
+                        continue;
+                    }
+                    // Handlers which reserve `EntityId`s are only ordered relative to
+                    // each other when grouped into waves.
+                    Dependency::ReserveEntities => continue,
+                    Dependency::Label(name) => {
+                        // Members of a label run before its start node's dependents,
+                        // and the label's end node depends on every member.
+                        if let Some(&start) = label_start_nodes.get(name) {
+                            graph.add_edge(handler_node, start, dep.clone());
+                        }
+                        (label_nodes[name], handler_node)
+                    }
+                    Dependency::After(name) => (handler_node, label_nodes[name]),
+                    Dependency::Before(name) => (label_start_nodes[name], handler_node),
+                };
+                graph.add_edge(from, to, dep.clone());
+            }
+        }
+
+        DependencyGraph {
+            graph,
+            delayed_reads,
+            handlers: handlers
+                .iter()
+                .map(|h| {
+                    let name = h.name().unwrap_or("Unnamed handler");
+                    (name.to_owned(), *h.location())
+                })
+                .collect(),
+        }
     }
 
-    // Next, populate incoming and outgoing edges for each handler. Edges point from dependee to dependency.
-    for &handler_node in &handler_nodes {
-        let handler = match &graph[handler_node] {
-            &Node::Handler(idx) => &handlers[idx],
-            _ => panic!("Non-handler found at handler_node index"),
-        };
+    /// Compute the order to execute the handlers in, as indices into the handlers the
+    /// graph was built from.
+    fn execution_order(&self) -> Result<Vec<usize>, CyclicDependenciesError> {
+        // Find strongly connected components for the graph in reverse topological order.
+        let sccs_rev_topo = kosaraju_scc(&self.graph);
 
-        for dep in handler.dependencies() {
-            match dep {
-                Dependency::ReadState(id) => {
-                    graph.add_edge(handler_node, state_nodes[id], ());
-                }
-                Dependency::ReadStateDelayed(id) | Dependency::WriteState(id) => {
-                    graph.add_edge(state_nodes[id], handler_node, ());
-                }
-                Dependency::SubscribeTopic(id) => {
-                    graph.add_edge(handler_node, topic_nodes[id], ());
-                }
-                Dependency::PublishTopic(id) => {
-                    graph.add_edge(topic_nodes[id], handler_node, ());
-                }
+        let mut result = Vec::new();
+        for scc in sccs_rev_topo {
+            // Report a cyclic error if multiple nodes appear in the cycle.
+            if scc.len() > 1 {
+                let names = scc
+                    .iter()
+                    .map(|&node| match &self.graph[node] {
+                        &Node::Handler(idx) => {
+                            let (name, location) = &self.handlers[idx];
+                            format!("Handler {name} ({location})")
+                        }
+                        Node::State(id) => format!("State {}", id),
+                        Node::Topic(id) => format!("Topic {}", id),
+                        Node::Component(id) => format!("Component {}", id),
+                        Node::Label(name) => format!("Label {name}"),
+                        Node::LabelStart(name) => format!("Start of label {name}"),
+                    })
+                    .collect::<Vec<_>>();
+
+                return Err(CyclicDependenciesError(names));
+            }
+
+            // Append handlers to our output by taking them from the temporary storage.
+            if let &Node::Handler(idx) = &self.graph[scc[0]] {
+                result.push(idx);
             }
         }
+
+        Ok(result)
     }
 
-    // Find strongly connected components for the graph in reverse topological order.
-    let sccs_rev_topo = kosaraju_scc(&graph);
+    /// Describe each node as a kind and a name, and for handlers, a `Location`.
+    fn describe(&self) -> impl Iterator<Item = (&'static str, String, Option<&Location<'static>>)> {
+        self.graph.node_weights().map(|node| match node {
+            &Node::Handler(idx) => {
+                let (name, location) = &self.handlers[idx];
+                ("handler", name.clone(), Some(location))
+            }
+            Node::State(id) => ("state", id.to_string(), None),
+            Node::Topic(id) => ("topic", id.to_string(), None),
+            Node::Component(id) => ("component", id.to_string(), None),
+            Node::Label(name) => ("label", name.clone(), None),
+            Node::LabelStart(name) => ("label start", name.clone(), None),
+        })
+    }
 
-    let mut result = Vec::new();
-    for scc in sccs_rev_topo {
-        // Report a cyclic error if multiple nodes appear in the cycle.
-        if scc.len() > 1 {
-            let names = scc
-                .iter()
-                .map(|&node| match &graph[node] {
-                    &Node::Handler(idx) => format!("Handler {}", handlers[idx]),
-                    Node::State(id) => format!("State {}", id),
-                    Node::Topic(id) => format!("Topic {}", id),
+    /// Iterate over the edges as the indices of the nodes they connect and a
+    /// description of the access.
+    ///
+    /// Unlike the graph itself, edges point in the direction data flows: from writers
+    /// and publishers to readers and subscribers. A handler therefore runs after every
+    /// handler which has a path to it, except through a `"reads delayed"` edge.
+    fn edges(&self) -> impl Iterator<Item = (usize, usize, &'static str)> + '_ {
+        let delayed_reads = self
+            .delayed_reads
+            .iter()
+            .map(|(handler, state)| (state.index(), handler.index(), "reads delayed"));
+        let edges = self.graph.edge_references().map(|edge| {
+            let access = match edge.weight() {
+                Dependency::ReadState(_) => "reads",
+                Dependency::ReadStateDelayed(_) | Dependency::ReserveEntities => {
+                    unreachable!("Delayed reads and reservations aren't edges")
+                }
+                Dependency::WriteState(_) => "writes",
+                Dependency::SubscribeTopic(_) => "subscribes",
+                Dependency::PublishTopic(_) => "publishes",
+                Dependency::ReadComponent(_) | Dependency::ReadAllComponents => "reads",
+                Dependency::WriteComponent(_) => "writes",
+                Dependency::Label(_) => "labels",
+                Dependency::After(_) => "after",
+                Dependency::Before(_) => "before",
+            };
+            (edge.target().index(), edge.source().index(), access)
+        });
+        edges.chain(delayed_reads)
+    }
+
+    /// Render the graph in the DOT language, for use with Graphviz.
+    ///
+    /// Handlers are drawn as boxes labeled with their name and `Location`. Edges point
+    /// in the direction data flows, so a handler runs after every handler which has a
+    /// path to it.
+    pub fn to_dot(&self) -> String {
+        /// Escape `s` for use in a quoted DOT string.
+        fn escape(s: &str) -> String {
+            s.replace('\\', "\\\\").replace('"', "\\\"")
+        }
+
+        let mut out = String::from("digraph {\n");
+        for (idx, (kind, name, location)) in self.describe().enumerate() {
+            let (label, shape) = match location {
+                Some(location) => (
+                    format!("{}\\n{}", escape(&name), escape(&location.to_string())),
+                    "box",
+                ),
+                None => (format!("{} {}", kind, escape(&name)), "ellipse"),
+            };
+            out += &format!("    {idx} [label=\"{label}\", shape={shape}];\n");
+        }
+        for (from, to, access) in self.edges() {
+            out += &format!("    {from} -> {to} [label=\"{access}\"];\n");
+        }
+        out += "}\n";
+        out
+    }
+
+    /// Render the graph as JSON, for use by tooling.
+    ///
+    /// The result is an object with `nodes` and `edges` arrays. Each node has a `kind`
+    /// (`"handler"`, `"state"`, `"topic"`, `"component"`, `"label"` or `"label start"`) and a `name`, and handlers
+    /// also have a `location`. Each edge has `from` and `to` indices into `nodes`, and an
+    /// `access` such as `"reads"`. Edges point in the same direction as [`DependencyGraph::to_dot`].
+    pub fn to_json(&self) -> String {
+        /// JSON representation of a node.
+        #[derive(Serialize)]
+        struct JsonNode {
+            /// Kind of node.
+            kind: &'static str,
+            /// Name of the handler or type.
+            name: String,
+            /// `Location` of the handler.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            location: Option<String>,
+        }
+
+        /// JSON representation of an edge.
+        #[derive(Serialize)]
+        struct JsonEdge {
+            /// Index of the source node.
+            from: usize,
+            /// Index of the target node.
+            to: usize,
+            /// Description of the access.
+            access: &'static str,
+        }
+
+        /// JSON representation of the graph.
+        #[derive(Serialize)]
+        struct JsonGraph {
+            /// Every node.
+            nodes: Vec<JsonNode>,
+            /// Every edge.
+            edges: Vec<JsonEdge>,
+        }
+
+        let graph = JsonGraph {
+            nodes: self
+                .describe()
+                .map(|(kind, name, location)| JsonNode {
+                    kind,
+                    name,
+                    location: location.map(ToString::to_string),
                 })
-                .collect::<Vec<_>>();
+                .collect(),
+            edges: self
+                .edges()
+                .map(|(from, to, access)| JsonEdge { from, to, access })
+                .collect(),
+        };
+        serde_json::to_string_pretty(&graph).unwrap()
+    }
+}
+
+/// Group handlers into waves which can be executed concurrently.
+///
+/// `order` must be a valid serial execution order, as returned by [`DependencyGraph::execution_order`].
+/// Each handler is placed in the wave after the last earlier handler it conflicts with,
+/// so conflicting handlers keep their relative order. Executing the waves in sequence
+/// therefore gives the same result as executing `order` serially.
+fn compute_execution_waves(handlers: &[&Handler], order: &[usize]) -> Vec<Vec<usize>> {
+    let mut waves: Vec<Vec<usize>> = Vec::new();
+    let mut handler_waves = HashMap::new();
+    for (pos, &idx) in order.iter().enumerate() {
+        let wave = order[..pos]
+            .iter()
+            .filter(|&&prev| conflicts(handlers[prev], handlers[idx]))
+            .map(|prev| handler_waves[prev] + 1)
+            .max()
+            .unwrap_or(0);
 
-            return Err(CyclicDependenciesError(names));
+        handler_waves.insert(idx, wave);
+        if wave == waves.len() {
+            waves.push(Vec::new());
         }
+        waves[wave].push(idx);
+    }
 
-        // Append handlers to our output by taking them from the temporary storage.
-        if let &Node::Handler(idx) = &graph[scc[0]] {
-            result.push(idx);
+    waves
+}
+
+/// Returns true if `a` and `b` can't safely run concurrently.
+///
+/// This is the case when one writes a `State` or `Component` the other reads or writes,
+/// or when one publishes to a `Topic` the other publishes or subscribes to. Publishers to
+/// the same `Topic` conflict so that the order of messages is deterministic, and
+/// handlers which reserve `EntityId`s conflict so that each gets the same IDs in every
+/// `DispatchMode`. Handlers which read the `EntityState` directly conflict with every
+/// writer of a `Component`, and handlers ordered relative to a label conflict with its
+/// members.
+fn conflicts(a: &Handler, b: &Handler) -> bool {
+    /// Returns true if a dependency `x` conflicts with a dependency `y`, in one direction.
+    fn conflicts_with(x: &Dependency, y: &Dependency) -> bool {
+        match (x, y) {
+            (Dependency::WriteState(x), Dependency::ReadState(y) | Dependency::WriteState(y)) => {
+                x == y
+            }
+            (
+                Dependency::PublishTopic(x),
+                Dependency::PublishTopic(y) | Dependency::SubscribeTopic(y),
+            ) => x == y,
+            (
+                Dependency::WriteComponent(x),
+                Dependency::ReadComponent(y) | Dependency::WriteComponent(y),
+            ) => x == y,
+            (Dependency::WriteComponent(_), Dependency::ReadAllComponents) => true,
+            (Dependency::ReserveEntities, Dependency::ReserveEntities) => true,
+            (Dependency::Label(x), Dependency::After(y) | Dependency::Before(y)) => x == y,
+            _ => false,
         }
     }
 
-    Ok(result)
+    a.dependencies().iter().any(|x| {
+        b.dependencies()
+            .iter()
+            .any(|y| conflicts_with(x, y) || conflicts_with(y, x))
+    })
 }
@@ -0,0 +1,444 @@
+//! [`Recorder`], [`Replay`] and related types.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::event::{AnyEvent, Event, EventId};
+use super::reactor::{DispatchError, DispatchReport, Reactor};
+use super::save::{LoadError, SaveError, SaveFormat, StateRegistry};
+use super::state::StateContainer;
+
+/// Version of the file layout written by [`Replay::save`].
+pub const REPLAY_FORMAT_VERSION: u32 = 1;
+
+/// Trait for `Event`s which can be recorded by a [`Recorder`] or sent over the network.
+pub trait SerializableEvent: Event + Serialize + DeserializeOwned {
+    /// Stable name identifying this `Event` in recorded data.
+    ///
+    /// Unlike the [`EventId`], which is derived from the type, the name must stay the
+    /// same between builds and targets, so that recordings can be played back by other
+    /// builds and events can be sent between the client and server.
+    const NAME: &'static str;
+}
+
+/// Deserializes a recorded [`SerializableEvent`] and wraps it in an `AnyEvent`.
+type DeserializeEventFn = fn(&[u8]) -> bincode::Result<AnyEvent>;
+
+/// Serializes an `AnyEvent` holding a [`SerializableEvent`].
+type SerializeEventFn = fn(&AnyEvent) -> bincode::Result<Vec<u8>>;
+
+/// Set of [`SerializableEvent`]s which can be played back by a [`Replay`], or encoded
+/// as [`EncodedEvent`]s to send over the network.
+#[derive(Default)]
+pub struct EventRegistry {
+    /// Deserialization function for each stable name.
+    registrations: HashMap<&'static str, DeserializeEventFn>,
+    /// Stable name and serialization function for each registered `Event`.
+    names: HashMap<EventId, (&'static str, SerializeEventFn)>,
+}
+
+impl EventRegistry {
+    /// Construct an empty registry.
+    pub fn new() -> EventRegistry {
+        Default::default()
+    }
+
+    /// Register `E` so that it can be played back.
+    ///
+    /// Panics if another `Event` is already registered with the same name.
+    pub fn register<E: SerializableEvent>(mut self) -> Self {
+        assert!(
+            !self.registrations.contains_key(E::NAME),
+            "Event name `{}` is registered twice",
+            E::NAME
+        );
+
+        self.registrations.insert(E::NAME, deserialize_event::<E>);
+        self.names.insert(E::id(), (E::NAME, serialize_event::<E>));
+        self
+    }
+
+    /// Get the stable name `id` was registered with.
+    pub fn name(&self, id: &EventId) -> Option<&'static str> {
+        Some(self.names.get(id)?.0)
+    }
+
+    /// Get the stable name of every registered `Event`, sorted.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names = self.registrations.keys().copied().collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    /// Encode `event` with its stable name.
+    ///
+    /// Fails with [`ReplayError::UnregisteredEvent`] if its type isn't registered.
+    pub fn encode(&self, event: &AnyEvent) -> Result<EncodedEvent, ReplayError> {
+        let (name, serialize) = self
+            .names
+            .get(&event.id())
+            .ok_or_else(|| ReplayError::UnregisteredEvent(event.id()))?;
+        Ok(EncodedEvent {
+            name: (*name).to_owned(),
+            data: serialize(event)?,
+        })
+    }
+
+    /// Decode an `Event` encoded by [`EventRegistry::encode`], possibly by another
+    /// build.
+    ///
+    /// Fails with [`ReplayError::UnknownEvent`] if its name isn't registered.
+    pub fn decode(&self, event: &EncodedEvent) -> Result<AnyEvent, ReplayError> {
+        let deserialize = self
+            .registrations
+            .get(event.name.as_str())
+            .ok_or_else(|| ReplayError::UnknownEvent(event.name.clone()))?;
+        Ok(deserialize(&event.data)?)
+    }
+}
+
+/// Implementation of [`DeserializeEventFn`].
+fn deserialize_event<E: SerializableEvent>(data: &[u8]) -> bincode::Result<AnyEvent> {
+    Ok(AnyEvent::new(bincode::deserialize::<E>(data)?))
+}
+
+/// Implementation of [`SerializeEventFn`].
+fn serialize_event<E: SerializableEvent>(event: &AnyEvent) -> bincode::Result<Vec<u8>> {
+    bincode::serialize(event.downcast::<E>().unwrap())
+}
+
+/// Errors which can occur while recording or playing back a [`Replay`].
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    /// Indicates that saving a snapshot of the `State`s failed.
+    #[error("While saving states: {0}")]
+    Save(#[from] SaveError),
+    /// Indicates that loading the initial snapshot failed.
+    #[error("While loading states: {0}")]
+    Load(#[from] LoadError),
+    /// Indicates that encoding or decoding the replay or an `Event` failed.
+    #[error("While encoding replay: {0}")]
+    Binary(#[from] bincode::Error),
+    /// Indicates that a handler failed with [`ErrorPolicy::Propagate`](super::ErrorPolicy::Propagate).
+    #[error("While dispatching: {0}")]
+    Dispatch(#[from] DispatchError),
+    /// Indicates that the replay was written with an unsupported version.
+    #[error("Unsupported replay format version {0}, expected {REPLAY_FORMAT_VERSION}")]
+    UnsupportedVersion(u32),
+    /// Indicates that the replay contains an `Event` which isn't registered.
+    #[error("Unknown event `{0}`")]
+    UnknownEvent(String),
+    /// Indicates that an `Event` couldn't be encoded because its type isn't registered.
+    #[error("Event {0} is not registered")]
+    UnregisteredEvent(EventId),
+    /// Indicates that playing back the replay didn't reproduce the recorded `State`s.
+    #[error("Desync: states hashed to {actual:#018x}, but {expected:#018x} was recorded")]
+    Desync {
+        /// Hash of the `State`s at the end of the recording.
+        expected: u64,
+        /// Hash of the `State`s at the end of playback.
+        actual: u64,
+    },
+}
+
+/// [`SerializableEvent`] encoded with its stable name, as recorded by a [`Recorder`]
+/// or sent over the network.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct EncodedEvent {
+    /// Stable name of the `Event`.
+    name: String,
+    /// The `Event`, encoded with `bincode`.
+    data: Vec<u8>,
+}
+
+impl EncodedEvent {
+    /// Stable name of the `Event`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Encode the event to send it over the network.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Decode an event received over the network.
+    pub fn from_bytes(data: &[u8]) -> Result<EncodedEvent, ReplayError> {
+        Ok(bincode::deserialize(data)?)
+    }
+}
+
+/// Recording of every top-level `Event` dispatched to a [`Reactor`], along with the
+/// `State`s before the first `Event` and a hash of the `State`s after the last.
+///
+/// Only `State`s registered in the [`StateRegistry`] used to record are included in the
+/// snapshot and hash.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Replay {
+    /// The `State`s when recording began, in [`SaveFormat::Binary`].
+    snapshot: Vec<u8>,
+    /// Every top-level `Event`, in the order it was dispatched.
+    events: Vec<EncodedEvent>,
+    /// Result of [`StateContainer::state_hash`] when recording finished.
+    final_hash: u64,
+}
+
+impl Replay {
+    /// Number of `Event`s in the replay.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Returns true if the replay doesn't contain any `Event`s.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Write the replay to `writer`.
+    pub fn save(&self, writer: impl Write) -> Result<(), ReplayError> {
+        bincode::serialize_into(writer, &(REPLAY_FORMAT_VERSION, self))?;
+        Ok(())
+    }
+
+    /// Read a replay written by [`Replay::save`] from `reader`.
+    pub fn load(mut reader: impl Read) -> Result<Replay, ReplayError> {
+        let version: u32 = bincode::deserialize_from(&mut reader)?;
+        if version != REPLAY_FORMAT_VERSION {
+            return Err(ReplayError::UnsupportedVersion(version));
+        }
+
+        Ok(bincode::deserialize_from(reader)?)
+    }
+
+    /// Dispatch every recorded `Event` to `reactor`, starting from a fresh
+    /// `StateContainer` with the recorded snapshot loaded.
+    ///
+    /// Returns the resulting `StateContainer`, or [`ReplayError::Desync`] if its hash
+    /// doesn't match the hash recorded at the end of the recording.
+    pub fn play(
+        &self,
+        reactor: &Reactor,
+        states: &StateRegistry,
+        events: &EventRegistry,
+    ) -> Result<StateContainer, ReplayError> {
+        let container = reactor.new_state_container();
+        container.load(states, SaveFormat::Binary, self.snapshot.as_slice())?;
+
+        for event in &self.events {
+            let event = events.decode(event)?;
+            reactor.dispatch_any(&container, event)?;
+        }
+
+        let actual = container.state_hash(states)?;
+        if actual != self.final_hash {
+            return Err(ReplayError::Desync {
+                expected: self.final_hash,
+                actual,
+            });
+        }
+
+        Ok(container)
+    }
+}
+
+/// Records the top-level `Event`s dispatched to a [`Reactor`] into a [`Replay`].
+///
+/// `Event`s are dispatched through the recorder in place of [`Reactor::dispatch`].
+pub struct Recorder<'a> {
+    /// `Reactor` which `Event`s are dispatched to.
+    reactor: &'a Reactor,
+    /// `State`s to snapshot and hash.
+    registry: &'a StateRegistry,
+    /// `State`s when recording began.
+    snapshot: Vec<u8>,
+    /// `Event`s recorded so far.
+    events: Vec<EncodedEvent>,
+}
+
+impl<'a> Recorder<'a> {
+    /// Begin recording, taking a snapshot of the `State`s in `states` which are
+    /// registered in `registry`.
+    pub fn new(
+        reactor: &'a Reactor,
+        registry: &'a StateRegistry,
+        states: &StateContainer,
+    ) -> Result<Recorder<'a>, ReplayError> {
+        let mut snapshot = Vec::new();
+        states.save(registry, SaveFormat::Binary, &mut snapshot)?;
+
+        Ok(Recorder {
+            reactor,
+            registry,
+            snapshot,
+            events: Vec::new(),
+        })
+    }
+
+    /// Record `event` and dispatch it, like [`Reactor::dispatch`].
+    pub fn dispatch<E: SerializableEvent>(
+        &mut self,
+        states: &StateContainer,
+        event: E,
+    ) -> Result<DispatchReport, ReplayError> {
+        self.events.push(EncodedEvent {
+            name: E::NAME.to_owned(),
+            data: bincode::serialize(&event)?,
+        });
+
+        Ok(self.reactor.dispatch(states, event)?)
+    }
+
+    /// Finish recording, hashing the `State`s in `states`.
+    pub fn finish(self, states: &StateContainer) -> Result<Replay, ReplayError> {
+        Ok(Replay {
+            snapshot: self.snapshot,
+            events: self.events,
+            final_hash: states.state_hash(self.registry)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecs::{
+        Commands, Component, ComponentRegistry, EntityId, EntityState, Reader,
+        SerializableComponent, SerializableState, State, Writer,
+    };
+
+    #[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+    struct Ship {
+        position: f64,
+        velocity: f64,
+    }
+    impl State for Ship {}
+    impl SerializableState for Ship {
+        const NAME: &'static str = "ship";
+    }
+
+    #[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+    struct Log(Vec<f64>);
+    impl State for Log {}
+    impl SerializableState for Log {
+        const NAME: &'static str = "log";
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Thrust(f64);
+    impl Event for Thrust {}
+    impl SerializableEvent for Thrust {
+        const NAME: &'static str = "thrust";
+    }
+
+    #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+    struct Exhaust(f64);
+    impl Component for Exhaust {}
+    impl SerializableComponent for Exhaust {
+        const NAME: &'static str = "exhaust";
+    }
+
+    fn exhaust(ev: &Thrust, commands: Commands<'_>) -> anyhow::Result<()> {
+        let plume = commands.spawn();
+        commands.insert(plume, Exhaust(ev.0));
+        Ok(())
+    }
+
+    fn thrust(ev: &Thrust, mut ship: Writer<'_, Ship>) -> anyhow::Result<()> {
+        ship.velocity += ev.0;
+        ship.position += ship.velocity;
+        Ok(())
+    }
+
+    fn log(_: &Thrust, ship: Reader<'_, Ship>, mut log: Writer<'_, Log>) -> anyhow::Result<()> {
+        log.0.push(ship.position);
+        Ok(())
+    }
+
+    fn reactor(scale: f64) -> Reactor {
+        Reactor::builder()
+            .add(thrust)
+            .add(log)
+            .add(exhaust)
+            .add(move |_: &Thrust, mut ship: Writer<'_, Ship>| {
+                ship.velocity *= scale;
+                Ok(())
+            })
+            .build()
+            .unwrap()
+    }
+
+    fn registries() -> (StateRegistry, EventRegistry) {
+        (
+            StateRegistry::new()
+                .register::<Ship>()
+                .register::<Log>()
+                .register_entities(ComponentRegistry::new().register::<Exhaust>()),
+            EventRegistry::new().register::<Thrust>(),
+        )
+    }
+
+    /// Every `Exhaust` with its entity, in order of `EntityId`.
+    fn plumes(entities: &EntityState) -> Vec<(EntityId, Exhaust)> {
+        let mut plumes = entities
+            .entities()
+            .map(|e| (e, entities.get::<Exhaust>(e).unwrap().clone()))
+            .collect::<Vec<_>>();
+        plumes.sort_by_key(|(e, _)| *e);
+        plumes
+    }
+
+    fn record() -> (Replay, Ship, Log, Vec<(EntityId, Exhaust)>) {
+        let (states, _) = registries();
+        let reactor = reactor(0.5);
+        let container = reactor.new_state_container();
+        container.get_mut::<Ship>().unwrap().position = 10.0;
+
+        let mut recorder = Recorder::new(&reactor, &states, &container).unwrap();
+        for thrust in [1.0, 2.0, -0.5, 0.25] {
+            recorder.dispatch(&container, Thrust(thrust)).unwrap();
+        }
+
+        let replay = recorder.finish(&container).unwrap();
+        let ship = container.get::<Ship>().unwrap().clone();
+        let log = container.get::<Log>().unwrap().clone();
+        let plumes = plumes(&container.get::<EntityState>().unwrap());
+        (replay, ship, log, plumes)
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let (replay, ship, log, exhaust) = record();
+        assert_eq!(replay.len(), 4);
+        assert_eq!(log.0.len(), 4);
+        assert_eq!(exhaust.len(), 4);
+
+        let mut data = Vec::new();
+        replay.save(&mut data).unwrap();
+        let replay = Replay::load(data.as_slice()).unwrap();
+
+        let (states, events) = registries();
+        let played = replay.play(&reactor(0.5), &states, &events).unwrap();
+        assert_eq!(*played.get::<Ship>().unwrap(), ship);
+        assert_eq!(*played.get::<Log>().unwrap(), log);
+        assert_eq!(plumes(&played.get::<EntityState>().unwrap()), exhaust);
+    }
+
+    #[test]
+    fn test_replay_desync() {
+        let (replay, ..) = record();
+        let (states, events) = registries();
+        assert!(matches!(
+            replay.play(&reactor(0.25), &states, &events),
+            Err(ReplayError::Desync { .. })
+        ));
+
+        assert!(matches!(
+            replay.play(&reactor(0.5), &states, &EventRegistry::new()),
+            Err(ReplayError::UnknownEvent(name)) if name == "thrust"
+        ));
+    }
+}
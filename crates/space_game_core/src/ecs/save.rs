@@ -0,0 +1,928 @@
+//! [`SerializableState`] and saving and loading [`StateContainer`]s.
+
+use std::any::{type_name, Any};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use anyhow::{bail, format_err};
+use bincode::Options;
+use ron::ser::PrettyConfig;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use serde::{Deserializer, Serialize};
+use thiserror::Error;
+
+use super::entity::{ComponentId, ComponentRegistry, EntityState, SavedEntities};
+use super::replay::{EventRegistry, ReplayError};
+use super::schedule::{SavedScheduler, Scheduler};
+use super::state::{AnyState, State, StateContainer, StateId};
+
+/// Version of the file layout written by [`StateContainer::save`].
+///
+/// Files written with version 1, which predates per-`State` versions, can still be
+/// loaded. Every `State` in them is treated as version 1.
+pub const SAVE_FORMAT_VERSION: u32 = 2;
+
+/// Trait for `State`s which can be saved and loaded with a [`StateRegistry`].
+pub trait SerializableState: State + Serialize + DeserializeOwned {
+    /// Stable name identifying this `State` in saved data.
+    ///
+    /// Unlike the [`StateId`], which is derived from the type, the name must stay the
+    /// same between builds so that older saves can still be loaded.
+    const NAME: &'static str;
+
+    /// Version of this `State`'s serialized layout.
+    ///
+    /// Increment this when the layout changes, and register a migration from the
+    /// previous version with [`StateRegistry::migration`].
+    const VERSION: u32 = 1;
+}
+
+/// File format used to save a [`StateContainer`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SaveFormat {
+    /// Human-readable [RON](https://github.com/ron-rs/ron).
+    Ron,
+    /// Compact binary encoding, using [`bincode`].
+    Binary,
+}
+
+/// Type-erased functions for saving and loading a [`SerializableState`].
+struct Registration {
+    /// Current version of the `State`'s layout.
+    version: u32,
+    /// Returns the `State` inside an `AnyState` as a serializable value.
+    serialize: SerializeFn,
+    /// Deserializes the `State` and wraps it in an `AnyState`.
+    deserialize: DeserializeFn,
+    /// Converts the result of the last migration to the `State`.
+    from_migrated: fn(Box<dyn Any>) -> Option<AnyState>,
+}
+
+/// Type-erased function which returns the `State` inside an `AnyState` as a
+/// serializable value.
+type SerializeFn = Box<
+    dyn for<'s> Fn(&'s AnyState) -> Result<Box<dyn erased_serde::Serialize + 's>, SaveError>
+        + Send
+        + Sync,
+>;
+
+/// Type-erased function which deserializes a `State` and wraps it in an `AnyState`.
+type DeserializeFn = Box<
+    dyn Fn(&mut dyn erased_serde::Deserializer) -> Result<AnyState, erased_serde::Error>
+        + Send
+        + Sync,
+>;
+
+/// Type-erased function which deserializes the layout a [`Migration`] starts from.
+type DeserializeMigratedFn =
+    fn(&mut dyn erased_serde::Deserializer) -> Result<Box<dyn Any>, erased_serde::Error>;
+
+/// Type-erased function which converts a value to the layout of the next version.
+type MigrateFnBox = Box<dyn Fn(Box<dyn Any>) -> anyhow::Result<Box<dyn Any>> + Send + Sync>;
+
+/// Type-erased functions for migrating a `State` from one version to the next.
+struct Migration {
+    /// Deserializes the layout of the version being migrated from.
+    deserialize: DeserializeMigratedFn,
+    /// Converts a value of the version being migrated from to the next version.
+    migrate: MigrateFnBox,
+}
+
+/// Set of [`SerializableState`]s which can be saved and loaded by a [`StateContainer`].
+#[derive(Default)]
+pub struct StateRegistry {
+    /// Registration for each stable name.
+    registrations: HashMap<&'static str, Registration>,
+    /// Stable name for each registered `State`.
+    names: HashMap<StateId, &'static str>,
+    /// Migration for each stable name and the version it migrates from.
+    migrations: HashMap<(&'static str, u32), Migration>,
+}
+
+impl StateRegistry {
+    /// Construct an empty registry.
+    pub fn new() -> StateRegistry {
+        Default::default()
+    }
+
+    /// Register `S` so that it is included when saving and loading.
+    ///
+    /// Panics if another `State` is already registered with the same name.
+    pub fn register<S: SerializableState>(mut self) -> Self {
+        assert!(
+            !self.registrations.contains_key(S::NAME),
+            "State name `{}` is registered twice",
+            S::NAME
+        );
+
+        self.registrations.insert(
+            S::NAME,
+            Registration {
+                version: S::VERSION,
+                serialize: Box::new(as_serialize::<S>),
+                deserialize: Box::new(deserialize::<S>),
+                from_migrated: from_migrated::<S>,
+            },
+        );
+        self.names.insert(S::id(), S::NAME);
+        self
+    }
+
+    /// Register the [`Scheduler`] so that it is included when saving and loading, with
+    /// the name `scheduler`.
+    ///
+    /// Scheduled events are encoded with `events`. Saving fails with
+    /// [`SaveError::ScheduledEvent`] if an event is scheduled whose type isn't
+    /// registered in `events`.
+    ///
+    /// Panics if another `State` is already registered with the name `scheduler`.
+    pub fn register_scheduler(mut self, events: EventRegistry) -> Self {
+        const NAME: &str = "scheduler";
+        assert!(
+            !self.registrations.contains_key(NAME),
+            "State name `{NAME}` is registered twice"
+        );
+
+        let events = Arc::new(events);
+        let serialize: SerializeFn = {
+            let events = events.clone();
+            Box::new(move |state| {
+                let scheduler = state.downcast::<Scheduler>().unwrap();
+                let saved = scheduler
+                    .to_saved(&events)
+                    .map_err(|err| SaveError::ScheduledEvent(Box::new(err)))?;
+                Ok(Box::new(saved))
+            })
+        };
+        let deserialize = move |deserializer: &mut dyn erased_serde::Deserializer| {
+            let saved = erased_serde::deserialize::<SavedScheduler>(deserializer)?;
+            let scheduler = Scheduler::from_saved(saved, &events).map_err(de::Error::custom)?;
+            Ok(AnyState::new(scheduler))
+        };
+
+        self.registrations.insert(
+            NAME,
+            Registration {
+                version: 1,
+                serialize,
+                deserialize: Box::new(deserialize),
+                from_migrated: from_migrated::<Scheduler>,
+            },
+        );
+        self.names.insert(Scheduler::id(), NAME);
+        self
+    }
+
+    /// Register the [`EntityState`] so that it is included when saving and loading,
+    /// with the name `entities`.
+    ///
+    /// `Component`s are encoded with `components`. Saving fails with
+    /// [`SaveError::UnregisteredComponent`] if an entity has a `Component` which isn't
+    /// registered in `components`.
+    ///
+    /// Panics if another `State` is already registered with the name `entities`.
+    pub fn register_entities(mut self, components: ComponentRegistry) -> Self {
+        const NAME: &str = "entities";
+        assert!(
+            !self.registrations.contains_key(NAME),
+            "State name `{NAME}` is registered twice"
+        );
+
+        let components = Arc::new(components);
+        let serialize: SerializeFn = {
+            let components = components.clone();
+            Box::new(move |state| {
+                let entities = state.downcast::<EntityState>().unwrap();
+                Ok(Box::new(entities.to_saved(&components)?))
+            })
+        };
+        let deserialize = move |deserializer: &mut dyn erased_serde::Deserializer| {
+            let saved = erased_serde::deserialize::<SavedEntities>(deserializer)?;
+            let entities =
+                EntityState::from_saved(saved, &components).map_err(de::Error::custom)?;
+            Ok(AnyState::new(entities))
+        };
+
+        self.registrations.insert(
+            NAME,
+            Registration {
+                version: 1,
+                serialize,
+                deserialize: Box::new(deserialize),
+                from_migrated: from_migrated::<EntityState>,
+            },
+        );
+        self.names.insert(EntityState::id(), NAME);
+        self
+    }
+
+    /// Register a migration for the `State` named `name` from version `from` to `from + 1`.
+    ///
+    /// `Old` is the layout the `State` was saved with at version `from`, and `New` is its
+    /// layout at `from + 1`, which is either the `State` itself or the `Old` type of the
+    /// next migration. When loading, migrations are chained until the current version.
+    ///
+    /// Panics if a migration from `from` is already registered for `name`.
+    pub fn migration<Old, New>(
+        mut self,
+        name: &'static str,
+        from: u32,
+        migrate: impl Fn(Old) -> New + Send + Sync + 'static,
+    ) -> Self
+    where
+        Old: DeserializeOwned + 'static,
+        New: 'static,
+    {
+        assert!(
+            !self.migrations.contains_key(&(name, from)),
+            "Migration for state `{name}` from version {from} is registered twice"
+        );
+
+        let migrate = move |value: Box<dyn Any>| -> anyhow::Result<Box<dyn Any>> {
+            match value.downcast::<Old>() {
+                Ok(old) => Ok(Box::new(migrate(*old))),
+                Err(_) => bail!(
+                    "Migration for state `{name}` from version {from} expects `{}`",
+                    type_name::<Old>()
+                ),
+            }
+        };
+
+        self.migrations.insert(
+            (name, from),
+            Migration {
+                deserialize: deserialize_migrated::<Old>,
+                migrate: Box::new(migrate),
+            },
+        );
+        self
+    }
+
+    /// Get the stable name `id` was registered with.
+    pub fn name(&self, id: &StateId) -> Option<&'static str> {
+        self.names.get(id).copied()
+    }
+
+    /// Deserialize the `State` named `name` which was saved at `version`, migrating it
+    /// to the current version if needed.
+    fn deserialize(
+        &self,
+        name: &str,
+        version: u32,
+        deserializer: &mut dyn erased_serde::Deserializer,
+    ) -> anyhow::Result<AnyState> {
+        let registration = self
+            .registrations
+            .get(name)
+            .ok_or_else(|| format_err!("unknown state `{name}`"))?;
+
+        if version == registration.version {
+            return Ok((registration.deserialize)(deserializer)?);
+        } else if version > registration.version {
+            bail!(
+                "state `{name}` was saved with version {version}, but the latest supported is {}",
+                registration.version
+            );
+        }
+
+        let migration = |from| {
+            self.migrations
+                .get(&(name, from))
+                .ok_or_else(|| format_err!("no migration for state `{name}` from version {from}"))
+        };
+
+        let mut value = (migration(version)?.deserialize)(deserializer)?;
+        for from in version..registration.version {
+            value = (migration(from)?.migrate)(value)?;
+        }
+
+        (registration.from_migrated)(value).ok_or_else(|| {
+            format_err!(
+                "migrations for state `{name}` don't produce the version {} layout",
+                registration.version
+            )
+        })
+    }
+}
+
+/// Implementation of [`Registration::serialize`].
+fn as_serialize<S: SerializableState>(
+    state: &AnyState,
+) -> Result<Box<dyn erased_serde::Serialize + '_>, SaveError> {
+    Ok(Box::new(state.downcast::<S>().unwrap()))
+}
+
+/// Implementation of [`Registration::deserialize`].
+fn deserialize<S: SerializableState>(
+    deserializer: &mut dyn erased_serde::Deserializer,
+) -> Result<AnyState, erased_serde::Error> {
+    Ok(AnyState::new(erased_serde::deserialize::<S>(deserializer)?))
+}
+
+/// Implementation of [`Registration::from_migrated`].
+fn from_migrated<S: State>(value: Box<dyn Any>) -> Option<AnyState> {
+    Some(AnyState::new(*value.downcast::<S>().ok()?))
+}
+
+/// Implementation of [`Migration::deserialize`].
+fn deserialize_migrated<T: DeserializeOwned + 'static>(
+    deserializer: &mut dyn erased_serde::Deserializer,
+) -> Result<Box<dyn Any>, erased_serde::Error> {
+    Ok(Box::new(erased_serde::deserialize::<T>(deserializer)?))
+}
+
+/// Options used for [`SaveFormat::Binary`].
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+/// Errors which can occur while saving a [`StateContainer`].
+#[derive(Error, Debug)]
+pub enum SaveError {
+    /// Indicates that writing [`SaveFormat::Ron`] failed.
+    #[error("While writing RON: {0}")]
+    Ron(#[from] ron::Error),
+    /// Indicates that writing [`SaveFormat::Binary`] failed.
+    #[error("While writing binary: {0}")]
+    Binary(#[from] bincode::Error),
+    /// Indicates that an event scheduled in the [`Scheduler`] couldn't be encoded.
+    #[error("While encoding a scheduled event: {0}")]
+    ScheduledEvent(Box<ReplayError>),
+    /// Indicates that an entity has a `Component` which isn't registered in the
+    /// [`ComponentRegistry`].
+    #[error("Component {0} is not registered")]
+    UnregisteredComponent(ComponentId),
+}
+
+/// Errors which can occur while loading a [`StateContainer`].
+#[derive(Error, Debug)]
+pub enum LoadError {
+    /// Indicates that reading [`SaveFormat::Ron`] failed.
+    #[error("While reading RON: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+    /// Indicates that reading [`SaveFormat::Binary`] failed.
+    #[error("While reading binary: {0}")]
+    Binary(#[from] bincode::Error),
+    /// Indicates that the saved data contains a `State` which isn't in the container.
+    #[error("State {0} is not in the container")]
+    NotInContainer(StateId),
+}
+
+impl StateContainer {
+    /// Write every `State` registered in `registry` to `writer`.
+    ///
+    /// `State`s which aren't registered are skipped, and their `StateId`s are returned
+    /// so the caller can report them. `State`s are written in order of their stable
+    /// name, so saving the same values always produces the same output.
+    pub fn save(
+        &self,
+        registry: &StateRegistry,
+        format: SaveFormat,
+        writer: impl Write,
+    ) -> Result<Vec<StateId>, SaveError> {
+        let mut skipped = Vec::new();
+        let mut borrows = BTreeMap::new();
+        for id in self.ids() {
+            match registry.name(id) {
+                Some(name) => {
+                    borrows.insert(name, self.get_any(id).unwrap());
+                }
+                None => skipped.push(id.clone()),
+            }
+        }
+
+        let states = borrows
+            .iter()
+            .map(|(&name, state)| {
+                let registration = &registry.registrations[name];
+                Ok((
+                    name,
+                    (registration.version, (registration.serialize)(state)?),
+                ))
+            })
+            .collect::<Result<BTreeMap<_, _>, SaveError>>()?;
+
+        let file = (SAVE_FORMAT_VERSION, states);
+        match format {
+            SaveFormat::Ron => ron::ser::to_writer_pretty(writer, &file, PrettyConfig::default())?,
+            SaveFormat::Binary => bincode_options().serialize_into(writer, &file)?,
+        }
+
+        skipped.sort_by_key(|id| id.to_string());
+        Ok(skipped)
+    }
+
+    /// Hash every `State` registered in `registry`.
+    ///
+    /// The hash is computed from the [`SaveFormat::Binary`] encoding, so it is the same
+    /// between runs and builds as long as the `State`s' layouts don't change.
+    pub fn state_hash(&self, registry: &StateRegistry) -> Result<u64, SaveError> {
+        let mut data = Vec::new();
+        self.save(registry, SaveFormat::Binary, &mut data)?;
+        Ok(fnv1a(&data))
+    }
+
+    /// Read `State`s written by [`StateContainer::save`] from `reader`, replacing their
+    /// current values.
+    ///
+    /// `State`s saved with an older version are migrated using the migrations
+    /// registered in `registry`.
+    ///
+    /// Delayed `State`s have their previous-cycle value replaced as well. If an error
+    /// occurs, the container is left unchanged.
+    pub fn load(
+        &self,
+        registry: &StateRegistry,
+        format: SaveFormat,
+        reader: impl Read,
+    ) -> Result<(), LoadError> {
+        let seed = SaveFileSeed(registry);
+        let states = match format {
+            SaveFormat::Ron => ron::Options::default().from_reader_seed(reader, seed)?,
+            SaveFormat::Binary => bincode_options().deserialize_from_seed(seed, reader)?,
+        };
+
+        if let Some(state) = states
+            .iter()
+            .find(|state| self.get_any(&state.id()).is_none())
+        {
+            return Err(LoadError::NotInContainer(state.id()));
+        }
+
+        for mut state in states {
+            // Which lifecycle events have observers depends on the `Reactor`, so it is
+            // kept rather than loaded.
+            if let (Some(loaded), Some(current)) = (
+                state.downcast_mut::<EntityState>(),
+                self.get::<EntityState>(),
+            ) {
+                loaded.set_observed(current.observed());
+            }
+            self.replace(state);
+        }
+        Ok(())
+    }
+}
+
+/// 64-bit FNV-1a hash of `data`. Unlike `std`'s `DefaultHasher`, the result is
+/// guaranteed not to change between builds.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Deserializes a whole save file, checking its version.
+struct SaveFileSeed<'r>(&'r StateRegistry);
+
+impl<'de, 'r> DeserializeSeed<'de> for SaveFileSeed<'r> {
+    type Value = Vec<AnyState>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_tuple(2, self)
+    }
+}
+
+impl<'de, 'r> Visitor<'de> for SaveFileSeed<'r> {
+    type Value = Vec<AnyState>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a save file")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let version: u32 = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let versioned = match version {
+            1 => false,
+            SAVE_FORMAT_VERSION => true,
+            _ => {
+                return Err(de::Error::custom(format!(
+                    "unsupported save format version {version}, expected {SAVE_FORMAT_VERSION}"
+                )))
+            }
+        };
+
+        seq.next_element_seed(StatesSeed {
+            registry: self.0,
+            versioned,
+        })?
+        .ok_or_else(|| de::Error::invalid_length(1, &"a save file"))
+    }
+}
+
+/// Deserializes the map from stable name to `State`.
+struct StatesSeed<'r> {
+    /// Registry used to deserialize each `State`.
+    registry: &'r StateRegistry,
+    /// Whether each `State` is stored with its version, which is the case from
+    /// save format version 2.
+    versioned: bool,
+}
+
+impl<'de, 'r> DeserializeSeed<'de> for StatesSeed<'r> {
+    type Value = Vec<AnyState>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'r> Visitor<'de> for StatesSeed<'r> {
+    type Value = Vec<AnyState>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map from state name to state")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut states = Vec::new();
+        while let Some(name) = map.next_key::<String>()? {
+            let seed = StateSeed {
+                registry: self.registry,
+                name: &name,
+                version: 1,
+            };
+
+            states.push(if self.versioned {
+                map.next_value_seed(VersionedStateSeed(seed))?
+            } else {
+                map.next_value_seed(seed)?
+            });
+        }
+
+        Ok(states)
+    }
+}
+
+/// Deserializes a version followed by a `State` saved with that version.
+struct VersionedStateSeed<'r>(StateSeed<'r>);
+
+impl<'de, 'r> DeserializeSeed<'de> for VersionedStateSeed<'r> {
+    type Value = AnyState;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_tuple(2, self)
+    }
+}
+
+impl<'de, 'r> Visitor<'de> for VersionedStateSeed<'r> {
+    type Value = AnyState;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a version and state")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let version = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+
+        seq.next_element_seed(StateSeed { version, ..self.0 })?
+            .ok_or_else(|| de::Error::invalid_length(1, &"a version and state"))
+    }
+}
+
+/// Deserializes a single `State` saved with `version`.
+struct StateSeed<'r> {
+    /// Registry used to deserialize the `State`.
+    registry: &'r StateRegistry,
+    /// Stable name of the `State`.
+    name: &'r str,
+    /// Version the `State` was saved with.
+    version: u32,
+}
+
+impl<'de, 'r> DeserializeSeed<'de> for StateSeed<'r> {
+    type Value = AnyState;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let mut deserializer = <dyn erased_serde::Deserializer>::erase(deserializer);
+        self.registry
+            .deserialize(self.name, self.version, &mut deserializer)
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::ecs::{Component, SerializableComponent, Transform};
+
+    #[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+    struct Clock {
+        tick: u64,
+    }
+    impl State for Clock {}
+    impl SerializableState for Clock {
+        const NAME: &'static str = "clock";
+    }
+
+    #[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+    struct Ships(Vec<(String, f64)>);
+    impl State for Ships {}
+    impl SerializableState for Ships {
+        const NAME: &'static str = "ships";
+    }
+
+    #[derive(Clone, Default)]
+    struct Cache(Vec<u8>);
+    impl State for Cache {}
+
+    fn registry() -> StateRegistry {
+        StateRegistry::new().register::<Clock>().register::<Ships>()
+    }
+
+    fn container() -> StateContainer {
+        StateContainer::new([Clock::id(), Cache::id()], [Ships::id()])
+    }
+
+    #[test]
+    fn test_save_load() {
+        for format in [SaveFormat::Ron, SaveFormat::Binary] {
+            let states = container();
+            states.get_mut::<Clock>().unwrap().tick = 42;
+            states.get_mut::<Ships>().unwrap().0 = vec![("Vostok".into(), 1.5)];
+            states.get_mut::<Cache>().unwrap().0 = vec![1, 2, 3];
+
+            let mut data = Vec::new();
+            let skipped = states.save(&registry(), format, &mut data).unwrap();
+            assert_eq!(skipped, vec![Cache::id()]);
+
+            let loaded = container();
+            loaded.load(&registry(), format, data.as_slice()).unwrap();
+            assert_eq!(loaded.get::<Clock>().unwrap().tick, 42);
+            assert_eq!(
+                *loaded.get::<Ships>().unwrap(),
+                *states.get::<Ships>().unwrap()
+            );
+            assert_eq!(
+                *loaded.get_delayed::<Ships>().unwrap(),
+                *states.get::<Ships>().unwrap()
+            );
+            assert!(loaded.get::<Cache>().unwrap().0.is_empty());
+
+            let mut resaved = Vec::new();
+            loaded.save(&registry(), format, &mut resaved).unwrap();
+            assert_eq!(resaved, data);
+        }
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let states = container();
+        let mut data = Vec::new();
+        states
+            .save(&registry(), SaveFormat::Ron, &mut data)
+            .unwrap();
+
+        // The container isn't modified if a `State` is unknown.
+        let partial = StateRegistry::new().register::<Clock>();
+        let err = states
+            .load(&partial, SaveFormat::Ron, data.as_slice())
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown state `ships`"), "{err}");
+
+        let missing = StateContainer::new([Clock::id()], []);
+        assert!(matches!(
+            missing.load(&registry(), SaveFormat::Ron, data.as_slice()),
+            Err(LoadError::NotInContainer(id)) if id == Ships::id()
+        ));
+
+        let future =
+            String::from_utf8(data)
+                .unwrap()
+                .replacen(&SAVE_FORMAT_VERSION.to_string(), "999", 1);
+        let err = states
+            .load(&registry(), SaveFormat::Ron, future.as_bytes())
+            .unwrap_err();
+        assert!(err.to_string().contains("version 999"), "{err}");
+    }
+
+    #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+    struct Hull(f64);
+    impl Component for Hull {}
+    impl SerializableComponent for Hull {
+        const NAME: &'static str = "hull";
+    }
+
+    #[derive(Clone, PartialEq, Debug)]
+    struct Crew(u32);
+    impl Component for Crew {}
+
+    fn entity_registry() -> StateRegistry {
+        StateRegistry::new().register_entities(ComponentRegistry::new().register::<Hull>())
+    }
+
+    #[test]
+    fn test_save_load_entities() {
+        for format in [SaveFormat::Ron, SaveFormat::Binary] {
+            let states = StateContainer::new([EntityState::id()], []);
+            let (station, ship) = {
+                let mut entities = states.get_mut::<EntityState>().unwrap();
+                let station = entities.spawn();
+                let wreck = entities.spawn();
+                let ship = entities.spawn();
+                entities.insert(station, Transform::default()).unwrap();
+                entities.insert(ship, Hull(0.75)).unwrap();
+                entities.set_parent(ship, station).unwrap();
+                entities.despawn(wreck);
+                (station, ship)
+            };
+
+            let mut data = Vec::new();
+            states.save(&entity_registry(), format, &mut data).unwrap();
+
+            let loaded = StateContainer::new([EntityState::id()], []);
+            loaded
+                .load(&entity_registry(), format, data.as_slice())
+                .unwrap();
+            {
+                let entities = loaded.get::<EntityState>().unwrap();
+                assert_eq!(entities.entities().count(), 2);
+                assert!(entities.has::<Transform>(station));
+                assert_eq!(entities.get::<Hull>(ship).as_deref(), Some(&Hull(0.75)));
+                assert_eq!(entities.parent(ship), Some(station));
+                assert_eq!(entities.children(station), [ship]);
+            }
+
+            // Free slots are reused in the same order as before saving.
+            let spawned = states.get_mut::<EntityState>().unwrap().spawn();
+            assert_eq!(loaded.get_mut::<EntityState>().unwrap().spawn(), spawned);
+
+            let mut resaved = Vec::new();
+            loaded
+                .save(&entity_registry(), format, &mut resaved)
+                .unwrap();
+            let mut saved = Vec::new();
+            states.save(&entity_registry(), format, &mut saved).unwrap();
+            assert_eq!(resaved, saved);
+        }
+
+        let states = StateContainer::new([EntityState::id()], []);
+        {
+            let mut entities = states.get_mut::<EntityState>().unwrap();
+            let entity = entities.spawn();
+            entities.insert(entity, Crew(3)).unwrap();
+        }
+        assert!(matches!(
+            states.save(&entity_registry(), SaveFormat::Binary, &mut Vec::new()),
+            Err(SaveError::UnregisteredComponent(id)) if id == Crew::id()
+        ));
+    }
+
+    /// Version 1 of the `"ships"` state, which was [`Ships`].
+    #[derive(Deserialize)]
+    struct ShipsV1(Vec<(String, f64)>);
+
+    /// Version 2 of the `"ships"` state.
+    #[derive(Deserialize)]
+    struct ShipsV2(Vec<ShipV2>);
+
+    /// Layout of a ship in version 2 of the `"ships"` state.
+    #[derive(Deserialize)]
+    struct ShipV2 {
+        name: String,
+        altitude: f64,
+    }
+
+    #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+    struct Ship {
+        name: String,
+        altitude: f64,
+        fuel: f64,
+    }
+
+    /// Version 3 of the `"ships"` state.
+    #[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+    struct Fleet {
+        ships: Vec<Ship>,
+    }
+    impl State for Fleet {}
+    impl SerializableState for Fleet {
+        const NAME: &'static str = "ships";
+        const VERSION: u32 = 3;
+    }
+
+    fn migrating_registry() -> StateRegistry {
+        StateRegistry::new()
+            .register::<Clock>()
+            .register::<Fleet>()
+            .migration("ships", 1, |ships: ShipsV1| {
+                ShipsV2(
+                    ships
+                        .0
+                        .into_iter()
+                        .map(|(name, altitude)| ShipV2 { name, altitude })
+                        .collect(),
+                )
+            })
+            .migration("ships", 2, |ships: ShipsV2| Fleet {
+                ships: ships
+                    .0
+                    .into_iter()
+                    .map(|ship| Ship {
+                        name: ship.name,
+                        altitude: ship.altitude,
+                        fuel: 100.0,
+                    })
+                    .collect(),
+            })
+    }
+
+    /// Load a fixture written by an older build into a fresh container.
+    fn load_fixture(
+        registry: &StateRegistry,
+        format: SaveFormat,
+        data: &[u8],
+    ) -> Result<StateContainer, LoadError> {
+        let states = StateContainer::new([Clock::id(), Fleet::id()], []);
+        states.load(registry, format, data)?;
+        Ok(states)
+    }
+
+    #[test]
+    fn test_migrate_fixtures() {
+        let ship = |name: &str, altitude| Ship {
+            name: name.into(),
+            altitude,
+            fuel: 100.0,
+        };
+
+        // Save format 1, with the ships at version 1.
+        let states = load_fixture(
+            &migrating_registry(),
+            SaveFormat::Ron,
+            include_bytes!("../../fixtures/save_v1.ron"),
+        )
+        .unwrap();
+        assert_eq!(states.get::<Clock>().unwrap().tick, 42);
+        assert_eq!(
+            states.get::<Fleet>().unwrap().ships,
+            vec![ship("Vostok", 1.5), ship("Mercury", 0.25)]
+        );
+
+        // Save format 2, with the ships at version 2.
+        for (format, data) in [
+            (
+                SaveFormat::Ron,
+                &include_bytes!("../../fixtures/save_v2.ron")[..],
+            ),
+            (
+                SaveFormat::Binary,
+                &include_bytes!("../../fixtures/save_v2.bin")[..],
+            ),
+        ] {
+            let states = load_fixture(&migrating_registry(), format, data).unwrap();
+            assert_eq!(states.get::<Clock>().unwrap().tick, 7);
+            assert_eq!(
+                states.get::<Fleet>().unwrap().ships,
+                vec![ship("Soyuz", 400.0)]
+            );
+        }
+    }
+
+    #[test]
+    fn test_migration_errors() {
+        let fixture = include_bytes!("../../fixtures/save_v2.ron");
+
+        // Migration from version 2 is missing.
+        let registry = StateRegistry::new().register::<Clock>().register::<Fleet>();
+        let err = load_fixture(&registry, SaveFormat::Ron, fixture)
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string()
+                .contains("no migration for state `ships` from version 2"),
+            "{err}"
+        );
+
+        // Migration from version 2 expects the wrong type.
+        let registry = StateRegistry::new()
+            .register::<Clock>()
+            .register::<Fleet>()
+            .migration("ships", 2, |ships: ShipsV2| ships);
+        let err = load_fixture(&registry, SaveFormat::Ron, fixture)
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string()
+                .contains("don't produce the version 3 layout"),
+            "{err}"
+        );
+
+        // Saved with a newer version than this build supports.
+        let future = String::from_utf8(fixture.to_vec())
+            .unwrap()
+            .replace("\"ships\": (2,", "\"ships\": (4,");
+        let err = load_fixture(&migrating_registry(), SaveFormat::Ron, future.as_bytes())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("saved with version 4"), "{err}");
+    }
+}
@@ -0,0 +1,496 @@
+//! [`Scheduler`] and related types.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{self, AtomicU64};
+
+use serde::{Deserialize, Serialize};
+
+use super::event::AnyEvent;
+use super::replay::{EncodedEvent, EventRegistry, ReplayError};
+use super::{Event, State};
+
+/// Event which advances simulated time.
+///
+/// Before the handlers for a `Tick` run, the [`Scheduler`]'s current time is set to
+/// [`Tick::time`]. Once they have run, every scheduled event which is due is
+/// delivered, in order of time.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize, Event)]
+#[event(name = "tick")]
+pub struct Tick {
+    /// Simulated time at the end of this tick, in seconds.
+    pub time: f64,
+    /// Simulated time elapsed since the previous tick, in seconds.
+    pub dt: f64,
+}
+
+/// Identifies an event scheduled with [`EventWriter::write_at`](super::EventWriter::write_at)
+/// or [`Scheduler::schedule`], so that it can be cancelled.
+///
+/// Handles are allocated by the `Scheduler`, so they are unique within its
+/// [`StateContainer`](super::StateContainer) and are the same every time the same
+/// events are dispatched to it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct ScheduleHandle {
+    /// Call of a handler which allocated the handle, reserved with the `Scheduler`.
+    call: u64,
+    /// Number of handles allocated before this one during the same call.
+    index: u64,
+}
+
+/// Allocates the [`ScheduleHandle`]s of events scheduled by one call of a handler.
+pub struct HandleAllocator {
+    /// Call reserved for the handler.
+    call: u64,
+    /// Index of the next handle.
+    next: AtomicU64,
+}
+
+impl HandleAllocator {
+    /// Construct an allocator for `call`, which was reserved with
+    /// [`Scheduler::reserve_calls`].
+    pub fn new(call: u64) -> HandleAllocator {
+        HandleAllocator {
+            call,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    /// Allocate the next handle.
+    pub(super) fn allocate(&self) -> ScheduleHandle {
+        ScheduleHandle {
+            call: self.call,
+            index: self.next.fetch_add(1, atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+/// Change to the [`Scheduler`] requested by a handler.
+pub enum ScheduleOp {
+    /// Schedule an event at an absolute time.
+    At(f64, ScheduleHandle, AnyEvent),
+    /// Schedule an event relative to the current time.
+    After(f64, ScheduleHandle, AnyEvent),
+    /// Cancel a scheduled event.
+    Cancel(ScheduleHandle),
+}
+
+/// Position of a scheduled event in the [`Scheduler`]'s queue.
+#[derive(Clone, Debug)]
+struct Entry {
+    /// Simulated time the event is due.
+    time: f64,
+    /// Order the event was scheduled in, which breaks ties between equal times.
+    seq: u64,
+    /// Handle of the event.
+    handle: ScheduleHandle,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.time
+            .total_cmp(&other.time)
+            .then(self.seq.cmp(&other.seq))
+    }
+}
+
+/// `State` which stores events scheduled for a later simulated time.
+///
+/// Every [`StateContainer`](super::StateContainer) created by a
+/// [`Reactor`](super::Reactor) has a `Scheduler`. Handlers schedule events through
+/// their [`EventWriter`](super::EventWriter), and the changes are applied once every
+/// handler for the current event has run.
+///
+/// The `Scheduler` is saved by [`StateContainer::save`](super::StateContainer::save)
+/// once it is registered with [`StateRegistry::register_scheduler`](super::StateRegistry::register_scheduler),
+/// which encodes scheduled events with an [`EventRegistry`].
+#[derive(Clone, Default, Debug, State)]
+pub struct Scheduler {
+    /// Simulated time of the most recent `Tick`.
+    now: f64,
+    /// Sequence number for the next scheduled event.
+    next_seq: u64,
+    /// First call which hasn't been reserved for allocating `ScheduleHandle`s.
+    next_call: u64,
+    /// Scheduled events ordered by time. Cancelled events are removed once they
+    /// reach the front.
+    queue: BinaryHeap<Reverse<Entry>>,
+    /// Events which are still scheduled.
+    events: HashMap<ScheduleHandle, AnyEvent>,
+}
+
+/// Layout of a [`Scheduler`] saved with
+/// [`StateRegistry::register_scheduler`](super::StateRegistry::register_scheduler).
+#[derive(Serialize, Deserialize)]
+pub(super) struct SavedScheduler {
+    /// Simulated time of the most recent `Tick`.
+    now: f64,
+    /// Sequence number for the next scheduled event.
+    next_seq: u64,
+    /// First call which hasn't been reserved for allocating `ScheduleHandle`s.
+    next_call: u64,
+    /// Events which are still scheduled, in the order they were scheduled.
+    events: Vec<SavedEvent>,
+}
+
+/// Scheduled event in a [`SavedScheduler`].
+#[derive(Serialize, Deserialize)]
+struct SavedEvent {
+    /// Simulated time the event is due.
+    time: f64,
+    /// Order the event was scheduled in.
+    seq: u64,
+    /// Handle of the event.
+    handle: ScheduleHandle,
+    /// The event, encoded with its stable name.
+    event: EncodedEvent,
+}
+
+impl Scheduler {
+    /// Simulated time of the most recent [`Tick`], in seconds.
+    pub fn now(&self) -> f64 {
+        self.now
+    }
+
+    /// Number of events which are scheduled.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Returns true if no events are scheduled.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Returns true if the event for `handle` is scheduled and hasn't been delivered.
+    pub fn is_scheduled(&self, handle: ScheduleHandle) -> bool {
+        self.events.contains_key(&handle)
+    }
+
+    /// Schedule `event` for the simulated time `time`, in seconds.
+    pub fn schedule<E: Event>(&mut self, time: f64, event: E) -> ScheduleHandle {
+        let handle = HandleAllocator::new(self.reserve_calls(1)).allocate();
+        self.insert(time, handle, AnyEvent::new(event));
+        handle
+    }
+
+    /// Cancel the event for `handle`. Returns false if it isn't scheduled.
+    pub fn cancel(&mut self, handle: ScheduleHandle) -> bool {
+        self.events.remove(&handle).is_some()
+    }
+
+    /// Apply changes requested by handlers, in order.
+    pub(super) fn apply(&mut self, ops: Vec<ScheduleOp>) {
+        for op in ops {
+            match op {
+                ScheduleOp::At(time, handle, event) => self.insert(time, handle, event),
+                ScheduleOp::After(delay, handle, event) => {
+                    self.insert(self.now + delay, handle, event)
+                }
+                ScheduleOp::Cancel(handle) => {
+                    self.cancel(handle);
+                }
+            }
+        }
+    }
+
+    /// Reserve `count` consecutive calls for allocating `ScheduleHandle`s, and return
+    /// the first.
+    pub(super) fn reserve_calls(&mut self, count: u64) -> u64 {
+        let first = self.next_call;
+        self.next_call += count;
+        first
+    }
+
+    /// Set the current time.
+    pub(super) fn advance(&mut self, time: f64) {
+        self.now = time;
+    }
+
+    /// Remove and return the earliest event which is due at the current time.
+    pub(super) fn pop_due(&mut self) -> Option<AnyEvent> {
+        while let Some(Reverse(entry)) = self.queue.peek() {
+            if entry.time > self.now {
+                return None;
+            }
+
+            let handle = entry.handle;
+            self.queue.pop();
+            if let Some(event) = self.events.remove(&handle) {
+                return Some(event);
+            }
+        }
+
+        None
+    }
+
+    /// Convert to the saved layout, encoding scheduled events with `registry`.
+    pub(super) fn to_saved(&self, registry: &EventRegistry) -> Result<SavedScheduler, ReplayError> {
+        let mut entries = self
+            .queue
+            .iter()
+            .map(|Reverse(entry)| entry)
+            .filter(|entry| self.events.contains_key(&entry.handle))
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.seq);
+
+        let events = entries
+            .into_iter()
+            .map(|entry| {
+                Ok(SavedEvent {
+                    time: entry.time,
+                    seq: entry.seq,
+                    handle: entry.handle,
+                    event: registry.encode(&self.events[&entry.handle])?,
+                })
+            })
+            .collect::<Result<_, ReplayError>>()?;
+
+        Ok(SavedScheduler {
+            now: self.now,
+            next_seq: self.next_seq,
+            next_call: self.next_call,
+            events,
+        })
+    }
+
+    /// Convert from the saved layout, decoding scheduled events with `registry`.
+    pub(super) fn from_saved(
+        saved: SavedScheduler,
+        registry: &EventRegistry,
+    ) -> Result<Scheduler, ReplayError> {
+        let mut scheduler = Scheduler {
+            now: saved.now,
+            next_seq: saved.next_seq,
+            next_call: saved.next_call,
+            ..Default::default()
+        };
+        for saved in saved.events {
+            let entry = Entry {
+                time: saved.time,
+                seq: saved.seq,
+                handle: saved.handle,
+            };
+            scheduler.queue.push(Reverse(entry));
+            scheduler
+                .events
+                .insert(saved.handle, registry.decode(&saved.event)?);
+        }
+        Ok(scheduler)
+    }
+
+    /// Add `event` to the queue.
+    fn insert(&mut self, time: f64, handle: ScheduleHandle, event: AnyEvent) {
+        self.queue.push(Reverse(Entry {
+            time,
+            seq: self.next_seq,
+            handle,
+        }));
+        self.next_seq += 1;
+        self.events.insert(handle, event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecs::{
+        EventWriter, Reactor, Reader, SaveError, SaveFormat, SerializableEvent, StateContainer,
+        StateRegistry, Writer,
+    };
+
+    #[derive(Clone, Default)]
+    struct Log(Vec<(f64, &'static str)>);
+    impl State for Log {}
+
+    #[derive(Clone, Default)]
+    struct Burn(Option<ScheduleHandle>);
+    impl State for Burn {}
+
+    #[derive(Debug)]
+    struct StartBurn(f64);
+    impl Event for StartBurn {}
+
+    #[derive(Debug)]
+    struct AbortBurn;
+    impl Event for AbortBurn {}
+
+    #[derive(Debug)]
+    struct Message(&'static str);
+    impl Event for Message {}
+
+    #[derive(Debug)]
+    struct StartTimer(f64);
+    impl Event for StartTimer {}
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Timer;
+    impl Event for Timer {}
+    impl SerializableEvent for Timer {
+        const NAME: &'static str = "timer";
+    }
+
+    fn start_burn(
+        ev: &StartBurn,
+        mut burn: Writer<'_, Burn>,
+        events: EventWriter<'_>,
+    ) -> anyhow::Result<()> {
+        burn.0 = Some(events.write_after(ev.0, Message("burn ended")));
+        events.write_after(ev.0, Message("after burn"));
+        events.write_at(2.0, Message("deadline"));
+        Ok(())
+    }
+
+    fn abort_burn(
+        _: &AbortBurn,
+        mut burn: Writer<'_, Burn>,
+        events: EventWriter<'_>,
+    ) -> anyhow::Result<()> {
+        if let Some(handle) = burn.0.take() {
+            events.cancel(handle);
+        }
+        Ok(())
+    }
+
+    fn log(
+        ev: &Message,
+        scheduler: Reader<'_, Scheduler>,
+        mut log: Writer<'_, Log>,
+    ) -> anyhow::Result<()> {
+        log.0.push((scheduler.now(), ev.0));
+        Ok(())
+    }
+
+    fn reactor() -> Reactor {
+        Reactor::builder()
+            .add(start_burn)
+            .add(abort_burn)
+            .add(log)
+            .build()
+            .unwrap()
+    }
+
+    fn tick(reactor: &Reactor, states: &StateContainer, time: f64) {
+        reactor.dispatch(states, Tick { time, dt: 1.0 }).unwrap();
+    }
+
+    #[test]
+    fn test_scheduled_events() {
+        let reactor = reactor();
+        let states = reactor.new_state_container();
+        tick(&reactor, &states, 1.0);
+        reactor.dispatch(&states, StartBurn(1.5)).unwrap();
+        assert_eq!(states.get::<Scheduler>().unwrap().len(), 3);
+
+        tick(&reactor, &states, 2.0);
+        assert_eq!(states.get::<Log>().unwrap().0, [(2.0, "deadline")]);
+
+        tick(&reactor, &states, 3.0);
+        assert_eq!(
+            states.get::<Log>().unwrap().0,
+            [(2.0, "deadline"), (3.0, "burn ended"), (3.0, "after burn")]
+        );
+        assert!(states.get::<Scheduler>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cancel_scheduled_event() {
+        let reactor = reactor();
+        let states = reactor.new_state_container();
+        reactor.dispatch(&states, StartBurn(5.0)).unwrap();
+        let handle = states.get::<Burn>().unwrap().0.unwrap();
+        assert!(states.get::<Scheduler>().unwrap().is_scheduled(handle));
+
+        reactor.dispatch(&states, AbortBurn).unwrap();
+        assert!(!states.get::<Scheduler>().unwrap().is_scheduled(handle));
+
+        let later = states
+            .get_mut::<Scheduler>()
+            .unwrap()
+            .schedule(4.0, Message("manual"));
+        tick(&reactor, &states, 10.0);
+        assert_eq!(
+            states.get::<Log>().unwrap().0,
+            [(10.0, "deadline"), (10.0, "manual"), (10.0, "after burn")]
+        );
+        assert!(!states.get_mut::<Scheduler>().unwrap().cancel(later));
+    }
+
+    #[test]
+    fn test_save_scheduled_events() {
+        fn start_timer(
+            ev: &StartTimer,
+            mut burn: Writer<'_, Burn>,
+            events: EventWriter<'_>,
+        ) -> anyhow::Result<()> {
+            burn.0 = Some(events.write_after(ev.0, Timer));
+            Ok(())
+        }
+
+        fn timer(
+            _: &Timer,
+            scheduler: Reader<'_, Scheduler>,
+            mut log: Writer<'_, Log>,
+        ) -> anyhow::Result<()> {
+            log.0.push((scheduler.now(), "timer"));
+            Ok(())
+        }
+
+        let reactor = Reactor::builder()
+            .add(start_timer)
+            .add(timer)
+            .add(log)
+            .build()
+            .unwrap();
+        let registry =
+            StateRegistry::new().register_scheduler(EventRegistry::new().register::<Timer>());
+
+        let states = reactor.new_state_container();
+        tick(&reactor, &states, 1.0);
+        reactor.dispatch(&states, StartTimer(1.5)).unwrap();
+        let handle = states.get::<Burn>().unwrap().0.unwrap();
+        let mut data = Vec::new();
+        states
+            .save(&registry, SaveFormat::Binary, &mut data)
+            .unwrap();
+
+        let loaded = reactor.new_state_container();
+        loaded
+            .load(&registry, SaveFormat::Binary, data.as_slice())
+            .unwrap();
+        assert_eq!(loaded.get::<Scheduler>().unwrap().now(), 1.0);
+        assert!(loaded.get::<Scheduler>().unwrap().is_scheduled(handle));
+
+        // Handles allocated after loading don't collide with the saved ones.
+        reactor.dispatch(&loaded, StartTimer(5.0)).unwrap();
+        assert_ne!(loaded.get::<Burn>().unwrap().0, Some(handle));
+
+        tick(&reactor, &loaded, 3.0);
+        assert_eq!(loaded.get::<Log>().unwrap().0, [(3.0, "timer")]);
+        assert!(!loaded.get::<Scheduler>().unwrap().is_scheduled(handle));
+
+        // Events whose type isn't registered can't be saved.
+        states
+            .get_mut::<Scheduler>()
+            .unwrap()
+            .schedule(4.0, Message("unregistered"));
+        assert!(matches!(
+            states.save(&registry, SaveFormat::Binary, &mut Vec::new()),
+            Err(SaveError::ScheduledEvent(_))
+        ));
+    }
+}
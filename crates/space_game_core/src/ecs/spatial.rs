@@ -0,0 +1,590 @@
+//! [`SpatialIndex`] and related types, for finding the entities near a point.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+use nalgebra::{Unit, Vector3};
+
+use super::entity::{Component, EntityId, EntityState};
+use super::hierarchy::{Parent, Transform};
+use super::plugin::Plugin;
+use super::query::{Query, Without};
+use super::reactor::ReactorBuilder;
+use super::schedule::Tick;
+use super::state::Writer;
+use super::State;
+
+/// Maximum number of entities stored in a leaf of the [`SpatialIndex`].
+const LEAF_SIZE: usize = 4;
+
+/// `Component` which gives an entity a spherical extent in the [`SpatialIndex`].
+///
+/// Entities without one are indexed as points.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BoundingSphere {
+    /// Radius around the entity's position, in meters.
+    pub radius: f64,
+}
+
+impl Component for BoundingSphere {}
+
+/// Closest entity hit by a ray, returned by [`SpatialIndex::raycast`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RayHit {
+    /// The entity which was hit.
+    pub entity: EntityId,
+    /// Distance along the ray to the entity's [`BoundingSphere`], in meters.
+    pub distance: f64,
+}
+
+/// Entity stored in the [`SpatialIndex`].
+#[derive(Clone, Copy, Debug)]
+struct Item {
+    /// The indexed entity.
+    entity: EntityId,
+    /// World position of the entity.
+    center: Vector3<f64>,
+    /// Radius of its `BoundingSphere`, or 0.
+    radius: f64,
+}
+
+impl Item {
+    /// Distance from `point` to the surface of the item, or 0 if `point` is inside it.
+    fn distance(&self, point: &Vector3<f64>) -> f64 {
+        ((point - self.center).norm() - self.radius).max(0.0)
+    }
+}
+
+/// Axis-aligned bounding box.
+#[derive(Clone, Copy, Debug)]
+struct Aabb {
+    /// Minimum corner.
+    min: Vector3<f64>,
+    /// Maximum corner.
+    max: Vector3<f64>,
+}
+
+impl Aabb {
+    /// Construct the smallest box containing every item in `items`.
+    fn around(items: &[Item]) -> Aabb {
+        let mut min = Vector3::repeat(f64::INFINITY);
+        let mut max = Vector3::repeat(f64::NEG_INFINITY);
+        for item in items {
+            let extent = Vector3::repeat(item.radius);
+            min = min.inf(&(item.center - extent));
+            max = max.sup(&(item.center + extent));
+        }
+        Aabb { min, max }
+    }
+
+    /// Distance from `point` to the box, or 0 if `point` is inside it.
+    fn distance(&self, point: &Vector3<f64>) -> f64 {
+        let outside = (self.min - point)
+            .sup(&(point - self.max))
+            .sup(&Vector3::zeros());
+        outside.norm()
+    }
+
+    /// Distance along a ray to where it enters the box, if it does so within
+    /// `max_distance`. `inv_direction` is the reciprocal of each component of the ray's
+    /// direction.
+    fn ray_entry(
+        &self,
+        origin: &Vector3<f64>,
+        inv_direction: &Vector3<f64>,
+        max_distance: f64,
+    ) -> Option<f64> {
+        let mut near = 0.0_f64;
+        let mut far = max_distance;
+        for axis in 0..3 {
+            let t1 = (self.min[axis] - origin[axis]) * inv_direction[axis];
+            let t2 = (self.max[axis] - origin[axis]) * inv_direction[axis];
+            // NaN occurs when the ray is parallel to, and on the edge of, a slab.
+            if t1.is_nan() || t2.is_nan() {
+                continue;
+            }
+            near = near.max(t1.min(t2));
+            far = far.min(t1.max(t2));
+        }
+        (near <= far).then_some(near)
+    }
+}
+
+/// Node of the bounding volume hierarchy.
+#[derive(Clone, Debug)]
+struct Node {
+    /// Box containing every item below this node.
+    bounds: Aabb,
+    /// Contents of the node.
+    kind: NodeKind,
+}
+
+/// Contents of a [`Node`].
+#[derive(Clone, Copy, Debug)]
+enum NodeKind {
+    /// Leaf storing a range of `items`.
+    Leaf {
+        /// First item in the leaf.
+        start: usize,
+        /// One past the last item in the leaf.
+        end: usize,
+    },
+    /// Internal node with two children.
+    Internal {
+        /// Index of the first child in `nodes`.
+        left: usize,
+        /// Index of the second child in `nodes`.
+        right: usize,
+    },
+}
+
+/// `f64` which is totally ordered, for use in a `BinaryHeap`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Distance(f64);
+
+impl Eq for Distance {}
+
+impl PartialOrd for Distance {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Distance {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// `State` which indexes the world position of every entity with a [`Transform`], to
+/// find the entities near a point or along a ray.
+///
+/// The index is a bounding volume hierarchy which is rebuilt by
+/// [`SpatialIndex::update`]. [`SpatialIndexPlugin`] does so on every [`Tick`].
+/// Distances are measured to the surface of each entity's [`BoundingSphere`].
+#[derive(Clone, Default, Debug, State)]
+pub struct SpatialIndex {
+    /// Indexed entities, ordered so that each leaf stores a contiguous range.
+    items: Vec<Item>,
+    /// Nodes of the hierarchy, with the root first.
+    nodes: Vec<Node>,
+}
+
+impl SpatialIndex {
+    /// Rebuild the index from the world position of every entity with a [`Transform`].
+    pub fn update(&mut self, entities: &EntityState) {
+        let items = entities
+            .entities()
+            .filter(|&entity| entities.has::<Transform>(entity))
+            .map(|entity| Item {
+                entity,
+                center: entities.world_transform(entity).unwrap().position,
+                radius: entities
+                    .get::<BoundingSphere>(entity)
+                    .map_or(0.0, |sphere| sphere.radius),
+            })
+            .collect();
+        self.rebuild(items);
+    }
+
+    /// Rebuild the index from `items`.
+    fn rebuild(&mut self, items: Vec<Item>) {
+        self.items = items;
+        self.nodes.clear();
+        if !self.items.is_empty() {
+            self.build_node(0, self.items.len());
+        }
+    }
+
+    /// Build the subtree storing `items[start..end]`, and return the index of its root.
+    fn build_node(&mut self, start: usize, end: usize) -> usize {
+        let items = &mut self.items[start..end];
+        let bounds = Aabb::around(items);
+        let idx = self.nodes.len();
+        self.nodes.push(Node {
+            bounds,
+            kind: NodeKind::Leaf { start, end },
+        });
+        if items.len() <= LEAF_SIZE {
+            return idx;
+        }
+
+        // Split at the median along the longest axis of the box.
+        let axis = (bounds.max - bounds.min).imax();
+        let mid = items.len() / 2;
+        items.select_nth_unstable_by(mid, |a, b| a.center[axis].total_cmp(&b.center[axis]));
+
+        let left = self.build_node(start, start + mid);
+        let right = self.build_node(start + mid, end);
+        self.nodes[idx].kind = NodeKind::Internal { left, right };
+        idx
+    }
+
+    /// Number of indexed entities.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Returns true if no entities are indexed.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Get every entity within `radius` of `center`, nearest first.
+    pub fn within_radius(&self, center: &Vector3<f64>, radius: f64) -> Vec<EntityId> {
+        let mut found = Vec::new();
+        let mut stack = Vec::from_iter((!self.nodes.is_empty()).then_some(0));
+        while let Some(idx) = stack.pop() {
+            let node = &self.nodes[idx];
+            if node.bounds.distance(center) > radius {
+                continue;
+            }
+            match node.kind {
+                NodeKind::Leaf { start, end } => found.extend(
+                    self.items[start..end]
+                        .iter()
+                        .map(|item| (item.distance(center), item.entity))
+                        .filter(|&(distance, _)| distance <= radius),
+                ),
+                NodeKind::Internal { left, right } => stack.extend([left, right]),
+            }
+        }
+
+        found.sort_by(|a, b| a.0.total_cmp(&b.0));
+        found.into_iter().map(|(_, entity)| entity).collect()
+    }
+
+    /// Get the `k` entities nearest to `point` along with their distances, nearest first.
+    pub fn nearest(&self, point: &Vector3<f64>, k: usize) -> Vec<(EntityId, f64)> {
+        // Nodes are visited nearest first, and the search stops once the nearest
+        // remaining node is further than the `k`th nearest entity found so far.
+        let mut best = BinaryHeap::<(Distance, usize)>::new();
+        let mut queue = BinaryHeap::new();
+        if !self.nodes.is_empty() && k > 0 {
+            queue.push(Reverse((Distance(self.nodes[0].bounds.distance(point)), 0)));
+        }
+        while let Some(Reverse((Distance(distance), idx))) = queue.pop() {
+            if best.len() == k && distance > best.peek().unwrap().0 .0 {
+                break;
+            }
+            match self.nodes[idx].kind {
+                NodeKind::Leaf { start, end } => {
+                    for (item_idx, item) in self.items.iter().enumerate().take(end).skip(start) {
+                        best.push((Distance(item.distance(point)), item_idx));
+                        if best.len() > k {
+                            best.pop();
+                        }
+                    }
+                }
+                NodeKind::Internal { left, right } => {
+                    for child in [left, right] {
+                        let distance = self.nodes[child].bounds.distance(point);
+                        queue.push(Reverse((Distance(distance), child)));
+                    }
+                }
+            }
+        }
+
+        best.into_sorted_vec()
+            .into_iter()
+            .map(|(Distance(distance), idx)| (self.items[idx].entity, distance))
+            .collect()
+    }
+
+    /// Find the first entity whose [`BoundingSphere`] is hit by a ray from `origin`
+    /// along `direction`, within `max_distance`.
+    ///
+    /// Entities without a `BoundingSphere` can't be hit. A ray starting inside an
+    /// entity hits it at distance 0.
+    pub fn raycast(
+        &self,
+        origin: &Vector3<f64>,
+        direction: &Unit<Vector3<f64>>,
+        max_distance: f64,
+    ) -> Option<RayHit> {
+        let inv_direction = direction.map(f64::recip);
+        let mut closest: Option<RayHit> = None;
+        let mut stack = Vec::from_iter((!self.nodes.is_empty()).then_some(0));
+        while let Some(idx) = stack.pop() {
+            let limit = closest.map_or(max_distance, |hit| hit.distance);
+            let node = &self.nodes[idx];
+            if node
+                .bounds
+                .ray_entry(origin, &inv_direction, limit)
+                .is_none()
+            {
+                continue;
+            }
+            match node.kind {
+                NodeKind::Leaf { start, end } => {
+                    for item in &self.items[start..end] {
+                        let hit = ray_sphere(origin, direction, item);
+                        if let Some(distance) = hit.filter(|&d| d <= limit) {
+                            if closest.is_none_or(|hit| distance < hit.distance) {
+                                closest = Some(RayHit {
+                                    entity: item.entity,
+                                    distance,
+                                });
+                            }
+                        }
+                    }
+                }
+                NodeKind::Internal { left, right } => stack.extend([left, right]),
+            }
+        }
+        closest
+    }
+}
+
+/// Distance along a ray to where it first hits `item`'s sphere.
+fn ray_sphere(origin: &Vector3<f64>, direction: &Unit<Vector3<f64>>, item: &Item) -> Option<f64> {
+    if item.radius <= 0.0 {
+        return None;
+    }
+    let offset = origin - item.center;
+    let b = offset.dot(direction);
+    let c = offset.norm_squared() - item.radius * item.radius;
+    if c <= 0.0 {
+        return Some(0.0);
+    }
+    let discriminant = b * b - c;
+    if b > 0.0 || discriminant < 0.0 {
+        return None;
+    }
+    Some(-b - discriminant.sqrt())
+}
+
+/// [`Plugin`] which updates the [`SpatialIndex`] on every [`Tick`].
+///
+/// The update is labelled [`SpatialIndexPlugin::LABEL`], so handlers which move
+/// entities can run before it and handlers which query the index can run after it.
+pub struct SpatialIndexPlugin;
+
+impl SpatialIndexPlugin {
+    /// Label of the handler which updates the index.
+    pub const LABEL: &'static str = "spatial_index";
+}
+
+impl Plugin for SpatialIndexPlugin {
+    fn name(&self) -> &'static str {
+        "spatial_index"
+    }
+
+    fn build(&self, builder: ReactorBuilder) -> ReactorBuilder {
+        builder
+            .add(update_spatial_index)
+            .label(SpatialIndexPlugin::LABEL)
+    }
+}
+
+/// Handler which rebuilds the [`SpatialIndex`].
+///
+/// Reads entities through `Query`s so that it runs after handlers which move them.
+/// `unplaced` holds the parents of ancestors without a `Transform`, which are treated
+/// as the identity like in [`EntityState::world_transform`].
+fn update_spatial_index(
+    _: &Tick,
+    placed: Query<
+        '_,
+        (
+            EntityId,
+            &Transform,
+            Option<&BoundingSphere>,
+            Option<&Parent>,
+        ),
+    >,
+    unplaced: Query<'_, &Parent, Without<Transform>>,
+    mut index: Writer<'_, SpatialIndex>,
+) -> anyhow::Result<()> {
+    let items = placed
+        .iter()
+        .map(|(entity, transform, sphere, parent)| {
+            let mut world = *transform;
+            let mut ancestor = parent.map(Parent::get);
+            while let Some(current) = ancestor {
+                ancestor = match placed.get(current) {
+                    Some((_, transform, _, parent)) => {
+                        world = *transform * world;
+                        parent.map(Parent::get)
+                    }
+                    None => unplaced.get(current).map(Parent::get),
+                };
+            }
+            Item {
+                entity,
+                center: world.position,
+                radius: sphere.map_or(0.0, |sphere| sphere.radius),
+            }
+        })
+        .collect();
+    index.rebuild(items);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ecs::{DispatchMode, Reactor};
+
+    /// Spawn `count` entities at pseudo-random positions in a 1km cube, with radii up
+    /// to 10m, and return them along with their positions and radii.
+    fn scatter(entities: &mut EntityState, count: usize) -> Vec<(EntityId, Vector3<f64>, f64)> {
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % 1_000_000) as f64 / 1000.0
+        };
+
+        (0..count)
+            .map(|_| {
+                let entity = entities.spawn();
+                let position = Vector3::new(next(), next(), next());
+                let radius = next() / 100.0;
+                entities
+                    .insert(entity, Transform::from_position(position))
+                    .unwrap();
+                entities.insert(entity, BoundingSphere { radius }).unwrap();
+                (entity, position, radius)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_spatial_queries() {
+        let mut entities = EntityState::default();
+        let scattered = scatter(&mut entities, 2000);
+        let mut index = SpatialIndex::default();
+        index.update(&entities);
+        assert_eq!(index.len(), 2000);
+
+        let by_distance = |point: &Vector3<f64>| {
+            let mut sorted = scattered
+                .iter()
+                .map(|(entity, position, radius)| {
+                    (*entity, ((point - position).norm() - radius).max(0.0))
+                })
+                .collect::<Vec<_>>();
+            sorted.sort_by(|a, b| a.1.total_cmp(&b.1));
+            sorted
+        };
+
+        for point in [
+            Vector3::new(500.0, 500.0, 500.0),
+            Vector3::new(100.0, 900.0, 250.0),
+        ] {
+            let sorted = by_distance(&point);
+            let nearest = index.nearest(&point, 10);
+            assert_eq!(nearest, sorted[..10]);
+
+            let expected = sorted
+                .iter()
+                .take_while(|(_, distance)| *distance <= 100.0)
+                .map(|(entity, _)| *entity)
+                .collect::<Vec<_>>();
+            assert!(!expected.is_empty());
+            assert_eq!(index.within_radius(&point, 100.0), expected);
+        }
+
+        // Cast rays from outside the cube through each of the first few entities.
+        for &(_, position, _) in &scattered[..10] {
+            let origin = Vector3::new(-100.0, position.y, position.z);
+            let expected = scattered
+                .iter()
+                .filter_map(|&(entity, center, radius)| {
+                    let item = Item {
+                        entity,
+                        center,
+                        radius,
+                    };
+                    Some((entity, ray_sphere(&origin, &Vector3::x_axis(), &item)?))
+                })
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap();
+            let hit = index.raycast(&origin, &Vector3::x_axis(), 2000.0).unwrap();
+            assert_eq!((hit.entity, hit.distance), expected);
+            assert_eq!(index.raycast(&origin, &-Vector3::x_axis(), 2000.0), None);
+            assert_eq!(index.raycast(&origin, &Vector3::x_axis(), 1.0), None);
+        }
+    }
+
+    #[test]
+    fn test_spatial_index_plugin() {
+        let reactor = Reactor::builder()
+            .add_plugin(SpatialIndexPlugin)
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+        let (station, ship) = {
+            let mut entities = states.get_mut::<EntityState>().unwrap();
+            let [station, ship, _] = [(); 3].map(|_| entities.spawn());
+            entities
+                .insert(
+                    station,
+                    Transform::from_position(Vector3::new(1000.0, 0.0, 0.0)),
+                )
+                .unwrap();
+            entities
+                .insert(ship, Transform::from_position(Vector3::new(0.0, 5.0, 0.0)))
+                .unwrap();
+            entities.set_parent(ship, station).unwrap();
+            (station, ship)
+        };
+
+        reactor
+            .dispatch(&states, Tick { time: 1.0, dt: 1.0 })
+            .unwrap();
+        let index = states.get::<SpatialIndex>().unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(
+            index.nearest(&Vector3::new(1000.0, 10.0, 0.0), 2),
+            [(ship, 5.0), (station, 10.0)]
+        );
+    }
+
+    #[test]
+    fn test_spatial_index_after_mover() {
+        // Added before the plugin without an explicit ordering, so the `Query`s are
+        // what keep the two handlers from running alongside each other.
+        fn mover(_: &Tick, mut transforms: Query<'_, &mut Transform>) -> anyhow::Result<()> {
+            for transform in transforms.iter_mut() {
+                transform.position.x += 10.0;
+            }
+            Ok(())
+        }
+
+        let reactor = Reactor::builder()
+            .dispatch_mode(DispatchMode::Parallel)
+            .add(mover)
+            .add_plugin(SpatialIndexPlugin)
+            .build()
+            .unwrap();
+        let states = reactor.new_state_container();
+        let (station, ship) = {
+            let mut entities = states.get_mut::<EntityState>().unwrap();
+            let [station, dock, ship] = [(); 3].map(|_| entities.spawn());
+            entities
+                .insert(station, Transform::from_position(Vector3::zeros()))
+                .unwrap();
+            entities
+                .insert(ship, Transform::from_position(Vector3::zeros()))
+                .unwrap();
+            // `dock` has no `Transform`, so the ship is placed relative to the station.
+            entities.set_parent(dock, station).unwrap();
+            entities.set_parent(ship, dock).unwrap();
+            (station, ship)
+        };
+
+        for time in 1..=3 {
+            let time = time as f64;
+            let report = reactor.dispatch(&states, Tick { time, dt: 1.0 }).unwrap();
+            assert!(report.failures.is_empty());
+            let index = states.get::<SpatialIndex>().unwrap();
+            assert_eq!(
+                index.nearest(&Vector3::zeros(), 2),
+                [(station, 10.0 * time), (ship, 20.0 * time)]
+            );
+        }
+    }
+}
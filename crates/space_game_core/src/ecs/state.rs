@@ -1,19 +1,26 @@
 //! [`State`] and related types.
 
 use std::any::{type_name, Any, TypeId};
-use std::cell::{Ref, RefCell, RefMut};
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::hash::Hash;
 use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
 use anyhow::format_err;
+use atomic_refcell::{AtomicRef, AtomicRefCell, AtomicRefMut};
 
-use super::handler::{Context, Dependency, HandlerFnArg, HandlerFnArgBuilder};
+use super::entity::EntityState;
+use super::handler::{
+    Context, Dependency, HandlerFnArg, HandlerFnArgBuilder, ReadOnlyHandlerFnArg,
+};
 
 /// Trait for types stored in a [`StateContainer`]
-pub trait State: Clone + Default + 'static {
+///
+/// `State`s must be `Send + Sync` so that handlers using them can be dispatched in parallel.
+pub trait State: Clone + Default + Send + Sync + 'static {
     /// Return the `StateId` of this type.
     fn id() -> StateId {
         StateId {
@@ -57,7 +64,7 @@ impl Display for StateId {
 pub struct AnyState(Box<dyn AnyStateInner>);
 
 /// Object-safe trait used inside [`AnyState`]
-trait AnyStateInner {
+trait AnyStateInner: Send + Sync {
     /// Returns `self` as an [`Any`].
     fn as_any(&self) -> &dyn Any;
     /// Returns `self` as an [`Any`] mutably.
@@ -114,48 +121,207 @@ impl Clone for AnyState {
     }
 }
 
+/// Return a change tick which is greater than every change tick returned before it.
+///
+/// Change ticks order modifications of `State`s and `Component`s relative to handler
+/// runs, so that handlers can tell what changed since they last ran.
+pub(super) fn next_change_tick() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
 /// Contains a set of types implementing [`State`].
+///
+/// `State`s which are read through a [`DelayedReader`] are double-buffered: in
+/// addition to the current value, the container keeps a copy of the value as of
+/// the end of the previous cycle. The copy is refreshed by [`StateContainer::end_cycle`].
+///
+/// Each `State` also has a change tick, which is updated whenever it is borrowed
+/// mutably through [`StateContainer::get_mut`] or a [`Writer`] is dereferenced mutably.
+///
+/// A container is one independent world. It also stores the per-world bookkeeping of
+/// the [`Reactor`](super::Reactor) which dispatches to it, such as when each handler
+/// last ran and how many times it has failed.
 #[derive(Default)]
-pub struct StateContainer(HashMap<StateId, RefCell<AnyState>>);
+pub struct StateContainer {
+    /// Current value of each `State`.
+    states: HashMap<StateId, AtomicRefCell<AnyState>>,
+    /// Value of each delayed `State` as of the end of the previous cycle.
+    delayed: HashMap<StateId, AtomicRefCell<AnyState>>,
+    /// Change tick of the most recent modification to each `State`.
+    change_ticks: HashMap<StateId, AtomicU64>,
+    /// Change tick of the most recent run of each handler, by its index in the `Reactor`.
+    last_runs: Mutex<HashMap<usize, u64>>,
+    /// Number of times each handler has failed, by its index in the `Reactor`.
+    failure_counts: Mutex<HashMap<usize, u32>>,
+}
 
 impl StateContainer {
     /// Initialize from a set of `StateId`s. The `State`s are `Default` initialized.
-    pub fn new(ids: impl IntoIterator<Item = StateId>) -> StateContainer {
-        StateContainer(
-            ids.into_iter()
-                .map(|id| {
-                    let state = (id.default_fn)();
-                    (id, RefCell::new(state))
-                })
-                .collect(),
-        )
+    ///
+    /// `StateId`s in `delayed` are double-buffered so they can be read with a
+    /// [`DelayedReader`]. They are also added to the container if not present in `ids`.
+    pub fn new(
+        ids: impl IntoIterator<Item = StateId>,
+        delayed: impl IntoIterator<Item = StateId>,
+    ) -> StateContainer {
+        let delayed = delayed
+            .into_iter()
+            .map(|id| {
+                let state = (id.default_fn)();
+                (id, AtomicRefCell::new(state))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let states = ids
+            .into_iter()
+            .chain(delayed.keys().cloned())
+            .map(|id| {
+                let state = (id.default_fn)();
+                (id, AtomicRefCell::new(state))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let tick = next_change_tick();
+        let change_ticks = states
+            .keys()
+            .map(|id| (id.clone(), AtomicU64::new(tick)))
+            .collect();
+
+        StateContainer {
+            states,
+            delayed,
+            change_ticks,
+            last_runs: Default::default(),
+            failure_counts: Default::default(),
+        }
     }
 
     /// Get a reference to a `State` by its type.
-    pub fn get<S: State>(&self) -> Option<Ref<S>> {
-        let cell = self.0.get(&S::id())?;
-        Some(Ref::map(cell.borrow(), |a| a.downcast::<S>().unwrap()))
+    pub fn get<S: State>(&self) -> Option<AtomicRef<'_, S>> {
+        let cell = self.states.get(&S::id())?;
+        Some(AtomicRef::map(cell.borrow(), |a| {
+            a.downcast::<S>().unwrap()
+        }))
+    }
+
+    /// Get a mutable reference to a `State` by its type, and mark it changed.
+    pub fn get_mut<S: State>(&self) -> Option<AtomicRefMut<'_, S>> {
+        let (state, change_tick) = self.get_mut_untracked::<S>()?;
+        change_tick.store(next_change_tick(), Ordering::Relaxed);
+        Some(state)
+    }
+
+    /// Get a mutable reference to a `State` by its type, along with its change tick,
+    /// without marking it changed.
+    pub(super) fn get_mut_untracked<S: State>(&self) -> Option<(AtomicRefMut<'_, S>, &AtomicU64)> {
+        let cell = self.states.get(&S::id())?;
+        let state = AtomicRefMut::map(cell.borrow_mut(), |a| a.downcast_mut::<S>().unwrap());
+        Some((state, &self.change_ticks[&S::id()]))
+    }
+
+    /// Get the change tick of the most recent modification to a `State`.
+    pub fn change_tick(&self, id: &StateId) -> Option<u64> {
+        Some(self.change_ticks.get(id)?.load(Ordering::Relaxed))
+    }
+
+    /// Get the change tick of the most recent run of the handler at `idx`, or 0 if it
+    /// hasn't run with this container.
+    pub(super) fn last_run(&self, idx: usize) -> u64 {
+        self.last_runs
+            .lock()
+            .unwrap()
+            .get(&idx)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Record that the handler at `idx` ran at change tick `tick`.
+    pub(super) fn set_last_run(&self, idx: usize, tick: u64) {
+        self.last_runs.lock().unwrap().insert(idx, tick);
+    }
+
+    /// Get the number of times the handler at `idx` has failed with this container.
+    pub(super) fn failure_count(&self, idx: usize) -> u32 {
+        self.failure_counts
+            .lock()
+            .unwrap()
+            .get(&idx)
+            .copied()
+            .unwrap_or(0)
     }
 
-    /// Get a mutable reference to a `State` by its type.
-    pub fn get_mut<S: State>(&self) -> Option<RefMut<S>> {
-        let cell = self.0.get(&S::id())?;
-        Some(RefMut::map(cell.borrow_mut(), |a| {
-            a.downcast_mut::<S>().unwrap()
+    /// Record a failure of the handler at `idx`, and return its number of failures.
+    pub(super) fn record_failure(&self, idx: usize) -> u32 {
+        let mut failure_counts = self.failure_counts.lock().unwrap();
+        let failures = failure_counts.entry(idx).or_default();
+        *failures += 1;
+        *failures
+    }
+
+    /// Get a reference to the value a `State` had at the end of the previous cycle.
+    ///
+    /// Returns `None` unless the `State` was registered as delayed in [`StateContainer::new`].
+    pub fn get_delayed<S: State>(&self) -> Option<AtomicRef<'_, S>> {
+        let cell = self.delayed.get(&S::id())?;
+        Some(AtomicRef::map(cell.borrow(), |a| {
+            a.downcast::<S>().unwrap()
         }))
     }
+
+    /// Iterate over the `StateId` of every `State` in the container.
+    pub fn ids(&self) -> impl Iterator<Item = &StateId> {
+        self.states.keys()
+    }
+
+    /// Get a reference to a dynamically-typed `State` by its `StateId`.
+    pub fn get_any(&self, id: &StateId) -> Option<AtomicRef<'_, AnyState>> {
+        Some(self.states.get(id)?.borrow())
+    }
+
+    /// Replace the value of a `State` with `state`, including its previous-cycle value
+    /// if it is delayed. Returns false if the `State` isn't in the container.
+    pub fn replace(&self, state: AnyState) -> bool {
+        let id = state.id();
+        let cell = match self.states.get(&id) {
+            Some(cell) => cell,
+            None => return false,
+        };
+
+        if let Some(previous) = self.delayed.get(&id) {
+            *previous.borrow_mut() = state.clone();
+        }
+        *cell.borrow_mut() = state;
+        self.change_ticks[&id].store(next_change_tick(), Ordering::Relaxed);
+        true
+    }
+
+    /// Finish a cycle by copying the current value of every delayed `State`
+    /// into its previous-cycle buffer.
+    pub fn end_cycle(&self) {
+        for (id, previous) in &self.delayed {
+            previous.borrow_mut().clone_from(&self.states[id].borrow());
+        }
+    }
 }
 
 /// Handler argument used to read a `State`.
-pub struct Reader<'s, S: State>(Ref<'s, S>);
+pub struct Reader<'s, S: State>(AtomicRef<'s, S>);
 
 impl<'s, S: State> HandlerFnArg for Reader<'s, S> {
     type Builder = ReaderBuilder<S>;
     fn dependencies(out: &mut Vec<Dependency>) {
         out.push(Dependency::ReadState(S::id()));
+        // The `EntityState` lets the reader borrow any `Component`, so it must be
+        // ordered after every handler which writes one.
+        if S::id() == <EntityState as State>::id() {
+            out.push(Dependency::ReadAllComponents);
+        }
     }
 }
 
+impl<'s, S: State> ReadOnlyHandlerFnArg for Reader<'s, S> {}
+
 #[doc(hidden)]
 pub struct ReaderBuilder<S>(PhantomData<S>);
 
@@ -176,13 +342,16 @@ impl<'s, S: State> Deref for Reader<'s, S> {
     type Target = S;
 
     fn deref(&self) -> &Self::Target {
-        &*self.0
+        &self.0
     }
 }
 
 /// Handler argument used to read the value of a `State`
 /// on the previous cycle.
-pub struct DelayedReader<'s, S: State>(Ref<'s, S>);
+///
+/// Writers of the `State` in the current cycle are not visible, so a `DelayedReader`
+/// does not impose any ordering on the handler.
+pub struct DelayedReader<'s, S: State>(AtomicRef<'s, S>);
 
 impl<'s, S: State> HandlerFnArg for DelayedReader<'s, S> {
     type Builder = DelayedReaderBuilder<S>;
@@ -191,6 +360,8 @@ impl<'s, S: State> HandlerFnArg for DelayedReader<'s, S> {
     }
 }
 
+impl<'s, S: State> ReadOnlyHandlerFnArg for DelayedReader<'s, S> {}
+
 #[doc(hidden)]
 pub struct DelayedReaderBuilder<S>(PhantomData<S>);
 
@@ -200,8 +371,8 @@ impl<'c, S: State> HandlerFnArgBuilder<'c> for DelayedReaderBuilder<S> {
     fn build(context: &'c Context) -> anyhow::Result<DelayedReader<'c, S>> {
         let s = context
             .states
-            .get()
-            .ok_or_else(|| format_err!("Missing state `{}` for ReaderDelayed", S::id()))?;
+            .get_delayed()
+            .ok_or_else(|| format_err!("Missing state `{}` for DelayedReader", S::id()))?;
 
         Ok(DelayedReader(s))
     }
@@ -211,12 +382,21 @@ impl<'s, S: State> Deref for DelayedReader<'s, S> {
     type Target = S;
 
     fn deref(&self) -> &Self::Target {
-        &*self.0
+        &self.0
     }
 }
 
 /// Handler argument used to write a `State`.
-pub struct Writer<'s, S: State>(RefMut<'s, S>);
+///
+/// The `State` is only marked changed if the `Writer` is dereferenced mutably.
+pub struct Writer<'s, S: State> {
+    /// The borrowed `State`.
+    state: AtomicRefMut<'s, S>,
+    /// Change tick of the `State`.
+    change_tick: &'s AtomicU64,
+    /// Change tick of the current handler run.
+    tick: u64,
+}
 
 impl<'s, S: State> HandlerFnArg for Writer<'s, S> {
     type Builder = WriterBuilder<S>;
@@ -233,12 +413,16 @@ impl<'c, S: State> HandlerFnArgBuilder<'c> for WriterBuilder<S> {
     type Arg = Writer<'c, S>;
 
     fn build(context: &'c Context) -> anyhow::Result<Writer<'c, S>> {
-        let s = context
+        let (state, change_tick) = context
             .states
-            .get_mut()
+            .get_mut_untracked()
             .ok_or_else(|| format_err!("Missing state `{}` for Writer", S::id()))?;
 
-        Ok(Writer(s))
+        Ok(Writer {
+            state,
+            change_tick,
+            tick: context.tick,
+        })
     }
 }
 
@@ -246,12 +430,63 @@ impl<'s, S: State> Deref for Writer<'s, S> {
     type Target = S;
 
     fn deref(&self) -> &Self::Target {
-        &*self.0
+        &self.state
     }
 }
 
 impl<'s, S: State> DerefMut for Writer<'s, S> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut *self.0
+        self.change_tick.store(self.tick, Ordering::Relaxed);
+        &mut self.state
+    }
+}
+
+/// Handler argument and [`QueryFilter`](super::QueryFilter) which detect changes made
+/// since the handler last ran.
+///
+/// As a handler argument, `Changed<S>` reports whether `State` `S` changed, and orders
+/// the handler after writers of `S` like a [`Reader`]. As a query filter, `Changed<C>`
+/// matches entities in archetypes whose `Component` `C` changed. Everything counts as
+/// changed on a handler's first run.
+pub struct Changed<T> {
+    /// True if `T` changed since the handler last ran.
+    changed: bool,
+    /// Marker for the changed type.
+    marker: PhantomData<T>,
+}
+
+impl<T> Changed<T> {
+    /// Returns true if `T` changed since the handler last ran.
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+}
+
+impl<S: State> HandlerFnArg for Changed<S> {
+    type Builder = ChangedBuilder<S>;
+
+    fn dependencies(out: &mut Vec<Dependency>) {
+        out.push(Dependency::ReadState(S::id()));
+    }
+}
+
+impl<S: State> ReadOnlyHandlerFnArg for Changed<S> {}
+
+#[doc(hidden)]
+pub struct ChangedBuilder<S>(PhantomData<S>);
+
+impl<'c, S: State> HandlerFnArgBuilder<'c> for ChangedBuilder<S> {
+    type Arg = Changed<S>;
+
+    fn build(context: &'c Context) -> anyhow::Result<Changed<S>> {
+        let change_tick = context
+            .states
+            .change_tick(&S::id())
+            .ok_or_else(|| format_err!("Missing state `{}` for Changed", S::id()))?;
+
+        Ok(Changed {
+            changed: change_tick > context.last_run,
+            marker: PhantomData,
+        })
     }
 }
@@ -0,0 +1,324 @@
+//! [`ReactorTest`] and related types, for testing handlers.
+
+use std::fmt::Debug;
+
+use atomic_refcell::AtomicRef;
+
+use super::event::{AnyEvent, Event};
+use super::reactor::{DispatchError, DispatchReport, HandlerGroup, Reactor, ReactorBuilder};
+use super::save::StateRegistry;
+use super::state::{State, StateContainer};
+use super::topic::{AnyTopic, Topic};
+
+/// Every event processed and message published while dispatching, in order.
+#[derive(Default, Debug)]
+pub(super) struct DispatchTrace {
+    /// Every event processed, starting with the dispatched event.
+    pub(super) events: Vec<AnyEvent>,
+    /// Every message published. Messages published while handling the same event are
+    /// grouped by `Topic`.
+    pub(super) topics: Vec<AnyTopic>,
+}
+
+/// Harness which builds a [`Reactor`], dispatches a script of events to it, and
+/// captures everything that happened so that tests can assert on it.
+///
+/// The script is run twice with fresh `StateContainer`s, and [`ReactorTest::run`]
+/// panics unless both runs process the same events and publish the same messages,
+/// which catches handlers whose results depend on anything other than the script.
+pub struct ReactorTest {
+    /// Builder for the `Reactor` under test.
+    builder: ReactorBuilder,
+    /// Events to dispatch, in order.
+    script: Vec<AnyEvent>,
+    /// `State`s whose hashes must match between runs.
+    registry: Option<StateRegistry>,
+}
+
+impl Default for ReactorTest {
+    fn default() -> Self {
+        ReactorTest::new()
+    }
+}
+
+impl ReactorTest {
+    /// Construct a test with an empty `Reactor` and script.
+    pub fn new() -> ReactorTest {
+        ReactorTest {
+            builder: Reactor::builder(),
+            script: Vec::new(),
+            registry: None,
+        }
+    }
+
+    /// Add the handlers in `G` to the `Reactor`.
+    pub fn add_group<G: HandlerGroup>(self) -> Self {
+        self.configure(ReactorBuilder::add_group::<G>)
+    }
+
+    /// Configure the `Reactor` directly, such as to add individual handlers or plugins.
+    pub fn configure(mut self, f: impl FnOnce(ReactorBuilder) -> ReactorBuilder) -> Self {
+        self.builder = f(self.builder);
+        self
+    }
+
+    /// Seed `state` as the initial value of its `State`, before the `InitEvent`.
+    pub fn insert_state<S: State>(self, state: S) -> Self {
+        self.configure(|builder| builder.insert_state(state))
+    }
+
+    /// Append `event` to the script.
+    pub fn dispatch<E: Event>(mut self, event: E) -> Self {
+        self.script.push(AnyEvent::new(event));
+        self
+    }
+
+    /// Also require the hash of the `State`s in `registry` to match between runs.
+    pub fn compare_states(mut self, registry: StateRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Build the `Reactor` and run the script twice, returning the second run.
+    ///
+    /// Panics if the `Reactor` can't be built, or if the runs differ.
+    pub fn run(self) -> ReactorTestRun {
+        let reactor = self
+            .builder
+            .build()
+            .unwrap_or_else(|err| panic!("Failed to build reactor: {err}"));
+
+        let first = ReactorTestRun::new(&reactor, &self.script);
+        let second = ReactorTestRun::new(&reactor, &self.script);
+        assert_eq!(
+            format!("{:?}", first.trace),
+            format!("{:?}", second.trace),
+            "Runs of the same script produced different events or messages"
+        );
+        if let Some(registry) = &self.registry {
+            let hash = |run: &ReactorTestRun| run.states.state_hash(registry).unwrap();
+            assert_eq!(
+                hash(&first),
+                hash(&second),
+                "Runs of the same script produced different states"
+            );
+        }
+        second
+    }
+}
+
+/// Result of running the script of a [`ReactorTest`].
+pub struct ReactorTestRun {
+    /// `State`s at the end of the script.
+    states: StateContainer,
+    /// Everything which happened during the script.
+    trace: DispatchTrace,
+    /// Result of dispatching each event in the script.
+    reports: Vec<Result<DispatchReport, DispatchError>>,
+}
+
+impl ReactorTestRun {
+    /// Dispatch `script` to a new `StateContainer` for `reactor`.
+    fn new(reactor: &Reactor, script: &[AnyEvent]) -> ReactorTestRun {
+        let states = reactor.new_state_container();
+        let mut trace = DispatchTrace::default();
+        let reports = script
+            .iter()
+            .map(|event| reactor.dispatch_traced(&states, event.clone(), Some(&mut trace)))
+            .collect();
+        ReactorTestRun {
+            states,
+            trace,
+            reports,
+        }
+    }
+
+    /// The `State`s at the end of the script.
+    pub fn states(&self) -> &StateContainer {
+        &self.states
+    }
+
+    /// Get the final value of `State` `S`.
+    ///
+    /// Panics if the `Reactor` doesn't use `S`.
+    pub fn state<S: State>(&self) -> AtomicRef<'_, S> {
+        self.states
+            .get::<S>()
+            .unwrap_or_else(|| panic!("State `{}` is not used", S::id()))
+    }
+
+    /// Assert that the final value of `State` `S` is `expected`.
+    #[track_caller]
+    pub fn assert_state<S: State + PartialEq + Debug>(&self, expected: &S) {
+        assert_eq!(&*self.state::<S>(), expected);
+    }
+
+    /// Every event of type `E` which was processed, including those in the script, in
+    /// the order they were processed.
+    pub fn events<E: Event>(&self) -> Vec<&E> {
+        self.trace
+            .events
+            .iter()
+            .filter_map(|event| event.downcast::<E>())
+            .collect()
+    }
+
+    /// Every message published to `Topic` `T`, in order.
+    pub fn messages<T: Topic>(&self) -> Vec<&T> {
+        self.trace
+            .topics
+            .iter()
+            .filter_map(|message| message.downcast::<T>())
+            .collect()
+    }
+
+    /// Result of dispatching each event in the script.
+    pub fn reports(&self) -> &[Result<DispatchReport, DispatchError>] {
+        &self.reports
+    }
+
+    /// Assert that every event in the script was dispatched without a handler failing.
+    #[track_caller]
+    pub fn assert_no_failures(&self) {
+        for report in &self.reports {
+            match report {
+                Ok(report) => assert!(
+                    report.failures.is_empty(),
+                    "Handlers failed: {:?}",
+                    report.failures
+                ),
+                Err(err) => panic!("Dispatch failed: {err}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::ecs::{
+        handler_group, Commands, Component, ComponentRegistry, Event, EventWriter, Publisher,
+        SerializableComponent, State, Topic, Writer,
+    };
+
+    #[derive(Clone, Default, PartialEq, Debug, State)]
+    struct Fuel(f64);
+
+    #[derive(Debug, Event)]
+    struct Burn(f64);
+
+    #[derive(Debug, Event)]
+    struct Empty;
+
+    #[derive(Debug, Topic)]
+    struct Burned(f64);
+
+    struct Engine;
+
+    #[handler_group]
+    impl Engine {
+        #[handler]
+        fn burn(
+            ev: &Burn,
+            mut fuel: Writer<'_, Fuel>,
+            burned: Publisher<'_, Burned>,
+            events: EventWriter<'_>,
+        ) -> anyhow::Result<()> {
+            let amount = ev.0.min(fuel.0);
+            fuel.0 -= amount;
+            burned.publish(Burned(amount));
+            if fuel.0 == 0.0 {
+                events.write(Empty);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reactor_test() {
+        let run = ReactorTest::new()
+            .add_group::<Engine>()
+            .insert_state(Fuel(5.0))
+            .dispatch(Burn(3.0))
+            .dispatch(Burn(3.0))
+            .run();
+
+        run.assert_no_failures();
+        run.assert_state(&Fuel(0.0));
+        assert_eq!(run.events::<Burn>().len(), 2);
+        assert_eq!(run.events::<Empty>().len(), 1);
+        let burned = run.messages::<Burned>();
+        assert_eq!(burned.iter().map(|b| b.0).collect::<Vec<_>>(), [3.0, 2.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "different events or messages")]
+    fn test_reactor_test_nondeterministic() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        // Global state leaks between runs, so the second run behaves differently.
+        static RUNS: AtomicU32 = AtomicU32::new(0);
+        ReactorTest::new()
+            .configure(|builder| {
+                builder.add(|_: &Burn, events: EventWriter<'_>| {
+                    if RUNS.fetch_add(1, Ordering::Relaxed) == 0 {
+                        events.write(Empty);
+                    }
+                    Ok(())
+                })
+            })
+            .dispatch(Burn(1.0))
+            .run();
+    }
+
+    #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+    struct Debris(f64);
+    impl Component for Debris {}
+    impl SerializableComponent for Debris {
+        const NAME: &'static str = "debris";
+    }
+
+    fn entity_registry() -> StateRegistry {
+        StateRegistry::new().register_entities(ComponentRegistry::new().register::<Debris>())
+    }
+
+    #[test]
+    fn test_reactor_test_compare_entities() {
+        ReactorTest::new()
+            .configure(|builder| {
+                builder.add(|ev: &Burn, commands: Commands<'_>| {
+                    let debris = commands.spawn();
+                    commands.insert(debris, Debris(ev.0));
+                    Ok(())
+                })
+            })
+            .dispatch(Burn(1.0))
+            .dispatch(Burn(2.0))
+            .compare_states(entity_registry())
+            .run()
+            .assert_no_failures();
+    }
+
+    #[test]
+    #[should_panic(expected = "different states")]
+    fn test_reactor_test_entities_differ() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        // Global state leaks between runs, so the second run spawns different debris.
+        static RUNS: AtomicU32 = AtomicU32::new(0);
+        ReactorTest::new()
+            .configure(|builder| {
+                builder.add(|_: &Burn, commands: Commands<'_>| {
+                    let debris = commands.spawn();
+                    let runs = RUNS.fetch_add(1, Ordering::Relaxed);
+                    commands.insert(debris, Debris(f64::from(runs)));
+                    Ok(())
+                })
+            })
+            .dispatch(Burn(1.0))
+            .compare_states(entity_registry())
+            .run();
+    }
+}
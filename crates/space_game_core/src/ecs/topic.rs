@@ -1,13 +1,16 @@
 use std::any::{type_name, Any, TypeId};
-use std::cell::{Ref, RefCell};
 use std::collections::HashMap;
 use std::fmt::{self, Debug, Display};
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
 
-use super::handler::{Context, Dependency, HandlerFnArg, HandlerFnArgBuilder};
+use atomic_refcell::{AtomicRef, AtomicRefCell};
 
-pub trait Topic: Debug + 'static {
+use super::handler::{
+    Context, Dependency, HandlerFnArg, HandlerFnArgBuilder, ReadOnlyHandlerFnArg,
+};
+
+pub trait Topic: Debug + Send + Sync + 'static {
     fn id() -> TopicId {
         TopicId {
             id: TypeId::of::<Self>(),
@@ -44,7 +47,7 @@ impl Display for TopicId {
 pub struct AnyTopic(Box<dyn AnyTopicInner>);
 
 /// Object-safe trait used inside [`AnyTopic`].
-trait AnyTopicInner {
+trait AnyTopicInner: Send + Sync {
     fn as_any(&self) -> &dyn Any;
     fn id(&self) -> TopicId;
     fn debug_fmt(&self, f: &mut fmt::Formatter) -> fmt::Result;
@@ -84,36 +87,54 @@ impl Debug for AnyTopic {
     }
 }
 
+/// Stores the messages published to each `Topic` while handling an event.
+///
+/// Every `Topic` has its own cell, so handlers publishing to different `Topic`s
+/// can run in parallel.
 #[derive(Default)]
-pub struct TopicContainer(RefCell<HashMap<TypeId, Vec<AnyTopic>>>);
+pub struct TopicContainer(HashMap<TopicId, AtomicRefCell<Vec<AnyTopic>>>);
 
 impl TopicContainer {
-    pub fn new() -> Self {
-        Default::default()
+    pub fn new(ids: impl IntoIterator<Item = TopicId>) -> Self {
+        Self(
+            ids.into_iter()
+                .map(|id| (id, AtomicRefCell::default()))
+                .collect(),
+        )
     }
 
     pub fn publish<T: Topic>(&self, t: T) {
         self.0
+            .get(&T::id())
+            .unwrap_or_else(|| panic!("Topic `{}` was not registered", T::id()))
             .borrow_mut()
-            .entry(TypeId::of::<T>())
-            .or_default()
             .push(AnyTopic::new(t));
     }
 
-    pub fn get<T: Topic>(&self, idx: usize) -> Option<Ref<'_, T>> {
-        let tid = TypeId::of::<T>();
-        if self.0.borrow().get(&tid).map(|v| idx < v.len()) != Some(true) {
+    pub fn get<T: Topic>(&self, idx: usize) -> Option<AtomicRef<'_, T>> {
+        let messages = self.0.get(&T::id())?.borrow();
+        if idx >= messages.len() {
             return None;
         }
 
-        Some(Ref::map(self.0.borrow(), |m| {
-            m[&tid][idx].downcast::<T>().unwrap()
+        Some(AtomicRef::map(messages, |m| {
+            m[idx].downcast::<T>().unwrap()
         }))
     }
 
+    /// Remove every message, grouped by `Topic` in order of name.
+    pub fn take(&self) -> Vec<AnyTopic> {
+        let mut cells = self.0.iter().collect::<Vec<_>>();
+        cells.sort_by_key(|(id, _)| id.name);
+        cells
+            .into_iter()
+            .flat_map(|(_, messages)| std::mem::take(&mut *messages.borrow_mut()))
+            .collect()
+    }
+
     pub fn clear(&self) {
-        for v in self.0.borrow_mut().values_mut() {
-            v.clear();
+        for v in self.0.values() {
+            v.borrow_mut().clear();
         }
     }
 }
@@ -147,8 +168,8 @@ impl<'c, T: Topic> HandlerFnArgBuilder<'c> for PublisherBuilder<T> {
 pub struct Subscriber<'t, T: Topic>(&'t TopicContainer, PhantomData<&'t T>);
 
 impl<'t, T: Topic> Subscriber<'t, T> {
-    pub fn iter(&self) -> impl Iterator<Item = Ref<T>> + '_ {
-        (0..).into_iter().map_while(move |idx| self.0.get::<T>(idx))
+    pub fn iter(&self) -> impl Iterator<Item = AtomicRef<'_, T>> + '_ {
+        (0..).map_while(move |idx| self.0.get::<T>(idx))
     }
 }
 
@@ -160,6 +181,8 @@ impl<'t, T: Topic> HandlerFnArg for Subscriber<'t, T> {
     }
 }
 
+impl<'t, T: Topic> ReadOnlyHandlerFnArg for Subscriber<'t, T> {}
+
 pub struct SubscriberBuilder<T>(PhantomData<T>);
 
 impl<'c, T: Topic> HandlerFnArgBuilder<'c> for SubscriberBuilder<T> {
@@ -1,6 +1,12 @@
 //! TODO
 
 #![warn(clippy::missing_docs_in_private_items)]
+// Builders such as `ReactorBuilder` have `add` methods which add an item rather than
+// implement `std::ops::Add`.
+#![allow(clippy::should_implement_trait)]
+
+// Lets the derive macros refer to `::space_game_core` from inside this crate.
+extern crate self as space_game_core;
 
 #[allow(clippy::missing_docs_in_private_items)]
 pub mod orbit;
@@ -183,7 +183,7 @@ impl OrbitalElements {
                 if temp2 < 0.0 {
                     todo!();
                 }
-                2.0 * (temp2.sqrt() * (self.eccentricity / 2.0).tan()).atan()
+                2.0 * (temp2.sqrt() * (eccentric_anomaly / 2.0).tan()).atan()
             } else {
                 eccentric_anomaly
             };
@@ -286,4 +286,25 @@ mod tests {
         dbg!(&vel_error);
         assert!(pos_error < 1.0 && vel_error < 1.0);
     }
+
+    #[test]
+    fn true_anomaly_elliptic() {
+        let oe = OrbitalElements {
+            semi_major_axis: EARTH_RADIUS * 2.0,
+            eccentricity: 0.5,
+            inclination: 0.0,
+            longitude_of_ascending_node: 0.0,
+            argument_of_periapsis: 0.0,
+            mean_anomaly: 1.0,
+        };
+        let true_anomaly = oe.true_anomaly();
+
+        // Converting back to the mean anomaly through Kepler's equation must give the
+        // mean anomaly the true anomaly was computed from.
+        let e = oe.eccentricity;
+        let eccentric_anomaly =
+            2.0 * (((1.0 - e) / (1.0 + e)).sqrt() * (true_anomaly / 2.0).tan()).atan();
+        let mean_anomaly = eccentric_anomaly - e * eccentric_anomaly.sin();
+        assert!((mean_anomaly - oe.mean_anomaly).abs() < 1e-6);
+    }
 }
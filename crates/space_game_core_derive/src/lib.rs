@@ -0,0 +1,349 @@
+//! Derive macros for `space_game_core`.
+//!
+//! The generated code refers to items through `::space_game_core`, so use these
+//! macros through their re-exports in `space_game_core::ecs`.
+
+#![warn(clippy::missing_docs_in_private_items)]
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::meta::ParseNestedMeta;
+use syn::{
+    parse_macro_input, Attribute, DeriveInput, Expr, Ident, ImplItem, ItemImpl, LitInt, LitStr,
+    Meta,
+};
+
+/// Derive `State`.
+///
+/// `#[state(name = "...")]` also implements `SerializableState` with the given stable
+/// name, and `#[state(name = "...", version = N)]` sets its layout version.
+#[proc_macro_derive(State, attributes(state))]
+pub fn derive_state(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_state(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derive `Event`.
+///
+/// `#[event(name = "...")]` also implements `SerializableEvent` with the given stable
+/// name, so the event can be recorded and sent over the network, and
+/// `#[event(target = field)]` implements `EntityEvent` with the `EntityId` in `field`.
+#[proc_macro_derive(Event, attributes(event))]
+pub fn derive_event(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_event(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derive `Topic`.
+#[proc_macro_derive(Topic)]
+pub fn derive_topic(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    quote! {
+        impl #impl_generics ::space_game_core::ecs::Topic for #ident #ty_generics #where_clause {}
+    }
+    .into()
+}
+
+/// Implement `HandlerGroup` for the type of an inherent `impl` block, adding each of
+/// its functions marked `#[handler]` in order.
+///
+/// `#[handler]` accepts these options, which are applied in the order written:
+///
+/// - `global`: add the function with `ReactorBuilder::add_global`.
+/// - `label = "..."`, `after = "..."`, `before = "..."`: ordering labels.
+/// - `run_if = condition`: a run condition.
+/// - `run_if_changed`: skip the handler unless its inputs changed.
+/// - `error_policy = policy`: the handler's `ErrorPolicy`.
+#[proc_macro_attribute]
+pub fn handler_group(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let attr = TokenStream2::from(attr);
+    let mut item = parse_macro_input!(item as ItemImpl);
+    if !attr.is_empty() {
+        return syn::Error::new_spanned(attr, "`handler_group` doesn't take arguments")
+            .into_compile_error()
+            .into();
+    }
+
+    expand_handler_group(&mut item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Options accepted by the `State` and `Event` derives.
+#[derive(Default)]
+struct TypeOptions {
+    /// Stable name, from `name = "..."`.
+    name: Option<LitStr>,
+    /// Layout version, from `version = N`.
+    version: Option<LitInt>,
+    /// Field holding the target entity, from `target = field`.
+    target: Option<Ident>,
+}
+
+impl TypeOptions {
+    /// Parse the options in every `#[<attr>(...)]` attribute of `input`. `version` is
+    /// only accepted for states, and `target` for events.
+    fn parse(input: &DeriveInput, attr: &str) -> syn::Result<TypeOptions> {
+        let mut options = TypeOptions::default();
+        for attribute in input.attrs.iter().filter(|a| a.path().is_ident(attr)) {
+            attribute.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    options.name = Some(meta.value()?.parse()?);
+                } else if attr == "state" && meta.path.is_ident("version") {
+                    options.version = Some(meta.value()?.parse()?);
+                } else if attr == "event" && meta.path.is_ident("target") {
+                    options.target = Some(meta.value()?.parse()?);
+                } else {
+                    return Err(meta.error(format!("unsupported `{attr}` option")));
+                }
+                Ok(())
+            })?;
+        }
+
+        if let (None, Some(version)) = (&options.name, &options.version) {
+            return Err(syn::Error::new_spanned(
+                version,
+                "`version` requires `name`",
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// Implementation of [`derive_state`].
+fn expand_state(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let options = TypeOptions::parse(input, "state")?;
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut output = quote! {
+        impl #impl_generics ::space_game_core::ecs::State for #ident #ty_generics #where_clause {}
+    };
+    if let Some(name) = options.name {
+        let version = options
+            .version
+            .map(|version| quote! { const VERSION: u32 = #version; });
+        output.extend(quote! {
+            impl #impl_generics ::space_game_core::ecs::SerializableState
+                for #ident #ty_generics #where_clause
+            {
+                const NAME: &'static str = #name;
+                #version
+            }
+        });
+    }
+    Ok(output)
+}
+
+/// Implementation of [`derive_event`].
+fn expand_event(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let options = TypeOptions::parse(input, "event")?;
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut output = quote! {
+        impl #impl_generics ::space_game_core::ecs::Event for #ident #ty_generics #where_clause {}
+    };
+    if let Some(name) = options.name {
+        output.extend(quote! {
+            impl #impl_generics ::space_game_core::ecs::SerializableEvent
+                for #ident #ty_generics #where_clause
+            {
+                const NAME: &'static str = #name;
+            }
+        });
+    }
+    if let Some(target) = options.target {
+        output.extend(quote! {
+            impl #impl_generics ::space_game_core::ecs::EntityEvent
+                for #ident #ty_generics #where_clause
+            {
+                fn target(&self) -> ::space_game_core::ecs::EntityId {
+                    self.#target
+                }
+            }
+        });
+    }
+    Ok(output)
+}
+
+/// Implementation of [`handler_group`].
+fn expand_handler_group(item: &mut ItemImpl) -> syn::Result<TokenStream2> {
+    if let Some((_, path, _)) = &item.trait_ {
+        return Err(syn::Error::new_spanned(
+            path,
+            "`handler_group` must be applied to an inherent impl block",
+        ));
+    }
+
+    let mut calls = Vec::new();
+    for impl_item in &mut item.items {
+        let ImplItem::Fn(function) = impl_item else {
+            continue;
+        };
+        let Some(attr) = take_handler_attr(&mut function.attrs)? else {
+            continue;
+        };
+        if let Some(receiver) = function.sig.receiver() {
+            return Err(syn::Error::new_spanned(
+                receiver,
+                "handlers in a `handler_group` can't take `self`",
+            ));
+        }
+
+        let ident = &function.sig.ident;
+        calls.push(expand_handler(&attr, quote! { Self::#ident })?);
+    }
+
+    let self_ty = &item.self_ty;
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    Ok(quote! {
+        #item
+
+        impl #impl_generics ::space_game_core::ecs::HandlerGroup for #self_ty #where_clause {
+            fn add_group(
+                builder: ::space_game_core::ecs::ReactorBuilder,
+            ) -> ::space_game_core::ecs::ReactorBuilder {
+                builder #(#calls)*
+            }
+        }
+    })
+}
+
+/// Remove the `#[handler]` attribute from `attrs` and return it.
+fn take_handler_attr(attrs: &mut Vec<Attribute>) -> syn::Result<Option<Attribute>> {
+    let mut handler = None;
+    for attr in std::mem::take(attrs) {
+        if !attr.path().is_ident("handler") {
+            attrs.push(attr);
+        } else if handler.is_some() {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "duplicate `handler` attribute",
+            ));
+        } else {
+            handler = Some(attr);
+        }
+    }
+    Ok(handler)
+}
+
+/// Generate the `ReactorBuilder` calls which add the handler `function` with the
+/// options in its `#[handler]` attribute.
+fn expand_handler(attr: &Attribute, function: TokenStream2) -> syn::Result<TokenStream2> {
+    let mut global = false;
+    let mut modifiers = Vec::new();
+    if let Meta::List(_) = &attr.meta {
+        attr.parse_nested_meta(|meta| {
+            let modifier = match meta.path.get_ident().map(|i| i.to_string()).as_deref() {
+                Some("global") => {
+                    global = true;
+                    return Ok(());
+                }
+                Some("run_if_changed") => quote! { .run_if_changed() },
+                Some("label") => string_modifier(&meta, quote!(label))?,
+                Some("after") => string_modifier(&meta, quote!(after))?,
+                Some("before") => string_modifier(&meta, quote!(before))?,
+                Some("run_if") => {
+                    let condition: Expr = meta.value()?.parse()?;
+                    quote! { .run_if(#condition) }
+                }
+                Some("error_policy") => {
+                    let policy: Expr = meta.value()?.parse()?;
+                    quote! { .error_policy(#policy) }
+                }
+                _ => return Err(meta.error("unsupported `handler` option")),
+            };
+            modifiers.push(modifier);
+            Ok(())
+        })?;
+    }
+
+    let add = if global {
+        quote! { .add_global(#function) }
+    } else {
+        quote! { .add(#function) }
+    };
+    Ok(quote! { #add #(#modifiers)* })
+}
+
+/// Generate a `ReactorBuilder` call to `method` with a string argument from `meta`.
+fn string_modifier(meta: &ParseNestedMeta, method: TokenStream2) -> syn::Result<TokenStream2> {
+    let value: LitStr = meta.value()?.parse()?;
+    Ok(quote! { .#method(#value) })
+}
+
+#[cfg(test)]
+mod test {
+    use syn::parse_quote;
+
+    use super::*;
+
+    /// Expand `#[handler_group]` on `item` and return the error message.
+    fn handler_group_error(mut item: ItemImpl) -> String {
+        expand_handler_group(&mut item).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_duplicate_handler() {
+        let error = handler_group_error(parse_quote! {
+            impl Engine {
+                #[handler]
+                #[handler(global)]
+                fn burn(_: &Burn) -> anyhow::Result<()> {
+                    Ok(())
+                }
+            }
+        });
+        assert_eq!(error, "duplicate `handler` attribute");
+    }
+
+    #[test]
+    fn test_handler_self_receiver() {
+        let error = handler_group_error(parse_quote! {
+            impl Engine {
+                #[handler]
+                fn burn(&self, _: &Burn) -> anyhow::Result<()> {
+                    Ok(())
+                }
+            }
+        });
+        assert_eq!(error, "handlers in a `handler_group` can't take `self`");
+    }
+
+    #[test]
+    fn test_unsupported_handler_option() {
+        let error = handler_group_error(parse_quote! {
+            impl Engine {
+                #[handler(priority = 1)]
+                fn burn(_: &Burn) -> anyhow::Result<()> {
+                    Ok(())
+                }
+            }
+        });
+        assert_eq!(error, "unsupported `handler` option");
+    }
+
+    #[test]
+    fn test_version_requires_name() {
+        let input: DeriveInput = parse_quote! {
+            #[state(version = 2)]
+            struct Fuel(f64);
+        };
+        let error = expand_state(&input).unwrap_err().to_string();
+        assert_eq!(error, "`version` requires `name`");
+
+        let input: DeriveInput = parse_quote! {
+            #[state(name = "fuel", version = 2)]
+            struct Fuel(f64);
+        };
+        assert!(expand_state(&input).is_ok());
+    }
+}
@@ -1,12 +1,16 @@
 use std::net::SocketAddr;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
-use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::http::StatusCode;
 use axum::routing::{get, get_service};
 use axum::Router;
 use clap::Parser;
 use futures_util::StreamExt;
+use space_game_core::ecs::{network_events, Handshake, Reactor, DEFAULT_TIMESTEP};
+use tokio::time::Instant;
 use tower_http::services::ServeDir;
 
 #[derive(Parser)]
@@ -17,6 +21,51 @@ struct Args {
 
     #[clap(long, default_value = "127.0.0.1:8000")]
     addr: SocketAddr,
+
+    /// Number of matches to host.
+    #[clap(long, default_value = "1")]
+    matches: usize,
+}
+
+/// Advance one match in real time, one timestep at a time.
+async fn simulate(reactor: Arc<Reactor>, id: usize) {
+    let states = reactor.new_state_container();
+
+    let mut interval = tokio::time::interval(Duration::from_secs_f64(DEFAULT_TIMESTEP));
+    let mut last_frame = Instant::now();
+    loop {
+        let now = interval.tick().await;
+        if let Err(err) = reactor.run_frame(&states, now - last_frame) {
+            println!("Simulation error in match {}: {}", id, err);
+        }
+        last_frame = now;
+    }
+}
+
+/// Exchange [`Handshake`]s with a newly connected client, and return false if the
+/// client can't be served.
+///
+/// The server's handshake is sent even if the client's doesn't match, so that the
+/// client can report the mismatch too.
+async fn handshake(ws: &mut WebSocket) -> bool {
+    let registry = network_events();
+    let theirs = match ws.recv().await {
+        Some(Ok(Message::Binary(data))) => Handshake::from_bytes(&data),
+        other => {
+            println!("Expected a handshake, got: {:?}", other);
+            return false;
+        }
+    };
+
+    let ours = Handshake::new(&registry).to_bytes();
+    if ws.send(Message::Binary(ours)).await.is_err() {
+        return false;
+    }
+    if let Err(err) = theirs.and_then(|theirs| theirs.check(&registry)) {
+        println!("Handshake failed: {}", err);
+        return false;
+    }
+    true
 }
 
 #[tokio::main]
@@ -24,8 +73,18 @@ async fn main() {
     let args = Args::parse();
     assert!(Path::new(&args.space_game_pkg).is_dir());
 
+    // Every match is driven by the same `Reactor`, with its own `StateContainer`.
+    let reactor = Arc::new(Reactor::builder().build().unwrap());
+    for id in 0..args.matches {
+        tokio::spawn(simulate(reactor.clone(), id));
+    }
+
     let handle_ws = get(|wsu: WebSocketUpgrade| async {
         wsu.on_upgrade(|mut ws| async move {
+            if !handshake(&mut ws).await {
+                let _ = ws.close().await;
+                return;
+            }
             while let Some(val) = ws.next().await {
                 println!("Got: {:?}", val);
             }